@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN verification_sent_at TIMESTAMPTZ;
+
+-- Accounts that existed before verification was required stay usable.
+UPDATE users SET email_verified = TRUE;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE settings;
+DROP TABLE recovery_codes;
+DROP TABLE user_totp;
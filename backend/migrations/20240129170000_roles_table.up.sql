@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS roles (
+  name VARCHAR(32) PRIMARY KEY,
+  description TEXT NOT NULL
+);
+
+INSERT INTO roles (name, description) VALUES
+  ('reader', 'Can read and comment on posts'),
+  ('author', 'Can also write and edit their own posts'),
+  ('editor', 'Can also edit and publish anyone''s posts'),
+  ('admin', 'Can also manage users and site settings');
+
+ALTER TABLE users ADD COLUMN role VARCHAR(32) NOT NULL DEFAULT 'author' REFERENCES roles(name);
+UPDATE users SET role = 'admin' WHERE is_admin;
+ALTER TABLE users DROP COLUMN is_admin;
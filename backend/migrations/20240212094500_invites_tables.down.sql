@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE invite_redemptions;
+DROP TABLE invites;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS profiles (
+  user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  handle VARCHAR(32) UNIQUE NOT NULL,
+  display_name VARCHAR(100) NOT NULL,
+  bio TEXT NOT NULL DEFAULT '',
+  website VARCHAR(255),
+  avatar_url VARCHAR(255),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Existing accounts get the same placeholder profile new accounts start with.
+INSERT INTO profiles (user_id, handle, display_name)
+SELECT id, 'user' || id, 'User ' || id FROM users
+ON CONFLICT DO NOTHING;
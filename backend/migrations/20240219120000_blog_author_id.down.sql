@@ -0,0 +1,10 @@
+-- Add down migration script here
+ALTER TABLE users DROP COLUMN email_change_sent_at;
+
+ALTER TABLE blog ADD COLUMN email VARCHAR(255) REFERENCES users(email) ON DELETE CASCADE;
+
+UPDATE blog SET email = users.email FROM users WHERE users.id = blog.author_id;
+
+ALTER TABLE blog ALTER COLUMN email SET NOT NULL;
+
+ALTER TABLE blog DROP COLUMN author_id;
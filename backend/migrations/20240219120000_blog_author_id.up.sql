@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE blog ADD COLUMN author_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+
+UPDATE blog SET author_id = users.id FROM users WHERE users.email = blog.email;
+
+ALTER TABLE blog ALTER COLUMN author_id SET NOT NULL;
+
+-- Dropping the column drops its foreign key to users(email) with it.
+ALTER TABLE blog DROP COLUMN email;
+
+CREATE INDEX IF NOT EXISTS blog_author_id_idx ON blog (author_id);
+
+ALTER TABLE users ADD COLUMN email_change_sent_at TIMESTAMPTZ;
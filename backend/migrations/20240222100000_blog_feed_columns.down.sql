@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS blog_tags_idx;
+DROP INDEX IF EXISTS blog_published_at_idx;
+
+ALTER TABLE blog DROP COLUMN tags;
+ALTER TABLE blog DROP COLUMN updated_at;
+ALTER TABLE blog DROP COLUMN published_at;
+ALTER TABLE blog DROP COLUMN id;
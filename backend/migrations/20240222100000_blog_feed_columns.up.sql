@@ -0,0 +1,11 @@
+-- Add up migration script here
+ALTER TABLE blog ADD COLUMN id SERIAL PRIMARY KEY;
+
+-- publish_date was free text from the form, so existing posts count as
+-- published when this runs.
+ALTER TABLE blog ADD COLUMN published_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE blog ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE blog ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS blog_published_at_idx ON blog (published_at DESC);
+CREATE INDEX IF NOT EXISTS blog_tags_idx ON blog USING GIN (tags);
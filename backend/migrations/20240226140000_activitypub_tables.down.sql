@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS activity_deliveries;
+DROP TABLE IF EXISTS followers;
+DROP TABLE IF EXISTS actor_keys;
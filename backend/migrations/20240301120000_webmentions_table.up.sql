@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS webmentions (
+  id SERIAL PRIMARY KEY,
+  blog_id INTEGER NOT NULL REFERENCES blog(id) ON DELETE CASCADE,
+  source TEXT NOT NULL,
+  target TEXT NOT NULL,
+  -- pending until the source has been fetched, then verified or invalid;
+  -- admins can hide verified ones.
+  status VARCHAR(16) NOT NULL DEFAULT 'pending',
+  source_title TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (source, target)
+);
+
+CREATE INDEX IF NOT EXISTS webmentions_blog_id_idx ON webmentions (blog_id);
+CREATE INDEX IF NOT EXISTS webmentions_status_idx ON webmentions (status);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS comments;
+
+ALTER TABLE blog DROP COLUMN IF EXISTS import_key;
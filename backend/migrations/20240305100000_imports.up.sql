@@ -0,0 +1,17 @@
+-- Add up migration script here
+-- Where an imported post came from, so importing it again updates the post
+-- instead of adding a copy.
+ALTER TABLE blog ADD COLUMN import_key TEXT UNIQUE;
+
+CREATE TABLE IF NOT EXISTS comments (
+  id SERIAL PRIMARY KEY,
+  blog_id INTEGER NOT NULL REFERENCES blog(id) ON DELETE CASCADE,
+  author_name TEXT NOT NULL,
+  author_email TEXT,
+  author_url TEXT,
+  content TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  import_key TEXT UNIQUE
+);
+
+CREATE INDEX IF NOT EXISTS comments_blog_id_idx ON comments (blog_id);
@@ -0,0 +1,987 @@
+//! Makes every author an ActivityPub actor, so they can be followed from
+//! Mastodon and the rest of the fediverse.
+//!
+//! Actors live at `/ap/users/:id`, as the id never changes while a handle can,
+//! and are found through WebFinger as `acct:handle@host`. Remote servers
+//! follow an author by posting a signed `Follow` to their inbox. New posts go
+//! out to followers as `Create{Article}` through the `activity_deliveries`
+//! queue, which a background job works through with exponential backoff.
+//! Requests both ways are signed with HTTP Signatures (rsa-sha256).
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{HeaderMap, Method};
+use jsonwebtoken::Algorithm;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs1v15::{Signature, SigningKey as RsaSigningKey, VerifyingKey as RsaVerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::feeds::{author_url_at, post_url_at, tag_url_at};
+use crate::keys;
+use crate::models::blog::Blog;
+use crate::models::federation::{ActorKey, Delivery};
+use crate::models::profiles::Profile;
+use crate::remote::Remote;
+
+pub const ACTIVITY_CONTENT_TYPE: &str = "application/activity+json; charset=utf-8";
+pub const JRD_CONTENT_TYPE: &str = "application/jrd+json; charset=utf-8";
+const ACCEPT_ACTIVITY: &str =
+    r#"application/activity+json, application/ld+json; profile="https://www.w3.org/ns/activitystreams""#;
+
+const ACTIVITY_STREAMS: &str = "https://www.w3.org/ns/activitystreams";
+const SECURITY: &str = "https://w3id.org/security/v1";
+pub const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// How far the `Date` of a signed request may be from our clock.
+const SIGNATURE_MAX_SKEW: Duration = Duration::from_secs(60 * 60 * 12);
+
+/// How long we wait on another server, for fetching actors and delivering.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the delivery job looks for work, and how much it takes at once.
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(10);
+const DELIVERY_BATCH: i64 = 20;
+
+/// Long enough for a batch to finish, so a delivery isn't picked up twice.
+const DELIVERY_LEASE: Duration = Duration::from_secs(60 * 5);
+
+/// Retries start a minute apart and double up to this, giving an inbox that
+/// is down about a day to come back before we give up.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60 * 6);
+const MAX_DELIVERY_ATTEMPTS: i32 = 12;
+
+static SIGNATURE_PARAM: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(\w+)="([^"]*)""#).unwrap());
+
+// Everything takes the blog's `base_url`, normally `get_public_url()`, so
+// documents can be built for any address.
+
+pub fn actor_url(base_url: &str, user_id: i32) -> String {
+    format!("{}/ap/users/{}", base_url, user_id)
+}
+
+fn key_id(base_url: &str, user_id: i32) -> String {
+    format!("{}#main-key", actor_url(base_url, user_id))
+}
+
+fn followers_url(base_url: &str, user_id: i32) -> String {
+    format!("{}/followers", actor_url(base_url, user_id))
+}
+
+pub fn object_url(base_url: &str, blog: &Blog) -> String {
+    format!("{}/ap/posts/{}", base_url, blog.id)
+}
+
+/// The host name in our `acct:` addresses, with the port if it isn't the default.
+pub fn host(base_url: &str) -> String {
+    url::Url::parse(base_url)
+        .ok()
+        .and_then(|url| host_of(&url))
+        .unwrap_or_default()
+}
+
+fn host_of(url: &url::Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// `@handle@host`, how people on other servers find an author.
+pub fn fediverse_handle(base_url: &str, handle: &str) -> String {
+    format!("@{}@{}", handle, host(base_url))
+}
+
+/// The handle in a WebFinger `acct:handle@host` resource, if it is on this server.
+pub fn parse_acct(base_url: &str, resource: &str) -> Option<String> {
+    let acct = resource.strip_prefix("acct:").unwrap_or(resource);
+    let (handle, domain) = acct.trim_start_matches('@').split_once('@')?;
+
+    domain
+        .eq_ignore_ascii_case(&host(base_url))
+        .then(|| handle.to_lowercase())
+}
+
+/// Adds the JSON-LD context a document needs at the top level.
+pub fn document(mut object: Value) -> Value {
+    object["@context"] = json!([ACTIVITY_STREAMS, SECURITY]);
+    object
+}
+
+pub fn webfinger(base_url: &str, profile: &Profile) -> Value {
+    let actor = actor_url(base_url, profile.user_id);
+    let page = author_url_at(base_url, &profile.handle);
+
+    json!({
+        "subject": format!("acct:{}@{}", profile.handle, host(base_url)),
+        "aliases": [actor, page],
+        "links": [
+            {
+                "rel": "self",
+                "type": "application/activity+json",
+                "href": actor,
+            },
+            {
+                "rel": "http://webfinger.net/rel/profile-page",
+                "type": "text/html",
+                "href": page,
+            },
+        ],
+    })
+}
+
+pub fn actor(base_url: &str, profile: &Profile, key: &ActorKey) -> Value {
+    let id = actor_url(base_url, profile.user_id);
+    let summary = html_escape::encode_text(&profile.bio).replace('\n', "<br>");
+
+    let mut actor = json!({
+        "id": id,
+        "type": "Person",
+        "preferredUsername": profile.handle,
+        "name": profile.display_name,
+        "summary": summary,
+        "url": author_url_at(base_url, &profile.handle),
+        "inbox": format!("{}/inbox", id),
+        "outbox": format!("{}/outbox", id),
+        "followers": followers_url(base_url, profile.user_id),
+        "manuallyApprovesFollowers": false,
+        "discoverable": true,
+        "publicKey": {
+            "id": key_id(base_url, profile.user_id),
+            "owner": id,
+            "publicKeyPem": key.public_key,
+        },
+    });
+    if let Some(avatar_url) = &profile.avatar_url {
+        actor["icon"] = json!({ "type": "Image", "url": avatar_url });
+    }
+
+    actor
+}
+
+pub fn article(base_url: &str, blog: &Blog) -> Value {
+    let tags: Vec<Value> = blog
+        .tags
+        .iter()
+        .map(|tag| {
+            json!({
+                "type": "Hashtag",
+                "href": tag_url_at(base_url, tag),
+                "name": format!("#{}", tag),
+            })
+        })
+        .collect();
+
+    json!({
+        "id": object_url(base_url, blog),
+        "type": "Article",
+        "name": blog.title,
+        "content": blog.content,
+        "mediaType": "text/html",
+        "url": post_url_at(base_url, blog),
+        "attributedTo": actor_url(base_url, blog.author_id),
+        "published": blog.published_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "updated": blog.updated_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "to": [PUBLIC],
+        "cc": [followers_url(base_url, blog.author_id)],
+        "tag": tags,
+    })
+}
+
+pub fn create(base_url: &str, blog: &Blog) -> Value {
+    json!({
+        "id": format!("{}/activity", object_url(base_url, blog)),
+        "type": "Create",
+        "actor": actor_url(base_url, blog.author_id),
+        "published": blog.published_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "to": [PUBLIC],
+        "cc": [followers_url(base_url, blog.author_id)],
+        "object": article(base_url, blog),
+    })
+}
+
+/// The author's newest posts; `total` counts all of them.
+pub fn outbox(base_url: &str, user_id: i32, blogs: &[Blog], total: i64) -> Value {
+    json!({
+        "id": format!("{}/outbox", actor_url(base_url, user_id)),
+        "type": "OrderedCollection",
+        "totalItems": total,
+        "orderedItems": blogs.iter().map(|blog| create(base_url, blog)).collect::<Vec<_>>(),
+    })
+}
+
+/// Only says how many followers there are, not who they are.
+pub fn followers(base_url: &str, user_id: i32, total: i64) -> Value {
+    json!({
+        "id": followers_url(base_url, user_id),
+        "type": "OrderedCollection",
+        "totalItems": total,
+    })
+}
+
+fn accept(base_url: &str, user_id: i32, follow: &Value) -> Value {
+    let mut follow = follow.clone();
+    if let Some(follow) = follow.as_object_mut() {
+        follow.remove("@context");
+    }
+
+    json!({
+        "id": format!("{}#accepts/{}", actor_url(base_url, user_id), Uuid::new_v4()),
+        "type": "Accept",
+        "actor": actor_url(base_url, user_id),
+        "object": follow,
+    })
+}
+
+fn signature_error(message: &str, err: impl std::fmt::Display) -> AppError {
+    warn!("{}: {}", message, err);
+    AppError::InvalidSignature
+}
+
+/// The author's actor key, made the first time it is needed.
+pub async fn actor_key(store: &Store, user_id: i32) -> Result<ActorKey, AppError> {
+    if let Some(key) = store.get_actor_key(user_id).await? {
+        return Ok(key);
+    }
+
+    let key = keys::generate_key(Algorithm::RS256)?;
+    store.add_actor_key(user_id, &key.private_key, &key.public_key).await
+}
+
+fn body_digest(body: &[u8]) -> String {
+    format!("SHA-256={}", STANDARD.encode(Sha256::digest(body)))
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// The `Date`, `Digest` and `Signature` headers for POSTing `body` to `url`.
+fn signed_post_headers(
+    base_url: &str,
+    key: &ActorKey,
+    url: &url::Url,
+    body: &[u8],
+) -> Result<Vec<(&'static str, String)>, AppError> {
+    let host = host_of(url).ok_or(AppError::InternalServerError)?;
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let date = http_date(Utc::now());
+    let digest = body_digest(body);
+    let signing_string = format!(
+        "(request-target): post {}\nhost: {}\ndate: {}\ndigest: {}",
+        target, host, date, digest
+    );
+
+    let private_key = RsaPrivateKey::from_pkcs8_pem(&key.private_key).map_err(|err| {
+        error!("Invalid actor key for user {}: {}", key.user_id, err);
+        AppError::InternalServerError
+    })?;
+    let signature = RsaSigningKey::<Sha256>::new(private_key).sign(signing_string.as_bytes());
+    let signature = format!(
+        r#"keyId="{}",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="{}""#,
+        key_id(base_url, key.user_id),
+        STANDARD.encode(signature.to_bytes())
+    );
+
+    Ok(vec![("date", date), ("digest", digest), ("signature", signature)])
+}
+
+/// Another server's actor, as far as we need it.
+pub struct RemoteActor {
+    pub id: String,
+    pub inbox: String,
+    pub shared_inbox: Option<String>,
+    public_key_pem: String,
+}
+
+/// Remote URLs have to be https, unless `remote` allows local servers, and
+/// pass [`Remote::is_fetchable`]. They come from whoever posts to our inbox,
+/// so they are only fetched through `remote`.
+fn check_remote_url(remote: &Remote, url: &str) -> Result<url::Url, AppError> {
+    let url = url::Url::parse(url).map_err(|err| signature_error("Invalid remote URL", err))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if remote.allows_local() => {}
+        scheme => return Err(signature_error("Refusing remote URL scheme", scheme)),
+    }
+    if !remote.is_fetchable(&url) {
+        return Err(signature_error("Refusing remote URL", &url));
+    }
+
+    Ok(url)
+}
+
+async fn fetch_object(client: &reqwest::Client, url: &url::Url) -> Result<Value, AppError> {
+    let object = client
+        .get(url.clone())
+        .header(ACCEPT, ACCEPT_ACTIVITY)
+        .timeout(REMOTE_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(object)
+}
+
+/// Fetches the actor owning `key_id`. The key can be served inside its actor,
+/// as Mastodon does, or on its own with an `owner`.
+async fn fetch_key_owner(remote: &Remote, key_id: &str) -> Result<RemoteActor, AppError> {
+    let mut key_url = check_remote_url(remote, key_id)?;
+    key_url.set_fragment(None);
+    let document = fetch_object(remote.client(), &key_url).await?;
+
+    let actor = if document.get("publicKey").is_some() {
+        document
+    } else {
+        let owner = document["owner"]
+            .as_str()
+            .ok_or_else(|| signature_error("Key has no owner", key_id))?;
+        fetch_object(remote.client(), &check_remote_url(remote, owner)?).await?
+    };
+
+    let id = actor["id"].as_str().unwrap_or_default();
+    let actor_host = check_remote_url(remote, id)?.host_str().map(str::to_lowercase);
+    if actor_host != key_url.host_str().map(str::to_lowercase) {
+        return Err(signature_error("Key and actor are on different hosts", key_id));
+    }
+
+    // Actors may list one key or several.
+    let keys = match &actor["publicKey"] {
+        Value::Array(keys) => keys.clone(),
+        key => vec![key.clone()],
+    };
+    let key = keys
+        .iter()
+        .find(|key| key["id"].as_str() == Some(key_id))
+        .ok_or_else(|| signature_error("Actor doesn't list the signing key", key_id))?;
+    if key["owner"].as_str().is_some_and(|owner| owner != id) {
+        return Err(signature_error("Key belongs to another actor", key_id));
+    }
+
+    let inbox = actor["inbox"]
+        .as_str()
+        .ok_or_else(|| signature_error("Actor has no inbox", id))?;
+
+    Ok(RemoteActor {
+        id: id.to_string(),
+        inbox: check_remote_url(remote, inbox)?.to_string(),
+        shared_inbox: actor["endpoints"]["sharedInbox"]
+            .as_str()
+            .and_then(|inbox| check_remote_url(remote, inbox).ok())
+            .map(|inbox| inbox.to_string()),
+        public_key_pem: key["publicKeyPem"].as_str().unwrap_or_default().to_string(),
+    })
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, AppError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| signature_error("Missing signed header", name))
+}
+
+/// Checks the HTTP signature on a request to one of our inboxes and returns
+/// the actor that signed it. The signature has to cover the request target,
+/// host, date and body digest, and the date has to be recent.
+pub async fn verify_request(
+    remote: &Remote,
+    method: &Method,
+    target: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<RemoteActor, AppError> {
+    let signature_header = header_value(headers, "signature")?;
+    let params: HashMap<&str, &str> = SIGNATURE_PARAM
+        .captures_iter(signature_header)
+        .filter_map(|captures| Some((captures.get(1)?.as_str(), captures.get(2)?.as_str())))
+        .collect();
+    let key_id = params
+        .get("keyId")
+        .ok_or_else(|| signature_error("Signature without keyId", signature_header))?;
+    let signature = params
+        .get("signature")
+        .and_then(|signature| STANDARD.decode(signature).ok())
+        .ok_or_else(|| signature_error("Signature isn't base64", signature_header))?;
+    let signed_headers: Vec<String> = params
+        .get("headers")
+        .unwrap_or(&"date")
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+    for required in ["(request-target)", "host", "date", "digest"] {
+        if !signed_headers.iter().any(|name| name == required) {
+            return Err(signature_error("Signature doesn't cover", required));
+        }
+    }
+
+    let date = DateTime::parse_from_rfc2822(header_value(headers, "date")?)
+        .map_err(|err| signature_error("Invalid Date header", err))?;
+    let skew = (Utc::now() - date.with_timezone(&Utc)).num_seconds().unsigned_abs();
+    if skew > SIGNATURE_MAX_SKEW.as_secs() {
+        return Err(signature_error("Signed request is too old", date));
+    }
+
+    let digest = body_digest(body);
+    if !header_value(headers, "digest")?
+        .split(',')
+        .any(|value| value.trim() == digest)
+    {
+        return Err(signature_error("Digest doesn't match the body", key_id));
+    }
+
+    let mut lines = Vec::new();
+    for name in &signed_headers {
+        if name == "(request-target)" {
+            lines.push(format!("(request-target): {} {}", method.as_str().to_lowercase(), target));
+        } else {
+            lines.push(format!("{}: {}", name, header_value(headers, name)?));
+        }
+    }
+
+    let actor = fetch_key_owner(remote, key_id).await?;
+    let public_key = RsaPublicKey::from_public_key_pem(&actor.public_key_pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(&actor.public_key_pem))
+        .map_err(|err| signature_error("Invalid actor public key", err))?;
+    let signature = Signature::try_from(signature.as_slice())
+        .map_err(|err| signature_error("Malformed signature", err))?;
+    RsaVerifyingKey::<Sha256>::new(public_key)
+        .verify(lines.join("\n").as_bytes(), &signature)
+        .map_err(|err| signature_error("Signature doesn't verify", err))?;
+
+    Ok(actor)
+}
+
+/// Acts on an activity `sender` posted to `user_id`'s inbox. We only care
+/// about being followed and unfollowed; everything else is accepted and
+/// dropped.
+pub async fn handle_activity(
+    store: &Store,
+    base_url: &str,
+    user_id: i32,
+    sender: RemoteActor,
+    activity: Value,
+) -> Result<(), AppError> {
+    if activity["actor"].as_str() != Some(sender.id.as_str()) {
+        warn!("Activity from {} signed by {}", activity["actor"], sender.id);
+        return Err(AppError::Forbidden);
+    }
+
+    match activity["type"].as_str() {
+        Some("Follow") if activity["object"].as_str() == Some(actor_url(base_url, user_id).as_str()) => {
+            store
+                .add_follower(user_id, &sender.id, &sender.inbox, sender.shared_inbox.as_deref())
+                .await?;
+            store
+                .queue_deliveries(
+                    user_id,
+                    std::slice::from_ref(&sender.inbox),
+                    &document(accept(base_url, user_id, &activity)),
+                )
+                .await?;
+            info!("{} now follows user {}", sender.id, user_id);
+        }
+        Some("Undo") => {
+            let undone = &activity["object"];
+            let is_follow = undone.is_string() || undone["type"].as_str() == Some("Follow");
+            let by_sender = undone["actor"].as_str().is_none_or(|actor| actor == sender.id);
+            if is_follow && by_sender && store.remove_follower(user_id, &sender.id).await? {
+                info!("{} stopped following user {}", sender.id, user_id);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Queues a new post for every inbox following its author. Followers on the
+/// same server share one delivery when it has a shared inbox.
+pub async fn publish(store: &Store, base_url: &str, blog_id: i32) -> Result<(), AppError> {
+    let blog = store.get_blog(blog_id).await?.ok_or(AppError::NotFound)?;
+
+    let mut inboxes: Vec<String> = store
+        .get_followers(blog.author_id)
+        .await?
+        .into_iter()
+        .map(|follower| follower.shared_inbox.unwrap_or(follower.inbox))
+        .collect();
+    inboxes.sort();
+    inboxes.dedup();
+    if inboxes.is_empty() {
+        return Ok(());
+    }
+
+    store
+        .queue_deliveries(blog.author_id, &inboxes, &document(create(base_url, &blog)))
+        .await?;
+    info!("Queued post {} for {} inboxes", blog.id, inboxes.len());
+
+    Ok(())
+}
+
+enum DeliveryError {
+    Retry(String),
+    GiveUp(String),
+}
+
+async fn deliver(store: &Store, base_url: &str, delivery: &Delivery) -> Result<(), DeliveryError> {
+    let key = actor_key(store, delivery.user_id)
+        .await
+        .map_err(|err| DeliveryError::Retry(format!("{:?}", err)))?;
+    let url = check_remote_url(&store.remote, &delivery.inbox)
+        .map_err(|_| DeliveryError::GiveUp(format!("refusing to deliver to {}", delivery.inbox)))?;
+    let body = serde_json::to_vec(&delivery.activity).map_err(|err| DeliveryError::GiveUp(err.to_string()))?;
+    let headers = signed_post_headers(base_url, &key, &url, &body)
+        .map_err(|err| DeliveryError::GiveUp(format!("{:?}", err)))?;
+
+    let mut request = store
+        .remote
+        .client()
+        .post(url)
+        .header(CONTENT_TYPE, ACTIVITY_CONTENT_TYPE)
+        .timeout(REMOTE_TIMEOUT);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| DeliveryError::Retry(err.to_string()))?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_client_error() && status.as_u16() != 408 && status.as_u16() != 429 {
+        // The inbox is gone or won't take this; asking again won't change that.
+        Err(DeliveryError::GiveUp(format!("{} from {}", status, delivery.inbox)))
+    } else {
+        Err(DeliveryError::Retry(format!("{} from {}", status, delivery.inbox)))
+    }
+}
+
+fn retry_delay(attempts: i32) -> Duration {
+    let delay = Duration::from_secs(60).saturating_mul(1 << attempts.clamp(0, 16));
+    delay.min(MAX_RETRY_DELAY)
+}
+
+async fn run_deliveries(store: &Store, base_url: &str) -> Result<(), AppError> {
+    let deliveries = store.claim_due_deliveries(DELIVERY_BATCH, DELIVERY_LEASE).await?;
+    let results =
+        futures::future::join_all(deliveries.iter().map(|delivery| deliver(store, base_url, delivery))).await;
+
+    for (delivery, result) in deliveries.iter().zip(results) {
+        match result {
+            Ok(()) => store.finish_delivery(delivery.id).await?,
+            Err(DeliveryError::Retry(err)) if delivery.attempts + 1 < MAX_DELIVERY_ATTEMPTS => {
+                store
+                    .retry_delivery(delivery.id, retry_delay(delivery.attempts), &err)
+                    .await?
+            }
+            Err(DeliveryError::Retry(err)) | Err(DeliveryError::GiveUp(err)) => {
+                warn!(
+                    "Giving up delivering to {} after {} attempts: {}",
+                    delivery.inbox,
+                    delivery.attempts + 1,
+                    err
+                );
+                store.finish_delivery(delivery.id).await?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Works through the delivery queue in the background.
+pub fn spawn_delivery_worker(store: Store, base_url: String) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DELIVERY_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = run_deliveries(&store, &base_url).await {
+                error!("Could not deliver activities: {:?}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes;
+    use axum::extract::{Path, State};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use http::{HeaderValue, StatusCode, Uri};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::test_support;
+
+    const BASE_URL: &str = "https://blog.example";
+
+    /// Making RSA keys is slow, so the fake servers and our test authors
+    /// all sign with this one.
+    static KEY: Lazy<keys::SigningKey> = Lazy::new(|| keys::generate_key(Algorithm::RS256).unwrap());
+
+    /// A request the fake server's inboxes were sent.
+    struct Received {
+        path: String,
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    /// Another fediverse server. `/users/alice` is an actor in the Mastodon
+    /// shape, and `/users/mallory` claims an id on another host. Inboxes
+    /// under `/inbox/` record what they are sent and answer with the status
+    /// their path ends in.
+    struct FakeServer {
+        base: String,
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    fn start_server() -> FakeServer {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let inboxes = received.clone();
+        let base = test_support::serve(move |base| {
+            let base = base.to_string();
+            let port = url::Url::parse(&base).unwrap().port().unwrap();
+            Router::new()
+                .route(
+                    "/users/:name",
+                    get(move |Path(name): Path<String>| {
+                        let base = base.clone();
+                        async move {
+                            let id = match name.as_str() {
+                                "alice" => format!("{}/users/alice", base),
+                                "mallory" => format!("http://localhost:{}/users/alice", port),
+                                _ => return Err(StatusCode::NOT_FOUND),
+                            };
+                            Ok(Json(json!({
+                                "@context": [ACTIVITY_STREAMS, SECURITY],
+                                "id": id,
+                                "type": "Person",
+                                "inbox": format!("{}/users/{}/inbox", base, name),
+                                "endpoints": { "sharedInbox": format!("{}/inbox/shared/202", base) },
+                                "publicKey": {
+                                    "id": format!("{}/users/{}#main-key", base, name),
+                                    "owner": id,
+                                    "publicKeyPem": KEY.public_key,
+                                },
+                            })))
+                        }
+                    }),
+                )
+                .route("/inbox/*rest", post(inbox))
+                .with_state(inboxes)
+        });
+
+        FakeServer { base, received }
+    }
+
+    async fn inbox(
+        State(received): State<Arc<Mutex<Vec<Received>>>>,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let path = uri.path().to_string();
+        let status = path
+            .rsplit('/')
+            .next()
+            .and_then(|status| status.parse().ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::ACCEPTED);
+        received.lock().unwrap().push(Received { path, headers, body });
+        status
+    }
+
+    fn alice(server: &FakeServer) -> String {
+        format!("{}/users/alice", server.base)
+    }
+
+    fn received_under(server: &FakeServer, prefix: &str) -> Vec<(String, HeaderMap, Bytes)> {
+        server
+            .received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|received| received.path.starts_with(prefix))
+            .map(|received| (received.path.clone(), received.headers.clone(), received.body.clone()))
+            .collect()
+    }
+
+    /// Headers for `body` POSTed to `target` on our server, signed by the
+    /// fake server's key as `key_id`.
+    fn signed_headers(key_id: &str, target: &str, body: &[u8], date: DateTime<Utc>) -> HeaderMap {
+        let host = host(BASE_URL);
+        let date = http_date(date);
+        let digest = body_digest(body);
+        let signing_string = format!(
+            "(request-target): post {}\nhost: {}\ndate: {}\ndigest: {}",
+            target, host, date, digest
+        );
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&KEY.private_key).unwrap();
+        let signature = RsaSigningKey::<Sha256>::new(private_key).sign(signing_string.as_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_str(&host).unwrap());
+        headers.insert("date", HeaderValue::from_str(&date).unwrap());
+        headers.insert("digest", HeaderValue::from_str(&digest).unwrap());
+        headers.insert(
+            "signature",
+            HeaderValue::from_str(&format!(
+                r#"keyId="{}",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="{}""#,
+                key_id,
+                STANDARD.encode(signature.to_bytes())
+            ))
+            .unwrap(),
+        );
+        headers
+    }
+
+    fn inbox_target(user_id: i32) -> String {
+        format!("/ap/users/{}/inbox", user_id)
+    }
+
+    /// Posts `activity` to `user_id`'s inbox as alice, then acts on it.
+    async fn receive(store: &Store, server: &FakeServer, user_id: i32, activity: Value) -> Result<(), AppError> {
+        let body = serde_json::to_vec(&activity).unwrap();
+        let target = inbox_target(user_id);
+        let headers = signed_headers(&format!("{}#main-key", alice(server)), &target, &body, Utc::now());
+        let sender = verify_request(&store.remote, &Method::POST, &target, &headers, &body).await?;
+        handle_activity(store, BASE_URL, user_id, sender, activity).await
+    }
+
+    async fn verify(headers: &HeaderMap, body: &[u8]) -> Result<RemoteActor, AppError> {
+        verify_request(&Remote::new(true), &Method::POST, &inbox_target(1), headers, body).await
+    }
+
+    fn follow(server: &FakeServer, user_id: i32) -> Value {
+        document(json!({
+            "id": format!("{}#follows/{}", alice(server), Uuid::new_v4()),
+            "type": "Follow",
+            "actor": alice(server),
+            "object": actor_url(BASE_URL, user_id),
+        }))
+    }
+
+    /// Checks a delivery the way a receiving server would, with the public
+    /// key of the actor that sent it.
+    fn check_signature(path: &str, headers: &HeaderMap, body: &[u8], key: &ActorKey) {
+        let signature_header = headers["signature"].to_str().unwrap();
+        let params: HashMap<&str, &str> = SIGNATURE_PARAM
+            .captures_iter(signature_header)
+            .map(|captures| (captures.get(1).unwrap().as_str(), captures.get(2).unwrap().as_str()))
+            .collect();
+        assert_eq!(params["keyId"], key_id(BASE_URL, key.user_id));
+        assert_eq!(headers["digest"].to_str().unwrap(), body_digest(body));
+
+        let signing_string = params["headers"]
+            .split_whitespace()
+            .map(|name| match name {
+                "(request-target)" => format!("(request-target): post {}", path),
+                name => format!("{}: {}", name, headers[name].to_str().unwrap()),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let public_key = RsaPublicKey::from_public_key_pem(&key.public_key).unwrap();
+        let signature = Signature::try_from(STANDARD.decode(params["signature"]).unwrap().as_slice()).unwrap();
+        RsaVerifyingKey::<Sha256>::new(public_key)
+            .verify(signing_string.as_bytes(), &signature)
+            .expect("delivery signature doesn't verify");
+    }
+
+    async fn author(store: &Store) -> i32 {
+        let user = store.create_verified_user("author@blog.example", "unused").await.unwrap();
+        store
+            .add_actor_key(user.id, &KEY.private_key, &KEY.public_key)
+            .await
+            .unwrap();
+        user.id
+    }
+
+    #[tokio::test]
+    async fn signed_follow_is_verified() {
+        let server = start_server();
+        let body = serde_json::to_vec(&follow(&server, 1)).unwrap();
+        let headers = signed_headers(&format!("{}#main-key", alice(&server)), &inbox_target(1), &body, Utc::now());
+
+        let actor = verify(&headers, &body).await.unwrap();
+        assert_eq!(actor.id, alice(&server));
+        assert_eq!(actor.inbox, format!("{}/inbox", alice(&server)));
+        assert_eq!(actor.shared_inbox, Some(format!("{}/inbox/shared/202", server.base)));
+    }
+
+    #[tokio::test]
+    async fn body_that_doesnt_match_the_digest_is_rejected() {
+        let server = start_server();
+        let body = serde_json::to_vec(&follow(&server, 1)).unwrap();
+        let headers = signed_headers(&format!("{}#main-key", alice(&server)), &inbox_target(1), &body, Utc::now());
+
+        let tampered = serde_json::to_vec(&follow(&server, 2)).unwrap();
+        assert!(matches!(verify(&headers, &tampered).await, Err(AppError::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn digest_changed_after_signing_is_rejected() {
+        let server = start_server();
+        let body = serde_json::to_vec(&follow(&server, 1)).unwrap();
+        let tampered = serde_json::to_vec(&follow(&server, 2)).unwrap();
+        let mut headers = signed_headers(&format!("{}#main-key", alice(&server)), &inbox_target(1), &body, Utc::now());
+        headers.insert("digest", HeaderValue::from_str(&body_digest(&tampered)).unwrap());
+
+        assert!(matches!(verify(&headers, &tampered).await, Err(AppError::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn stale_date_is_rejected() {
+        let server = start_server();
+        let body = serde_json::to_vec(&follow(&server, 1)).unwrap();
+        let date = Utc::now() - chrono::Duration::hours(13);
+        let headers = signed_headers(&format!("{}#main-key", alice(&server)), &inbox_target(1), &body, date);
+
+        assert!(matches!(verify(&headers, &body).await, Err(AppError::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn key_on_another_host_than_its_actor_is_rejected() {
+        let server = start_server();
+        let body = serde_json::to_vec(&follow(&server, 1)).unwrap();
+        let key_id = format!("{}/users/mallory#main-key", server.base);
+        let headers = signed_headers(&key_id, &inbox_target(1), &body, Utc::now());
+
+        assert!(matches!(verify(&headers, &body).await, Err(AppError::InvalidSignature)));
+    }
+
+    #[sqlx::test]
+    async fn follow_and_undo_add_and_remove_the_follower(pool: PgPool) {
+        let store = test_support::store(pool);
+        let server = start_server();
+        let user_id = author(&store).await;
+
+        let follow = follow(&server, user_id);
+        receive(&store, &server, user_id, follow.clone()).await.unwrap();
+
+        let followers = store.get_followers(user_id).await.unwrap();
+        assert_eq!(followers.len(), 1);
+        assert_eq!(followers[0].actor_id, alice(&server));
+        assert_eq!(followers[0].inbox, format!("{}/inbox", alice(&server)));
+
+        let deliveries = store.claim_due_deliveries(DELIVERY_BATCH, DELIVERY_LEASE).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].inbox, format!("{}/inbox", alice(&server)));
+        assert_eq!(deliveries[0].activity["type"], "Accept");
+        assert_eq!(deliveries[0].activity["object"]["id"], follow["id"]);
+
+        let undo = document(json!({
+            "id": format!("{}#undo/{}", alice(&server), Uuid::new_v4()),
+            "type": "Undo",
+            "actor": alice(&server),
+            "object": follow,
+        }));
+        receive(&store, &server, user_id, undo).await.unwrap();
+        assert!(store.get_followers(user_id).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn activity_for_someone_other_than_the_signer_is_refused(pool: PgPool) {
+        let store = test_support::store(pool);
+        let server = start_server();
+        let user_id = author(&store).await;
+
+        let mut follow = follow(&server, user_id);
+        follow["actor"] = json!(format!("{}/users/bob", server.base));
+        assert!(matches!(receive(&store, &server, user_id, follow).await, Err(AppError::Forbidden)));
+        assert!(store.get_followers(user_id).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn deliveries_are_signed_and_retried_or_given_up(pool: PgPool) {
+        let mut store = test_support::store(pool);
+        let server = start_server();
+        let user_id = author(&store).await;
+
+        let run = Uuid::new_v4();
+        let inbox = |status: u16| format!("{}/inbox/{}/{}", server.base, run, status);
+        for status in [202, 503, 404] {
+            let actor = format!("{}/users/{}-{}", server.base, run, status);
+            store.add_follower(user_id, &actor, &inbox(status), None).await.unwrap();
+        }
+
+        let blog = store
+            .post_blog(
+                "Hello, fediverse".to_string(),
+                user_id,
+                "First post".to_string(),
+                "2024-03-01".to_string(),
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        publish(&store, BASE_URL, blog.id).await.unwrap();
+        run_deliveries(&store, BASE_URL).await.unwrap();
+
+        let prefix = format!("/inbox/{}/", run);
+        let received = received_under(&server, &prefix);
+        assert_eq!(received.len(), 3);
+        let key = actor_key(&store, user_id).await.unwrap();
+        for (path, headers, body) in &received {
+            check_signature(path, headers, body, &key);
+            let activity: Value = serde_json::from_slice(body).unwrap();
+            assert_eq!(activity["type"], "Create");
+            assert_eq!(activity["actor"], actor_url(BASE_URL, user_id));
+            assert_eq!(activity["object"]["id"], object_url(BASE_URL, &blog));
+        }
+
+        // Delivered and refused ones are done; the one that failed waits.
+        let left: Vec<(String, i32, bool)> = sqlx::query_as(
+            "SELECT inbox, attempts, next_attempt_at > NOW() FROM activity_deliveries",
+        )
+        .fetch_all(&store.conn_pool)
+        .await
+        .unwrap();
+        assert_eq!(left, vec![(inbox(503), 1, true)]);
+
+        // It isn't due again yet.
+        run_deliveries(&store, BASE_URL).await.unwrap();
+        assert_eq!(received_under(&server, &prefix).len(), 3);
+
+        // Once it is, it is tried again, and given up after the last attempt.
+        sqlx::query("UPDATE activity_deliveries SET attempts = $1, next_attempt_at = NOW()")
+            .bind(MAX_DELIVERY_ATTEMPTS - 1)
+            .execute(&store.conn_pool)
+            .await
+            .unwrap();
+        run_deliveries(&store, BASE_URL).await.unwrap();
+        assert_eq!(received_under(&server, &prefix).len(), 4);
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM activity_deliveries")
+            .fetch_one(&store.conn_pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+}
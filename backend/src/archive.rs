@@ -0,0 +1,320 @@
+//! Export archives, for backups and for leaving: a zip with `manifest.json`
+//! holding the users, tags and comments, one Markdown file per post under
+//! `posts/`, and the uploaded media under `media/`.
+//!
+//! The post files have the same front matter [`crate::markdown_import`]
+//! reads, so they work on their own in most static site generators too.
+//! [`restore`] loads an archive back into an empty database.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde_derive::Serialize;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::handlers::hash_password;
+use crate::markdown_import;
+use crate::micropub::media_dir;
+use crate::models::archive::{ArchiveManifest, ArchivePost, ArchiveTag};
+use crate::models::blog::Blog;
+use crate::models::imports::{ImportOutcome, ImportReport};
+use crate::models::tokens::generate_token;
+
+/// Bumped when the layout changes in a way older restores can't read.
+pub const ARCHIVE_VERSION: u32 = 1;
+pub const ARCHIVE_CONTENT_TYPE: &str = "application/zip";
+
+const MANIFEST_FILE: &str = "manifest.json";
+const POSTS_DIR: &str = "posts/";
+const MEDIA_DIR: &str = "media/";
+
+/// Restores hold the whole archive in memory, so we stop reading past this
+/// much uncompressed data rather than trust the sizes a zip claims.
+const MAX_ARCHIVE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// What to call a download of today's archive.
+pub fn file_name() -> String {
+    format!("blog-export-{}.zip", Utc::now().format("%Y-%m-%d"))
+}
+
+fn io_error(err: impl Into<anyhow::Error>) -> AppError {
+    AppError::Any(err.into())
+}
+
+#[derive(Serialize)]
+struct FrontMatter<'a> {
+    title: &'a str,
+    date: String,
+    author: &'a str,
+    tags: &'a [String],
+}
+
+fn post_file(blog: &Blog) -> String {
+    format!("{}{}-{}.md", POSTS_DIR, blog.published_at.format("%Y-%m-%d"), blog.id)
+}
+
+fn post_markdown(blog: &Blog) -> Result<String, AppError> {
+    let front_matter = serde_yaml::to_string(&FrontMatter {
+        title: &blog.title,
+        date: blog.published_at.to_rfc3339(),
+        author: &blog.author_handle,
+        tags: &blog.tags,
+    })
+    .map_err(io_error)?;
+
+    Ok(format!(
+        "---\n{}---\n\n{}\n",
+        front_matter,
+        blog.content.replace("\r\n", "\n")
+    ))
+}
+
+/// Writes an archive of everything in `store` and the media directory to
+/// `writer`, and hands it back.
+pub async fn export<W>(store: &Store, writer: W) -> Result<W, AppError>
+where
+    W: Write + Seek + Send + 'static,
+{
+    let users = store.export_users().await?;
+    let blogs = store.export_blogs().await?;
+    let comments = store.export_comments().await?;
+
+    let mut tags: BTreeMap<String, usize> = BTreeMap::new();
+    let mut posts = Vec::new();
+    let mut files = Vec::new();
+    for (blog, import_key) in blogs {
+        for tag in &blog.tags {
+            *tags.entry(tag.clone()).or_default() += 1;
+        }
+        let file = post_file(&blog);
+        files.push((file.clone(), post_markdown(&blog)?));
+        posts.push(ArchivePost {
+            id: blog.id,
+            file,
+            author_id: blog.author_id,
+            publish_date: blog.publish_date,
+            updated_at: blog.updated_at,
+            import_key,
+        });
+    }
+
+    let manifest = ArchiveManifest {
+        version: ARCHIVE_VERSION,
+        exported_at: Utc::now(),
+        users,
+        tags: tags
+            .into_iter()
+            .map(|(name, posts)| ArchiveTag { name, posts })
+            .collect(),
+        posts,
+        comments,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(io_error)?;
+
+    // Zipping and reading the media files blocks.
+    tokio::task::spawn_blocking(move || write_zip(writer, &manifest, &files, &media_dir()))
+        .await
+        .map_err(io_error)?
+}
+
+fn write_zip<W: Write + Seek>(
+    writer: W,
+    manifest: &[u8],
+    posts: &[(String, String)],
+    media: &Path,
+) -> Result<W, AppError> {
+    let mut zip = ZipWriter::new(writer);
+    let text = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    // Images and video are compressed already.
+    let binary = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    zip.start_file(MANIFEST_FILE, text).map_err(io_error)?;
+    zip.write_all(manifest).map_err(io_error)?;
+    for (file, markdown) in posts {
+        zip.start_file(file.as_str(), text).map_err(io_error)?;
+        zip.write_all(markdown.as_bytes()).map_err(io_error)?;
+    }
+
+    // Nothing has been uploaded yet if there is no media directory.
+    if let Ok(entries) = std::fs::read_dir(media) {
+        let mut paths = entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io_error)?;
+        paths.sort();
+        for path in paths.iter().filter(|path| path.is_file()) {
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            zip.start_file(format!("{}{}", MEDIA_DIR, name.to_string_lossy()), binary)
+                .map_err(io_error)?;
+            let mut file = std::fs::File::open(path).map_err(io_error)?;
+            std::io::copy(&mut file, &mut zip).map_err(io_error)?;
+        }
+    }
+
+    zip.finish().map_err(io_error)
+}
+
+/// An archive read into memory.
+pub struct Archive {
+    pub manifest: ArchiveManifest,
+    /// Post files by their path in the archive.
+    pub posts: HashMap<String, String>,
+    /// Media files by name.
+    pub media: Vec<(String, Vec<u8>)>,
+}
+
+pub fn read<R: Read + Seek>(reader: R) -> Result<Archive, AppError> {
+    let mut zip = ZipArchive::new(reader).map_err(|err| AppError::invalid_import(format!("That isn't a zip file: {}", err)))?;
+
+    let mut manifest = None;
+    let mut posts = HashMap::new();
+    let mut media = Vec::new();
+    let mut remaining = MAX_ARCHIVE_BYTES;
+    for i in 0..zip.len() {
+        let mut file = zip
+            .by_index(i)
+            .map_err(|err| AppError::invalid_import(format!("The archive is damaged: {}", err)))?;
+        if !file.is_file() {
+            continue;
+        }
+        // Names that would leave the archive's folder are passed over.
+        let Some(path) = file.enclosed_name().map(PathBuf::from) else {
+            continue;
+        };
+        let name = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut bytes = Vec::new();
+        (&mut file)
+            .take(remaining + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|err| AppError::invalid_import(format!("Could not read {} from the archive: {}", name, err)))?;
+        remaining = remaining.checked_sub(bytes.len() as u64).ok_or_else(|| {
+            AppError::invalid_import(format!(
+                "The archive holds more than {} MB, too much to restore",
+                MAX_ARCHIVE_BYTES / 1024 / 1024
+            ))
+        })?;
+
+        if name == MANIFEST_FILE {
+            manifest = Some(
+                serde_json::from_slice::<ArchiveManifest>(&bytes)
+                    .map_err(|err| AppError::invalid_import(format!("The manifest isn't valid: {}", err)))?,
+            );
+        } else if name.starts_with(POSTS_DIR) {
+            let text = String::from_utf8(bytes).map_err(|_| AppError::invalid_import(format!("{} isn't UTF-8", name)))?;
+            posts.insert(name, text);
+        } else if let Some(file_name) = name.strip_prefix(MEDIA_DIR).filter(|name| !name.contains('/')) {
+            media.push((file_name.to_string(), bytes));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| AppError::invalid_import("That isn't an export archive, it has no manifest.json"))?;
+    if manifest.version > ARCHIVE_VERSION {
+        return Err(AppError::invalid_import(format!(
+            "That archive is version {}, newer than this blog can restore",
+            manifest.version
+        )));
+    }
+
+    Ok(Archive {
+        manifest,
+        posts,
+        media,
+    })
+}
+
+/// Loads an archive into a database with no accounts in it yet. Users and
+/// posts keep their ids, so links to them still work. Users come back with
+/// a password nobody knows, so they sign in with "forgot password".
+///
+/// Everything goes into the database in one transaction, and the media files
+/// are only written once it has committed, so a failed restore can simply
+/// be run again.
+pub async fn restore(store: &Store, archive: Archive) -> Result<ImportReport, AppError> {
+    let mut report = ImportReport::default();
+    let manifest = archive.manifest;
+    let mut tx = store.begin().await?;
+    // Nobody can sign up between the check and the commit.
+    if store.lock_users(&mut tx).await? {
+        return Err(AppError::invalid_import("Archives can only be restored into an empty database"));
+    }
+
+    let mut users = HashSet::new();
+    for user in &manifest.users {
+        let password = hash_password(&generate_token().0)?;
+        store.restore_user(&mut tx, user, &password).await?;
+        users.insert(user.id);
+        report.users_created += 1;
+    }
+
+    let mut posts = HashSet::new();
+    for post in &manifest.posts {
+        let Some(text) = archive.posts.get(&post.file) else {
+            report.skipped.push(format!("{} is missing from the archive", post.file));
+            continue;
+        };
+        if !users.contains(&post.author_id) {
+            report.skipped.push(format!("{} is by a user who isn't in the archive", post.file));
+            continue;
+        }
+        let parsed = match markdown_import::parse_file(&post.file, text) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => {
+                report.skipped.push(format!("{} is a draft", post.file));
+                continue;
+            }
+            Err(reason) => {
+                report.skipped.push(format!("{} {}", post.file, reason));
+                continue;
+            }
+        };
+
+        let mut blog = Blog::new(parsed.title, post.author_id, parsed.content, post.publish_date.clone());
+        blog.id = post.id;
+        blog.tags = parsed.tags;
+        blog.published_at = parsed.published_at;
+        blog.updated_at = post.updated_at;
+        store.restore_blog(&mut tx, &blog, post.import_key.as_deref()).await?;
+        posts.insert(post.id);
+        report.count_post(ImportOutcome::Created);
+    }
+
+    for comment in &manifest.comments {
+        if !posts.contains(&comment.post_id) {
+            report
+                .skipped
+                .push(format!("A comment by {} on a post that wasn't restored", comment.author_name));
+            continue;
+        }
+        store.restore_comment(&mut tx, comment).await?;
+        report.comments_created += 1;
+    }
+
+    store.reset_restored_sequences(&mut tx).await?;
+    tx.commit().await?;
+
+    let dir = media_dir();
+    tokio::fs::create_dir_all(&dir).await.map_err(io_error)?;
+    for (name, bytes) in archive.media {
+        let path = dir.join(&name);
+        if tokio::fs::try_exists(&path).await.map_err(io_error)? {
+            report.skipped.push(format!("media/{} is already in {}", name, dir.display()));
+            continue;
+        }
+        tokio::fs::write(&path, bytes).await.map_err(io_error)?;
+        report.media_created += 1;
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,124 @@
+//! Every cookie we set goes through the site wide [`CookiePolicy`], so the
+//! Secure flag, SameSite, domain, path and name prefix are decided in one
+//! place from the environment.
+
+use std::time::Duration;
+
+use cookie::{Cookie, SameSite};
+use http::HeaderMap;
+use once_cell::sync::Lazy;
+use tracing::warn;
+
+use crate::get_public_url;
+use crate::models::users::read_cookie;
+
+/// A cookie we set: its base name and, for cookies that only a few endpoints
+/// need, the path below the policy's base path that it is limited to.
+pub struct CookieSpec {
+    pub name: &'static str,
+    pub path: Option<&'static str>,
+    /// Overrides the policy's SameSite for cookies that only work with one
+    /// setting.
+    pub same_site: Option<SameSite>,
+}
+
+pub struct CookiePolicy {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    pub path: String,
+    pub host_prefix: bool,
+}
+
+impl CookiePolicy {
+    /// Reads `COOKIE_SECURE`, `COOKIE_SAMESITE`, `COOKIE_DOMAIN`, `COOKIE_PATH`
+    /// and `COOKIE_HOST_PREFIX`. Cookies are Secure by default whenever
+    /// `PUBLIC_URL` is https.
+    pub fn from_env() -> Self {
+        let host_prefix = env_flag("COOKIE_HOST_PREFIX").unwrap_or(false);
+        let same_site = match std::env::var("COOKIE_SAMESITE")
+            .unwrap_or_else(|_| "lax".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            other => panic!("COOKIE_SAMESITE must be strict, lax or none, not {}", other),
+        };
+
+        let mut secure = env_flag("COOKIE_SECURE")
+            .unwrap_or_else(|| get_public_url().starts_with("https://"));
+        // Browsers drop prefixed and SameSite=None cookies that aren't Secure.
+        if !secure && (host_prefix || same_site == SameSite::None) {
+            warn!("COOKIE_HOST_PREFIX and COOKIE_SAMESITE=none need Secure cookies, turning it on");
+            secure = true;
+        }
+
+        let path = std::env::var("COOKIE_PATH")
+            .ok()
+            .filter(|path| path.starts_with('/'))
+            .unwrap_or_else(|| "/".to_string());
+
+        Self {
+            secure,
+            same_site,
+            domain: std::env::var("COOKIE_DOMAIN").ok().filter(|domain| !domain.is_empty()),
+            path,
+            host_prefix,
+        }
+    }
+
+    fn path_for(&self, spec: &CookieSpec) -> String {
+        match spec.path {
+            Some(path) => format!("{}{}", self.path.trim_end_matches('/'), path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The name the cookie is stored under. With `COOKIE_HOST_PREFIX` on,
+    /// cookies for the whole host get `__Host-`, which browsers only accept
+    /// for path `/` and no domain; everything else falls back to `__Secure-`.
+    pub fn name_for(&self, spec: &CookieSpec) -> String {
+        if !self.host_prefix {
+            spec.name.to_string()
+        } else if self.path_for(spec) == "/" && self.domain.is_none() {
+            format!("__Host-{}", spec.name)
+        } else {
+            format!("__Secure-{}", spec.name)
+        }
+    }
+
+    /// An HttpOnly cookie that expires after `max_age`, or when the browser
+    /// closes if there is none.
+    pub fn build(&self, spec: &CookieSpec, value: String, max_age: Option<Duration>) -> Cookie<'static> {
+        let mut builder = Cookie::build(self.name_for(spec), value)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(spec.same_site.unwrap_or(self.same_site))
+            .path(self.path_for(spec));
+        if let Some(domain) = &self.domain {
+            builder = builder.domain(domain.clone());
+        }
+        if let Some(max_age) = max_age {
+            builder = builder.max_age(cookie::time::Duration::seconds(max_age.as_secs() as i64));
+        }
+
+        builder.finish()
+    }
+
+    /// A cookie that makes the browser forget `spec` straight away.
+    pub fn clear(&self, spec: &CookieSpec) -> Cookie<'static> {
+        self.build(spec, String::new(), Some(Duration::ZERO))
+    }
+
+    pub fn read(&self, headers: &HeaderMap, spec: &CookieSpec) -> Option<String> {
+        read_cookie(headers, &self.name_for(spec))
+    }
+}
+
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name).ok().map(|value| value == "true")
+}
+
+pub static COOKIE_POLICY: Lazy<CookiePolicy> = Lazy::new(CookiePolicy::from_env);
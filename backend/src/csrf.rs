@@ -0,0 +1,202 @@
+//! Cross-site request forgery protection using double-submit cookies.
+//!
+//! Every browser gets a random token in the `csrf` cookie. Unsafe requests
+//! have to echo it back, either in the `csrf_token` form field that
+//! [`CsrfField`] renders into templates or in the `X-CSRF-Token` header for
+//! scripts. Another site can make the browser send the cookie but can't read
+//! it, so it can't produce a matching token.
+
+use std::collections::HashMap;
+
+use axum::middleware::Next;
+use axum::response::Response;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, COOKIE, SET_COOKIE};
+use http::{HeaderValue, Method, Request};
+use hyper::body::HttpBody;
+use hyper::Body;
+use rand::RngCore;
+
+use crate::cookies::{CookieSpec, COOKIE_POLICY};
+use crate::error::AppError;
+use crate::models::users::bearer_token;
+
+pub const CSRF_COOKIE: CookieSpec = CookieSpec {
+    name: "csrf",
+    path: None,
+    same_site: None,
+};
+pub const CSRF_FIELD: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Endpoints other sites and apps post to. They never act on the caller's
+/// cookies, so there is nothing to forge.
+const EXEMPT_PATHS: [&str; 3] = ["/webmention", "/micropub", "/micropub/media"];
+
+/// Form bodies bigger than this are rejected rather than buffered.
+const MAX_FORM_BYTES: usize = 2 * 1024 * 1024;
+
+tokio::task_local! {
+    /// The token for the request being handled, for [`CsrfField`] to render.
+    static CSRF_TOKEN: String;
+}
+
+pub async fn csrf_protect(request: Request<Body>, next: Next<Body>) -> Result<Response, AppError> {
+    let existing_token =
+        COOKIE_POLICY.read(request.headers(), &CSRF_COOKIE).filter(|token| is_well_formed(token));
+    let token = existing_token.clone().unwrap_or_else(generate_token);
+
+    let request = if needs_token(&request) {
+        verify(request, &token).await?
+    } else {
+        request
+    };
+
+    let mut response = CSRF_TOKEN.scope(token.clone(), next.run(request)).await;
+
+    if existing_token.is_none() {
+        let cookie = COOKIE_POLICY.build(&CSRF_COOKIE, token, None);
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Requests that could ride on a browser's cookies. Requests with a bearer
+/// token are authenticated by it and never by cookies, and cookie-less JSON
+/// can't be sent cross-site without a CORS preflight. Any other
+/// `Authorization` scheme falls back to the cookies, so it needs the token.
+fn needs_token(request: &Request<Body>) -> bool {
+    if matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return false;
+    }
+    if bearer_token(request.headers()).is_some() {
+        return false;
+    }
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return false;
+    }
+
+    request.headers().contains_key(COOKIE) || is_form(request)
+}
+
+fn is_form(request: &Request<Body>) -> bool {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    content_type.starts_with("application/x-www-form-urlencoded")
+        || content_type.starts_with("multipart/form-data")
+        || content_type.starts_with("text/plain")
+        || content_type.is_empty()
+}
+
+/// Checks the submitted token against the cookie, buffering form bodies to
+/// find the field and handing back a request with the body put back.
+async fn verify(request: Request<Body>, expected: &str) -> Result<Request<Body>, AppError> {
+    let header_token = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if let Some(submitted) = header_token {
+        return if constant_time_eq(&submitted, expected) {
+            Ok(request)
+        } else {
+            Err(AppError::InvalidCsrfToken)
+        };
+    }
+
+    let is_urlencoded = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_urlencoded {
+        return Err(AppError::InvalidCsrfToken);
+    }
+
+    let (parts, body) = request.into_parts();
+    let declared_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared_length.is_some_and(|length| length > MAX_FORM_BYTES) {
+        return Err(AppError::InvalidCsrfToken);
+    }
+    let bytes = read_limited(body).await?;
+
+    let fields: HashMap<_, _> = url::form_urlencoded::parse(&bytes).into_owned().collect();
+    match fields.get(CSRF_FIELD) {
+        Some(submitted) if constant_time_eq(submitted, expected) => {
+            Ok(Request::from_parts(parts, Body::from(bytes)))
+        }
+        _ => Err(AppError::InvalidCsrfToken),
+    }
+}
+
+/// The whole body, or an error as soon as it grows past [`MAX_FORM_BYTES`],
+/// as a missing or false `Content-Length` doesn't stop the client sending more.
+async fn read_limited(mut body: Body) -> Result<Vec<u8>, AppError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| AppError::InvalidCsrfToken)?;
+        if bytes.len() + chunk.len() > MAX_FORM_BYTES {
+            return Err(AppError::InvalidCsrfToken);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn is_well_formed(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The current request's token, or an empty string outside of a request.
+pub fn current_token() -> String {
+    CSRF_TOKEN.try_with(String::clone).unwrap_or_default()
+}
+
+/// `{{ csrf_field() }}` renders the hidden input every form that posts back
+/// to us needs.
+pub struct CsrfField;
+
+impl tera::Function for CsrfField {
+    fn call(&self, _args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+        Ok(tera::Value::String(format!(
+            r#"<input type="hidden" name="{}" value="{}">"#,
+            CSRF_FIELD,
+            current_token()
+        )))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// `{{ csrf_token() }}` for scripts, which send it in the `X-CSRF-Token` header.
+pub struct CsrfToken;
+
+impl tera::Function for CsrfToken {
+    fn call(&self, _args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+        Ok(tera::Value::String(current_token()))
+    }
+}
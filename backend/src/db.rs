@@ -1,19 +1,43 @@
 use axum::Json;
+use chrono::{DateTime, Utc};
 use serde_json::Value;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use sqlx::postgres::PgPoolOptions;
-use sqlx::{PgPool, Row};
+use sqlx::postgres::PgRow;
+use sqlx::{PgConnection, PgPool, Postgres, Row, Transaction};
 use tracing::info;
+use uuid::Uuid;
 
 use crate::error::AppError;
-use crate::models::users::{User, UserSignup};
+use crate::keys::SigningKey;
+use crate::mail::{self, MailTransport};
+use crate::models::api_tokens::ApiToken;
+use crate::models::archive::{ArchiveComment, ArchiveUser};
+use crate::models::comments::Comment;
+use crate::models::federation::{ActorKey, Delivery, Follower};
+use crate::models::imports::ImportOutcome;
+use crate::models::invites::{Invite, InviteRedemption};
+use crate::models::roles::Role;
+use crate::models::throttle::{LoginThrottle, ThrottlePolicy};
+use crate::models::users::{User, UserSignup, UserSummary};
 use crate::models::blog::{Blog};
+use crate::models::passkeys::Passkey;
+use crate::models::profiles::{default_handle, Profile};
+use crate::models::tokens::RefreshToken;
+use crate::models::two_factor::TotpSecret;
+use crate::models::webmentions::{Webmention, WebmentionStatus};
+use crate::remote::Remote;
 
 #[derive(Clone)]
 pub struct Store {
     pub conn_pool: PgPool,
     pub blogs: Arc<Mutex<Vec<Blog>>>,
+    pub mailer: Arc<dyn MailTransport>,
+    pub http_client: reqwest::Client,
+    /// For URLs other people choose, see [`crate::remote`].
+    pub remote: Remote,
 }
 
 pub async fn new_pool() -> PgPool {
@@ -39,6 +63,9 @@ impl Store {
       Self {
           conn_pool: pool,
           blogs: Default::default(),
+          mailer: mail::transport_from_env(),
+          http_client: reqwest::Client::new(),
+          remote: Remote::from_env(),
       }
   }
 
@@ -57,7 +84,7 @@ impl Store {
   pub async fn get_user(&self, email: &str) -> Result<User, AppError> {
     let user = sqlx::query_as::<_, User>(
         r#"
-            SELECT email, password, is_admin FROM users WHERE email = $1
+            SELECT id, email, password, role, email_verified FROM users WHERE email = $1
         "#,
     )
     .bind(email)
@@ -67,136 +94,1841 @@ impl Store {
     Ok(user)
   }
 
+  pub async fn get_user_by_id(&self, id: i32) -> Result<User, AppError> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+            SELECT id, email, password, role, email_verified FROM users WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_one(&self.conn_pool)
+    .await?;
+
+    Ok(user)
+  }
+
   pub async fn create_user(&self, user: UserSignup) -> Result<Json<Value>, AppError> {
-    let result = sqlx::query("INSERT INTO users(email, password, is_admin) values ($1, $2, $3)")
+    let mut tx = self.conn_pool.begin().await?;
+
+    let user_id: i32 = sqlx::query_scalar(
+        "INSERT INTO users(email, password, role) VALUES ($1, $2, $3) RETURNING id",
+    )
         .bind(&user.email)
         .bind(&user.password)
-        .bind(user.is_admin)
-        .execute(&self.conn_pool)
+        .bind(Role::DEFAULT)
+        .fetch_one(&mut *tx)
         .await
         .map_err(|_| AppError::InternalServerError)?;
 
+    create_default_profile(&mut tx, user_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(
+        serde_json::json!({"message": "User created successfully!"}),
+    ))
+  }
+
+  pub async fn create_refresh_token(
+    &self,
+    user_id: i32,
+    family_id: Uuid,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(user_id)
+    .bind(family_id)
+    .bind(token_hash)
+    .bind(expires_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn get_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, AppError> {
+    let token = sqlx::query_as::<_, RefreshToken>(
+        r#"
+            SELECT id, user_id, family_id, token_hash, expires_at, used_at, revoked_at
+            FROM refresh_tokens WHERE token_hash = $1
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(token)
+  }
+
+  /// Marks a refresh token as exchanged. Returns false if someone else got there
+  /// first, which means the token was replayed.
+  pub async fn mark_refresh_token_used(&self, id: i32) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE refresh_tokens SET used_at = NOW()
+            WHERE id = $1 AND used_at IS NULL AND revoked_at IS NULL
+        "#,
+    )
+    .bind(id)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+  }
+
+  pub async fn revoke_token_family(&self, family_id: Uuid) -> Result<u64, AppError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE refresh_tokens SET revoked_at = NOW()
+            WHERE family_id = $1 AND revoked_at IS NULL
+        "#,
+    )
+    .bind(family_id)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected())
+  }
+
+  pub async fn list_users(&self) -> Result<Vec<UserSummary>, AppError> {
+    let users = sqlx::query_as::<_, UserSummary>(
+        "SELECT id, email, role, email_verified FROM users ORDER BY id",
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(users)
+  }
+
+  pub async fn set_user_role(&self, user_id: i32, role: Role) -> Result<(), AppError> {
+    let result = sqlx::query("UPDATE users SET role = $1 WHERE id = $2")
+        .bind(role)
+        .bind(user_id)
+        .execute(&self.conn_pool)
+        .await?;
+
     if result.rows_affected() < 1 {
-        Err(AppError::InternalServerError)
+        return Err(AppError::NotFound);
+    }
+
+    Ok(())
+  }
+
+  pub async fn mark_email_verified(&self, user_id: i32) -> Result<(), AppError> {
+    sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = $1")
+        .bind(user_id)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(())
+  }
+
+  /// Records that a verification email is about to be sent. Returns false when
+  /// the last one went out less than `cooldown_secs` ago or the address is
+  /// already verified, in which case nothing should be sent.
+  pub async fn claim_verification_send(&self, user_id: i32, cooldown_secs: i64) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE users SET verification_sent_at = NOW()
+            WHERE id = $1
+              AND email_verified = FALSE
+              AND (verification_sent_at IS NULL
+                   OR verification_sent_at < NOW() - make_interval(secs => $2))
+        "#,
+    )
+    .bind(user_id)
+    .bind(cooldown_secs as f64)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+  }
+
+  /// Like [`Store::claim_verification_send`], for confirmation links sent to
+  /// a new address the user wants to switch to.
+  pub async fn claim_email_change_send(&self, user_id: i32, cooldown_secs: i64) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE users SET email_change_sent_at = NOW()
+            WHERE id = $1
+              AND (email_change_sent_at IS NULL
+                   OR email_change_sent_at < NOW() - make_interval(secs => $2))
+        "#,
+    )
+    .bind(user_id)
+    .bind(cooldown_secs as f64)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+  }
+
+  /// Moves an account to a confirmed new address, as long as it still has
+  /// the address the change was requested from.
+  pub async fn change_email(&self, user_id: i32, old_email: &str, new_email: &str) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        "UPDATE users SET email = $3, email_verified = TRUE WHERE id = $1 AND email = $2",
+    )
+    .bind(user_id)
+    .bind(old_email)
+    .bind(new_email)
+    .execute(&self.conn_pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => AppError::UserAlreadyExists,
+        err => AppError::Database(err),
+    })?;
+
+    Ok(result.rows_affected() == 1)
+  }
+
+  pub async fn update_password(&self, user_id: i32, hashed_password: &str) -> Result<(), AppError> {
+    sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+        .bind(hashed_password)
+        .bind(user_id)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(())
+  }
+
+  /// Revokes every outstanding refresh token for a user, logging them out everywhere.
+  pub async fn revoke_user_refresh_tokens(&self, user_id: i32) -> Result<u64, AppError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE refresh_tokens SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected())
+  }
+
+  pub async fn create_password_reset(
+    &self,
+    user_id: i32,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO password_resets (user_id, token_hash, expires_at)
+            VALUES ($1, $2, $3)
+        "#,
+    )
+    .bind(user_id)
+    .bind(token_hash)
+    .bind(expires_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Checks that a reset token is still unused and unexpired without spending it.
+  pub async fn password_reset_is_valid(&self, token_hash: &str) -> Result<bool, AppError> {
+    let row = sqlx::query(
+        r#"
+            SELECT id FROM password_resets
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(row.is_some())
+  }
+
+  /// Spends a reset token and returns the user it belongs to. The update only
+  /// matches unused, unexpired tokens, so each one works at most once.
+  pub async fn consume_password_reset(&self, token_hash: &str) -> Result<Option<i32>, AppError> {
+    let row = sqlx::query(
+        r#"
+            UPDATE password_resets SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    if let Some(row) = row {
+        let user_id: i32 = row.get("user_id");
+        // Any other links that were sent out are now stale.
+        sqlx::query(
+            "UPDATE password_resets SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .execute(&self.conn_pool)
+        .await?;
+
+        Ok(Some(user_id))
     } else {
-        Ok(Json(
-            serde_json::json!({"message": "User created successfully!"}),
-        ))
+        Ok(None)
     }
   }
 
-  pub async fn post_blog(
-    &mut self,
-    title: String,
-    email: String,
-    content: String,
-    publish_date: String,
-  ) -> Result<Blog, AppError> {
-    let _res = sqlx::query!(
+  pub async fn get_setting(&self, key: &str) -> Result<Option<String>, AppError> {
+    let row = sqlx::query("SELECT value FROM settings WHERE key = $1")
+        .bind(key)
+        .fetch_optional(&self.conn_pool)
+        .await?;
+
+    Ok(row.map(|row| row.get("value")))
+  }
+
+  pub async fn set_setting(&self, key: &str, value: &str) -> Result<(), AppError> {
+    sqlx::query(
         r#"
-            INSERT INTO blog (title, email, content, publish_date)
-            VALUES ($1, $2, $3, $4)
-            RETURNING *
+            INSERT INTO settings (key, value) VALUES ($1, $2)
+            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value
         "#,
-        title,
-        email,
-        content,
-        publish_date,
     )
-    .fetch_one(&self.conn_pool)
+    .bind(key)
+    .bind(value)
+    .execute(&self.conn_pool)
     .await?;
 
-    let blog = Blog {
-        title,
-        email,
-        content,
-        publish_date,
-    };
+    Ok(())
+  }
 
-    Ok(blog)
+  pub async fn get_totp(&self, user_id: i32) -> Result<Option<TotpSecret>, AppError> {
+    let totp = sqlx::query_as::<_, TotpSecret>(
+        "SELECT user_id, secret, enabled, last_used_step FROM user_totp WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(totp)
   }
 
-  pub async fn get_all_blogs(&self) -> Result<Vec<Blog>, AppError> {
-    let blog_pages = sqlx::query("SELECT * FROM blog")
-        .fetch_all(&self.conn_pool)
-        .await?;
-
-    let mut res = Vec::new();
-    for blog in blog_pages {
-        let incoming_content: String = blog.get("content");
-        let mut parsed_content = Vec::new();
-
-        for line in incoming_content.split("\r\n") {
-            println!("{}", line);
-            // check for ###
-            if line.starts_with("###") {
-                let new_string = line.replace("###", "<h3>") + "</h3>";
-                parsed_content.push(new_string);
-            }
-
-            //check for ##
-            else if line.starts_with("##") {
-                let new_string = line.replace("##", "<h2>") + "</h2>";
-                parsed_content.push(new_string);
-            }
-
-            //check for #
-            else if line.starts_with('#') {
-                let new_string = line.replace('#', "<h1>") + "</h1>";
-                parsed_content.push(new_string);
-            }
-
-            //check for ** **
-            else if line.starts_with("**") && line.ends_with("**") {
-                let start_tag = "<strong>";
-                let end_tag = "</strong>";
-
-                let new_string = start_tag.to_string() + &line[2..line.len() - 2] + end_tag;
-                parsed_content.push(new_string);
-            }
-
-            //check for * *
-            else if line.starts_with('*') && line.ends_with('*') {
-                let start_tag = "<i>";
-                let end_tag = "</i>";
-
-                let new_string = start_tag.to_string() + &line[1..line.len() - 1] + end_tag;
-                parsed_content.push(new_string);
-            }
-
-            //check for ~~ ~~
-            else if line.starts_with("~~") && line.ends_with("~~") {
-                let start_tag = "<s>";
-                let end_tag = "</s>";
-
-                let new_string = start_tag.to_string() + &line[2..line.len() - 2] + end_tag;
-                parsed_content.push(new_string);
-            }
-
-            //check for ---
-            else if line.starts_with("---") {
-                parsed_content.push(line.replace("---", "<hr>"));
-            }
-
-            else {
-                parsed_content.push(line.to_string());
-            }
-        }
+  /// Stores a new, not yet confirmed secret, replacing any earlier unconfirmed one.
+  pub async fn save_pending_totp(&self, user_id: i32, secret: &str) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, last_used_step = 0
+            WHERE user_totp.enabled = FALSE
+        "#,
+    )
+    .bind(user_id)
+    .bind(secret)
+    .execute(&self.conn_pool)
+    .await?;
 
-        for line in &parsed_content {
-            println!("{}", line);
-        }
+    Ok(())
+  }
 
+  /// Records the time step of a code that was just accepted. Returns false if
+  /// that step (or a later one) was already used.
+  pub async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        "UPDATE user_totp SET last_used_step = $2 WHERE user_id = $1 AND last_used_step < $2",
+    )
+    .bind(user_id)
+    .bind(step)
+    .execute(&self.conn_pool)
+    .await?;
 
-        let new_blog = Blog {
-            title: blog.get("title"),
-            email: blog.get("email"),
-            content: parsed_content.join("<br>"),
-            publish_date: blog.get("publish_date"),
-        };
-        res.push(new_blog);
+    Ok(result.rows_affected() == 1)
+  }
+
+  /// Turns on a confirmed TOTP secret and replaces any previous recovery codes.
+  pub async fn enable_totp(&self, user_id: i32, recovery_code_hashes: &[String]) -> Result<(), AppError> {
+    let mut tx = self.conn_pool.begin().await?;
+
+    sqlx::query("UPDATE user_totp SET enabled = TRUE WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for code_hash in recovery_code_hashes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&mut *tx)
+            .await?;
     }
 
-    Ok(res)
+    tx.commit().await?;
+    Ok(())
+  }
+
+  pub async fn disable_totp(&self, user_id: i32) -> Result<(), AppError> {
+    let mut tx = self.conn_pool.begin().await?;
+
+    sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+  }
+
+  /// Spends a recovery code. Returns false if it doesn't exist or was already used.
+  pub async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        r#"
+            UPDATE recovery_codes SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(code_hash)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+  }
+
+  pub async fn create_webauthn_challenge(
+    &self,
+    challenge: &str,
+    user_id: Option<i32>,
+    purpose: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO webauthn_challenges (challenge, user_id, purpose, expires_at)
+            VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(challenge)
+    .bind(user_id)
+    .bind(purpose)
+    .bind(expires_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Removes a challenge so it can't be answered twice. Returns `None` if it
+  /// doesn't exist, has expired or was issued for something else, and
+  /// `Some(user_id)` otherwise.
+  pub async fn take_webauthn_challenge(
+    &self,
+    challenge: &str,
+    purpose: &str,
+  ) -> Result<Option<Option<i32>>, AppError> {
+    let row = sqlx::query(
+        r#"
+            DELETE FROM webauthn_challenges
+            WHERE challenge = $1 AND purpose = $2 AND expires_at > NOW()
+            RETURNING user_id
+        "#,
+    )
+    .bind(challenge)
+    .bind(purpose)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    sqlx::query("DELETE FROM webauthn_challenges WHERE expires_at <= NOW()")
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(row.map(|row| row.get("user_id")))
+  }
+
+  pub async fn get_passkeys(&self, user_id: i32) -> Result<Vec<Passkey>, AppError> {
+    let passkeys = sqlx::query_as::<_, Passkey>(
+        r#"
+            SELECT id, user_id, credential_id, public_key, sign_count, name, created_at
+            FROM passkeys WHERE user_id = $1 ORDER BY created_at
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(passkeys)
+  }
+
+  pub async fn get_passkey(&self, credential_id: &str) -> Result<Option<Passkey>, AppError> {
+    let passkey = sqlx::query_as::<_, Passkey>(
+        r#"
+            SELECT id, user_id, credential_id, public_key, sign_count, name, created_at
+            FROM passkeys WHERE credential_id = $1
+        "#,
+    )
+    .bind(credential_id)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(passkey)
+  }
+
+  pub async fn create_passkey(
+    &self,
+    user_id: i32,
+    credential_id: &str,
+    public_key: &[u8],
+    sign_count: i64,
+    name: &str,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO passkeys (user_id, credential_id, public_key, sign_count, name)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(user_id)
+    .bind(credential_id)
+    .bind(public_key)
+    .bind(sign_count)
+    .bind(name)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn update_passkey_sign_count(&self, id: i32, sign_count: i64) -> Result<(), AppError> {
+    sqlx::query("UPDATE passkeys SET sign_count = $2, last_used_at = NOW() WHERE id = $1")
+        .bind(id)
+        .bind(sign_count)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(())
+  }
+
+  /// Creates an account whose email address has already been confirmed by
+  /// someone we trust, such as an identity provider.
+  pub async fn create_verified_user(&self, email: &str, hashed_password: &str) -> Result<User, AppError> {
+    let mut tx = self.conn_pool.begin().await?;
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+            INSERT INTO users (email, password, role, email_verified)
+            VALUES ($1, $2, $3, TRUE)
+            RETURNING id, email, password, role, email_verified
+        "#,
+    )
+    .bind(email)
+    .bind(hashed_password)
+    .bind(Role::DEFAULT)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    create_default_profile(&mut tx, user.id).await?;
+    tx.commit().await?;
+
+    Ok(user)
+  }
+
+  pub async fn get_oidc_identity(&self, issuer: &str, subject: &str) -> Result<Option<i32>, AppError> {
+    let row = sqlx::query("SELECT user_id FROM oidc_identities WHERE issuer = $1 AND subject = $2")
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(&self.conn_pool)
+        .await?;
+
+    Ok(row.map(|row| row.get("user_id")))
+  }
+
+  pub async fn link_oidc_identity(
+    &self,
+    user_id: i32,
+    issuer: &str,
+    subject: &str,
+    email: Option<&str>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO oidc_identities (user_id, issuer, subject, email)
+            VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(user_id)
+    .bind(issuer)
+    .bind(subject)
+    .bind(email)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn create_api_token(
+    &self,
+    user_id: i32,
+    name: &str,
+    token_hash: &str,
+    scopes: &[String],
+    expires_at: Option<DateTime<Utc>>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(token_hash)
+    .bind(scopes)
+    .bind(expires_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
   }
+
+  /// Lists a user's tokens that haven't been revoked, including expired ones
+  /// so the user can see why a script stopped working.
+  pub async fn list_api_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, AppError> {
+    let tokens = sqlx::query_as::<_, ApiToken>(
+        r#"
+            SELECT id, user_id, name, scopes, created_at, last_used_at, expires_at
+            FROM api_tokens WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY created_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(tokens)
+  }
+
+  pub async fn revoke_api_token(&self, user_id: i32, id: i32) -> Result<(), AppError> {
+    let result = sqlx::query(
+        "UPDATE api_tokens SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(&self.conn_pool)
+    .await?;
+
+    if result.rows_affected() < 1 {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(())
+  }
+
+  /// Looks up a live token by its hash, records that it was used and
+  /// returns it together with its owner.
+  pub async fn use_api_token(&self, token_hash: &str) -> Result<Option<(ApiToken, User)>, AppError> {
+    let token = sqlx::query_as::<_, ApiToken>(
+        r#"
+            UPDATE api_tokens SET last_used_at = NOW()
+            WHERE token_hash = $1
+              AND revoked_at IS NULL
+              AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING id, user_id, name, scopes, created_at, last_used_at, expires_at
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    match token {
+        Some(token) => {
+            let user = self.get_user_by_id(token.user_id).await?;
+            Ok(Some((token, user)))
+        }
+        None => Ok(None),
+    }
+  }
+
+  /// Like [`Store::get_user`], but a missing account is `None` rather than an error.
+  pub async fn find_user(&self, email: &str) -> Result<Option<User>, AppError> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+            SELECT id, email, password, role, email_verified FROM users WHERE email = $1
+        "#,
+    )
+    .bind(email)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(user)
+  }
+
+  /// The first of `keys` that is still waiting out a backoff or lockout, if any.
+  pub async fn get_active_throttle(&self, keys: &[String]) -> Result<Option<LoginThrottle>, AppError> {
+    let throttle = sqlx::query_as::<_, LoginThrottle>(
+        r#"
+            SELECT key, failures, locked_until FROM login_throttles
+            WHERE key = ANY($1) AND locked_until > NOW()
+            ORDER BY locked_until DESC
+            LIMIT 1
+        "#,
+    )
+    .bind(keys)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(throttle)
+  }
+
+  /// Counts a failed login, or anything else limited the same way, against
+  /// `key` and makes it wait according to `policy`.
+  /// Failures older than an hour are forgotten. Returns the new failure count.
+  pub async fn record_login_failure(&self, key: &str, policy: &ThrottlePolicy) -> Result<i32, AppError> {
+    let failures: i32 = sqlx::query_scalar(
+        r#"
+            INSERT INTO login_throttles (key, failures, last_failure_at) VALUES ($1, 1, NOW())
+            ON CONFLICT (key) DO UPDATE SET
+              failures = CASE
+                WHEN login_throttles.last_failure_at < NOW() - INTERVAL '1 hour' THEN 1
+                ELSE login_throttles.failures + 1
+              END,
+              last_failure_at = NOW()
+            RETURNING failures
+        "#,
+    )
+    .bind(key)
+    .fetch_one(&self.conn_pool)
+    .await?;
+
+    if let Some(delay) = policy.delay_after(failures) {
+        sqlx::query(
+            "UPDATE login_throttles SET locked_until = NOW() + make_interval(secs => $2) WHERE key = $1",
+        )
+        .bind(key)
+        .bind(delay.as_secs() as f64)
+        .execute(&self.conn_pool)
+        .await?;
+    }
+
+    Ok(failures)
+  }
+
+  pub async fn clear_login_throttle(&self, key: &str) -> Result<bool, AppError> {
+    let result = sqlx::query("DELETE FROM login_throttles WHERE key = $1")
+        .bind(key)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  pub async fn list_lockouts(&self) -> Result<Vec<LoginThrottle>, AppError> {
+    let lockouts = sqlx::query_as::<_, LoginThrottle>(
+        r#"
+            SELECT key, failures, locked_until FROM login_throttles
+            WHERE locked_until > NOW()
+            ORDER BY locked_until DESC
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(lockouts)
+  }
+
+  /// Keeps anyone from signing up until `conn`'s transaction ends, and says
+  /// whether there are accounts already, for restoring into an empty database.
+  pub async fn lock_users(&self, conn: &mut PgConnection) -> Result<bool, AppError> {
+    sqlx::query("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *conn)
+        .await?;
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users)")
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(exists)
+  }
+
+  pub async fn has_users(&self) -> Result<bool, AppError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users)")
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+    Ok(exists)
+  }
+
+  /// Creates an account with an invite, using up one of its uses and giving
+  /// the account the invite's role. Fails if the invite is unknown, expired,
+  /// revoked or used up.
+  pub async fn create_invited_user(&self, user: &UserSignup, invite_hash: &str) -> Result<(), AppError> {
+    let mut tx = self.conn_pool.begin().await?;
+
+    let invite: Option<(i32, Role)> = sqlx::query_as(
+        r#"
+            UPDATE invites SET uses = uses + 1
+            WHERE code_hash = $1 AND uses < max_uses AND expires_at > NOW()
+            RETURNING id, role
+        "#,
+    )
+    .bind(invite_hash)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (invite_id, role) = invite.ok_or(AppError::InvalidInvite)?;
+
+    let user_id: i32 = sqlx::query_scalar(
+        "INSERT INTO users (email, password, role) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(&user.email)
+    .bind(&user.password)
+    .bind(role)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    create_default_profile(&mut tx, user_id).await?;
+
+    sqlx::query("INSERT INTO invite_redemptions (invite_id, user_id) VALUES ($1, $2)")
+        .bind(invite_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  pub async fn create_invite(
+    &self,
+    created_by: i32,
+    code_hash: &str,
+    role: Role,
+    max_uses: i32,
+    expires_at: DateTime<Utc>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO invites (code_hash, role, max_uses, created_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(code_hash)
+    .bind(role)
+    .bind(max_uses)
+    .bind(created_by)
+    .bind(expires_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn list_invites(&self) -> Result<Vec<Invite>, AppError> {
+    let invites = sqlx::query_as::<_, Invite>(
+        r#"
+            SELECT invites.id, invites.role, invites.max_uses, invites.uses,
+                   users.email AS created_by, invites.created_at, invites.expires_at,
+                   (invites.uses < invites.max_uses AND invites.expires_at > NOW()) AS outstanding
+            FROM invites
+            LEFT JOIN users ON users.id = invites.created_by
+            ORDER BY invites.created_at DESC
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(invites)
+  }
+
+  pub async fn list_invite_redemptions(&self) -> Result<Vec<InviteRedemption>, AppError> {
+    let redemptions = sqlx::query_as::<_, InviteRedemption>(
+        r#"
+            SELECT invite_redemptions.invite_id, users.email, invite_redemptions.redeemed_at
+            FROM invite_redemptions
+            JOIN users ON users.id = invite_redemptions.user_id
+            ORDER BY invite_redemptions.redeemed_at DESC
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(redemptions)
+  }
+
+  /// Stops an invite from being used again by expiring it now.
+  pub async fn revoke_invite(&self, id: i32) -> Result<(), AppError> {
+    let result = sqlx::query(
+        "UPDATE invites SET expires_at = NOW() WHERE id = $1 AND expires_at > NOW()",
+    )
+    .bind(id)
+    .execute(&self.conn_pool)
+    .await?;
+
+    if result.rows_affected() < 1 {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(())
+  }
+
+  pub async fn has_current_signing_key(&self) -> Result<bool, AppError> {
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM signing_keys WHERE retired_at IS NULL)",
+    )
+    .fetch_one(&self.conn_pool)
+    .await?;
+
+    Ok(exists)
+  }
+
+  /// Keys that may still verify tokens: the current one and any retired within `grace`.
+  pub async fn get_signing_keys(&self, grace: Duration) -> Result<Vec<SigningKey>, AppError> {
+    let keys = sqlx::query_as::<_, SigningKey>(
+        r#"
+            SELECT kid, algorithm, private_key, public_key, created_at, retired_at
+            FROM signing_keys
+            WHERE retired_at IS NULL OR retired_at > NOW() - make_interval(secs => $1)
+            ORDER BY created_at DESC
+        "#,
+    )
+    .bind(grace.as_secs() as f64)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(keys)
+  }
+
+  /// Makes `key` the signing key, retiring the current one and deleting keys
+  /// that have been retired for longer than `grace`.
+  pub async fn add_signing_key(&self, key: &SigningKey, grace: Duration) -> Result<(), AppError> {
+    let mut tx = self.conn_pool.begin().await?;
+
+    sqlx::query("UPDATE signing_keys SET retired_at = NOW() WHERE retired_at IS NULL")
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+            INSERT INTO signing_keys (kid, algorithm, private_key, public_key)
+            VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(&key.kid)
+    .bind(&key.algorithm)
+    .bind(&key.private_key)
+    .bind(&key.public_key)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM signing_keys WHERE retired_at < NOW() - make_interval(secs => $1)")
+        .bind(grace.as_secs() as f64)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  pub async fn get_profile(&self, user_id: i32) -> Result<Profile, AppError> {
+    let profile = sqlx::query_as::<_, Profile>(
+        r#"
+            SELECT user_id, handle, display_name, bio, website, avatar_url
+            FROM profiles WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(&self.conn_pool)
+    .await?;
+
+    Ok(profile)
+  }
+
+  pub async fn get_profile_by_handle(&self, handle: &str) -> Result<Option<Profile>, AppError> {
+    let profile = sqlx::query_as::<_, Profile>(
+        r#"
+            SELECT user_id, handle, display_name, bio, website, avatar_url
+            FROM profiles WHERE handle = $1
+        "#,
+    )
+    .bind(handle.to_lowercase())
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(profile)
+  }
+
+  pub async fn update_profile(&self, profile: &Profile) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            UPDATE profiles
+            SET handle = $2, display_name = $3, bio = $4, website = $5, avatar_url = $6, updated_at = NOW()
+            WHERE user_id = $1
+        "#,
+    )
+    .bind(profile.user_id)
+    .bind(&profile.handle)
+    .bind(&profile.display_name)
+    .bind(&profile.bio)
+    .bind(&profile.website)
+    .bind(&profile.avatar_url)
+    .execute(&self.conn_pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => AppError::HandleTaken,
+        err => AppError::Database(err),
+    })?;
+
+    Ok(())
+  }
+
+  pub async fn post_blog(
+    &mut self,
+    title: String,
+    author_id: i32,
+    content: String,
+    publish_date: String,
+    tags: Vec<String>,
+    published_at: Option<DateTime<Utc>>,
+  ) -> Result<Blog, AppError> {
+    let row = sqlx::query(
+        r#"
+            INSERT INTO blog (title, author_id, content, publish_date, tags, published_at)
+            VALUES ($1, $2, $3, $4, $5, COALESCE($6, NOW()))
+            RETURNING id, published_at, updated_at
+        "#,
+    )
+    .bind(&title)
+    .bind(author_id)
+    .bind(&content)
+    .bind(&publish_date)
+    .bind(&tags)
+    .bind(published_at)
+    .fetch_one(&self.conn_pool)
+    .await?;
+
+    let mut blog = Blog::new(title, author_id, content, publish_date);
+    blog.id = row.get("id");
+    blog.published_at = row.get("published_at");
+    blog.updated_at = row.get("updated_at");
+    blog.tags = tags;
+
+    Ok(blog)
+  }
+
+  pub async fn get_blog(&self, id: i32) -> Result<Option<Blog>, AppError> {
+    let blog_page = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            WHERE blog.id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(blog_page.as_ref().map(blog_from_row))
+  }
+
+  /// A post as its author wrote it, without the markdown rendered.
+  pub async fn get_blog_source(&self, id: i32) -> Result<Option<Blog>, AppError> {
+    let blog_page = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            WHERE blog.id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(blog_page.as_ref().map(blog_source_from_row))
+  }
+
+  /// The source of the post imported under `import_key`, if there is one.
+  pub async fn get_blog_by_import_key(&self, import_key: &str) -> Result<Option<Blog>, AppError> {
+    let blog_page = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            WHERE blog.import_key = $1
+        "#,
+    )
+    .bind(import_key)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(blog_page.as_ref().map(blog_source_from_row))
+  }
+
+  /// Saves a post's title, content, tags and publish time. `content` has to
+  /// be the source from [`Store::get_blog_source`].
+  pub async fn update_blog(&self, blog: &Blog) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            UPDATE blog
+            SET title = $2, content = $3, tags = $4, published_at = $5, updated_at = NOW()
+            WHERE id = $1
+        "#,
+    )
+    .bind(blog.id)
+    .bind(&blog.title)
+    .bind(&blog.content)
+    .bind(&blog.tags)
+    .bind(blog.published_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn delete_blog(&self, id: i32) -> Result<bool, AppError> {
+    let result = sqlx::query("DELETE FROM blog WHERE id = $1")
+        .bind(id)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Creates the post imported under `import_key`, or brings it up to date
+  /// if it was imported before. `updated_at` only moves when something
+  /// actually changed, so importing the same file twice is a no-op.
+  pub async fn upsert_imported_blog(&self, import_key: &str, blog: &Blog) -> Result<(i32, ImportOutcome), AppError> {
+    let row = sqlx::query(
+        r#"
+            INSERT INTO blog (import_key, title, author_id, content, publish_date, tags, published_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (import_key) DO UPDATE
+            SET title = EXCLUDED.title, author_id = EXCLUDED.author_id, content = EXCLUDED.content,
+                publish_date = EXCLUDED.publish_date, tags = EXCLUDED.tags,
+                published_at = EXCLUDED.published_at, updated_at = NOW()
+            WHERE (blog.title, blog.author_id, blog.content, blog.tags, blog.published_at)
+                IS DISTINCT FROM
+                (EXCLUDED.title, EXCLUDED.author_id, EXCLUDED.content, EXCLUDED.tags, EXCLUDED.published_at)
+            RETURNING id, (xmax = 0) AS inserted
+        "#,
+    )
+    .bind(import_key)
+    .bind(&blog.title)
+    .bind(blog.author_id)
+    .bind(&blog.content)
+    .bind(&blog.publish_date)
+    .bind(&blog.tags)
+    .bind(blog.published_at)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    if let Some(row) = row {
+        let outcome = if row.get("inserted") {
+            ImportOutcome::Created
+        } else {
+            ImportOutcome::Updated
+        };
+        return Ok((row.get("id"), outcome));
+    }
+
+    let id = sqlx::query_scalar::<_, i32>("SELECT id FROM blog WHERE import_key = $1")
+        .bind(import_key)
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+    Ok((id, ImportOutcome::Unchanged))
+  }
+
+  pub async fn get_all_blogs(&self) -> Result<Vec<Blog>, AppError> {
+    let blog_pages = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            ORDER BY blog.published_at DESC
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(blog_pages.iter().map(blog_from_row).collect())
+  }
+
+  pub async fn get_blogs_by_author(&self, user_id: i32) -> Result<Vec<Blog>, AppError> {
+    let blog_pages = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            WHERE blog.author_id = $1
+            ORDER BY blog.published_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(blog_pages.iter().map(blog_from_row).collect())
+  }
+
+  /// The newest posts, optionally only those by one author or with one tag,
+  /// skipping the first `offset` for later pages.
+  pub async fn get_recent_blogs(
+    &self,
+    author_id: Option<i32>,
+    tag: Option<&str>,
+    limit: i64,
+    offset: i64,
+  ) -> Result<Vec<Blog>, AppError> {
+    let blog_pages = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            WHERE ($1::INTEGER IS NULL OR blog.author_id = $1)
+              AND ($2::TEXT IS NULL OR $2 = ANY(blog.tags))
+            ORDER BY blog.published_at DESC, blog.id DESC
+            LIMIT $3 OFFSET $4
+        "#,
+    )
+    .bind(author_id)
+    .bind(tag)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(blog_pages.iter().map(blog_from_row).collect())
+  }
+
+  /// Every post's id with when it last changed, for the sitemap.
+  pub async fn get_post_updates(&self) -> Result<Vec<(i32, DateTime<Utc>)>, AppError> {
+    let posts = sqlx::query_as::<_, (i32, DateTime<Utc>)>(
+        "SELECT id, updated_at FROM blog ORDER BY id",
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(posts)
+  }
+
+  /// The handle of everyone who has posted, with when their newest change was.
+  pub async fn get_author_updates(&self) -> Result<Vec<(String, DateTime<Utc>)>, AppError> {
+    let authors = sqlx::query_as::<_, (String, DateTime<Utc>)>(
+        r#"
+            SELECT profiles.handle, MAX(blog.updated_at)
+            FROM profiles
+            JOIN blog ON blog.author_id = profiles.user_id
+            GROUP BY profiles.handle
+            ORDER BY profiles.handle
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(authors)
+  }
+
+  /// Every tag in use, with when a post carrying it last changed.
+  pub async fn get_tag_updates(&self) -> Result<Vec<(String, DateTime<Utc>)>, AppError> {
+    let tags = sqlx::query_as::<_, (String, DateTime<Utc>)>(
+        r#"
+            SELECT tag, MAX(blog.updated_at)
+            FROM blog, UNNEST(blog.tags) AS tag
+            GROUP BY tag
+            ORDER BY tag
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(tags)
+  }
+
+  pub async fn count_blogs_by_author(&self, user_id: i32) -> Result<i64, AppError> {
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM blog WHERE author_id = $1")
+        .bind(user_id)
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+    Ok(count)
+  }
+
+  pub async fn get_actor_key(&self, user_id: i32) -> Result<Option<ActorKey>, AppError> {
+    let key = sqlx::query_as::<_, ActorKey>(
+        "SELECT user_id, private_key, public_key FROM actor_keys WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(key)
+  }
+
+  /// Stores a new actor key unless another request got there first; either
+  /// way the key now on record is returned.
+  pub async fn add_actor_key(
+    &self,
+    user_id: i32,
+    private_key: &str,
+    public_key: &str,
+  ) -> Result<ActorKey, AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO actor_keys (user_id, private_key, public_key)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO NOTHING
+        "#,
+    )
+    .bind(user_id)
+    .bind(private_key)
+    .bind(public_key)
+    .execute(&self.conn_pool)
+    .await?;
+
+    self.get_actor_key(user_id).await?.ok_or(AppError::InternalServerError)
+  }
+
+  pub async fn add_follower(
+    &self,
+    user_id: i32,
+    actor_id: &str,
+    inbox: &str,
+    shared_inbox: Option<&str>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO followers (user_id, actor_id, inbox, shared_inbox)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, actor_id) DO UPDATE
+            SET inbox = EXCLUDED.inbox, shared_inbox = EXCLUDED.shared_inbox
+        "#,
+    )
+    .bind(user_id)
+    .bind(actor_id)
+    .bind(inbox)
+    .bind(shared_inbox)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn remove_follower(&self, user_id: i32, actor_id: &str) -> Result<bool, AppError> {
+    let result = sqlx::query("DELETE FROM followers WHERE user_id = $1 AND actor_id = $2")
+        .bind(user_id)
+        .bind(actor_id)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  pub async fn count_followers(&self, user_id: i32) -> Result<i64, AppError> {
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM followers WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(&self.conn_pool)
+        .await?;
+
+    Ok(count)
+  }
+
+  pub async fn get_followers(&self, user_id: i32) -> Result<Vec<Follower>, AppError> {
+    let followers = sqlx::query_as::<_, Follower>(
+        r#"
+            SELECT id, user_id, actor_id, inbox, shared_inbox
+            FROM followers WHERE user_id = $1
+            ORDER BY id
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(followers)
+  }
+
+  /// Queues `activity` for each inbox. The delivery job picks them up.
+  pub async fn queue_deliveries(
+    &self,
+    user_id: i32,
+    inboxes: &[String],
+    activity: &Value,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO activity_deliveries (user_id, inbox, activity)
+            SELECT $1, inbox, $3 FROM UNNEST($2::TEXT[]) AS inbox
+        "#,
+    )
+    .bind(user_id)
+    .bind(inboxes)
+    .bind(activity)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Takes up to `limit` deliveries that are due and pushes their next
+  /// attempt back by `lease`, so another server running the job doesn't send
+  /// them too while these are in flight.
+  pub async fn claim_due_deliveries(
+    &self,
+    limit: i64,
+    lease: Duration,
+  ) -> Result<Vec<Delivery>, AppError> {
+    let deliveries = sqlx::query_as::<_, Delivery>(
+        r#"
+            UPDATE activity_deliveries
+            SET next_attempt_at = NOW() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM activity_deliveries
+                WHERE next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id, inbox, activity, attempts
+        "#,
+    )
+    .bind(limit)
+    .bind(lease.as_secs() as f64)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(deliveries)
+  }
+
+  pub async fn finish_delivery(&self, id: i64) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM activity_deliveries WHERE id = $1")
+        .bind(id)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(())
+  }
+
+  pub async fn retry_delivery(&self, id: i64, delay: Duration, error: &str) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            UPDATE activity_deliveries
+            SET attempts = attempts + 1,
+                next_attempt_at = NOW() + make_interval(secs => $2),
+                last_error = $3
+            WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(delay.as_secs() as f64)
+    .bind(error)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Records a mention to be checked, or asks for an old one to be checked
+  /// again, which is how senders tell us a source changed or went away.
+  /// Mentions an admin hid stay hidden.
+  /// Stores a mention as pending, or `None` if it already is, as its check
+  /// is still to come.
+  pub async fn upsert_webmention(
+    &self,
+    blog_id: i32,
+    source: &str,
+    target: &str,
+  ) -> Result<Option<Webmention>, AppError> {
+    let webmention = sqlx::query_as::<_, Webmention>(
+        r#"
+            INSERT INTO webmentions (blog_id, source, target)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (source, target) DO UPDATE
+            SET blog_id = EXCLUDED.blog_id,
+                status = CASE WHEN webmentions.status = 'hidden' THEN 'hidden' ELSE 'pending' END,
+                updated_at = NOW()
+            WHERE webmentions.status <> 'pending'
+            RETURNING *
+        "#,
+    )
+    .bind(blog_id)
+    .bind(source)
+    .bind(target)
+    .fetch_optional(&self.conn_pool)
+    .await?;
+
+    Ok(webmention)
+  }
+
+  /// Stores the outcome of checking a pending mention.
+  pub async fn finish_webmention_check(
+    &self,
+    id: i32,
+    status: WebmentionStatus,
+    source_title: Option<&str>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            UPDATE webmentions SET status = $2, source_title = $3, updated_at = NOW()
+            WHERE id = $1 AND status = 'pending'
+        "#,
+    )
+    .bind(id)
+    .bind(status)
+    .bind(source_title)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn get_verified_webmentions(&self, blog_id: i32) -> Result<Vec<Webmention>, AppError> {
+    let webmentions = sqlx::query_as::<_, Webmention>(
+        r#"
+            SELECT * FROM webmentions
+            WHERE blog_id = $1 AND status = 'verified'
+            ORDER BY created_at
+        "#,
+    )
+    .bind(blog_id)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(webmentions)
+  }
+
+  /// The newest mentions of any post, for moderation.
+  pub async fn list_webmentions(&self, limit: i64) -> Result<Vec<Webmention>, AppError> {
+    let webmentions = sqlx::query_as::<_, Webmention>(
+        "SELECT * FROM webmentions ORDER BY updated_at DESC LIMIT $1",
+    )
+    .bind(limit)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(webmentions)
+  }
+
+  pub async fn set_webmention_status(
+    &self,
+    id: i32,
+    status: WebmentionStatus,
+  ) -> Result<bool, AppError> {
+    let result = sqlx::query("UPDATE webmentions SET status = $2, updated_at = NOW() WHERE id = $1")
+        .bind(id)
+        .bind(status)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  pub async fn delete_webmention(&self, id: i32) -> Result<bool, AppError> {
+    let result = sqlx::query("DELETE FROM webmentions WHERE id = $1")
+        .bind(id)
+        .execute(&self.conn_pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  pub async fn get_comments(&self, blog_id: i32) -> Result<Vec<Comment>, AppError> {
+    let comments = sqlx::query_as::<_, Comment>(
+        r#"
+            SELECT id, blog_id, author_name, author_email, author_url, content, created_at
+            FROM comments WHERE blog_id = $1
+            ORDER BY created_at, id
+        "#,
+    )
+    .bind(blog_id)
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(comments)
+  }
+
+  /// Adds a comment unless one was already imported under `import_key`.
+  /// Returns whether it was added.
+  pub async fn add_imported_comment(&self, import_key: &str, comment: &Comment) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        r#"
+            INSERT INTO comments (import_key, blog_id, author_name, author_email, author_url, content, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (import_key) DO NOTHING
+        "#,
+    )
+    .bind(import_key)
+    .bind(comment.blog_id)
+    .bind(&comment.author_name)
+    .bind(&comment.author_email)
+    .bind(&comment.author_url)
+    .bind(&comment.content)
+    .bind(comment.created_at)
+    .execute(&self.conn_pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Every account with its profile, for an export archive.
+  pub async fn export_users(&self) -> Result<Vec<ArchiveUser>, AppError> {
+    let users = sqlx::query_as::<_, ArchiveUser>(
+        r#"
+            SELECT users.id, users.email, users.role, users.email_verified,
+                   profiles.handle, profiles.display_name, profiles.bio, profiles.website, profiles.avatar_url
+            FROM users
+            JOIN profiles ON profiles.user_id = users.id
+            ORDER BY users.id
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(users)
+  }
+
+  /// Every post as its author wrote it, with the key it was imported under.
+  pub async fn export_blogs(&self) -> Result<Vec<(Blog, Option<String>)>, AppError> {
+    let blog_pages = sqlx::query(
+        r#"
+            SELECT blog.*, profiles.display_name AS author_name, profiles.handle AS author_handle
+            FROM blog
+            LEFT JOIN profiles ON profiles.user_id = blog.author_id
+            ORDER BY blog.id
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(blog_pages
+        .iter()
+        .map(|row| (blog_source_from_row(row), row.get("import_key")))
+        .collect())
+  }
+
+  pub async fn export_comments(&self) -> Result<Vec<ArchiveComment>, AppError> {
+    let comments = sqlx::query_as::<_, ArchiveComment>(
+        r#"
+            SELECT blog_id AS post_id, author_name, author_email, author_url, content, created_at, import_key
+            FROM comments
+            ORDER BY id
+        "#,
+    )
+    .fetch_all(&self.conn_pool)
+    .await?;
+
+    Ok(comments)
+  }
+
+  /// Starts a transaction for work that has to happen completely or not at
+  /// all, such as restoring an archive.
+  pub async fn begin(&self) -> Result<Transaction<'static, Postgres>, AppError> {
+    Ok(self.conn_pool.begin().await?)
+  }
+
+  /// Recreates an exported account and its profile under its old id.
+  pub async fn restore_user(
+    &self,
+    conn: &mut PgConnection,
+    user: &ArchiveUser,
+    hashed_password: &str,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO users (id, email, password, role, email_verified)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(user.id)
+    .bind(&user.email)
+    .bind(hashed_password)
+    .bind(user.role)
+    .bind(user.email_verified)
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        r#"
+            INSERT INTO profiles (user_id, handle, display_name, bio, website, avatar_url)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+    )
+    .bind(user.id)
+    .bind(&user.handle)
+    .bind(&user.display_name)
+    .bind(&user.bio)
+    .bind(&user.website)
+    .bind(&user.avatar_url)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Recreates an exported post under its old id, so its links still work,
+  /// keeping when it was last updated.
+  pub async fn restore_blog(
+    &self,
+    conn: &mut PgConnection,
+    blog: &Blog,
+    import_key: Option<&str>,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO blog (id, import_key, title, author_id, content, publish_date, tags, published_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+    )
+    .bind(blog.id)
+    .bind(import_key)
+    .bind(&blog.title)
+    .bind(blog.author_id)
+    .bind(&blog.content)
+    .bind(&blog.publish_date)
+    .bind(&blog.tags)
+    .bind(blog.published_at)
+    .bind(blog.updated_at)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Recreates an exported comment on the post with id `comment.post_id`.
+  pub async fn restore_comment(&self, conn: &mut PgConnection, comment: &ArchiveComment) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+            INSERT INTO comments (import_key, blog_id, author_name, author_email, author_url, content, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+    )
+    .bind(&comment.import_key)
+    .bind(comment.post_id)
+    .bind(&comment.author_name)
+    .bind(&comment.author_email)
+    .bind(&comment.author_url)
+    .bind(&comment.content)
+    .bind(comment.created_at)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Moves the id sequences past the ids a restore inserted, so new
+  /// accounts and posts don't collide with them.
+  pub async fn reset_restored_sequences(&self, conn: &mut PgConnection) -> Result<(), AppError> {
+    for table in ["users", "blog"] {
+        sqlx::query(&format!(
+            "SELECT setval(pg_get_serial_sequence('{0}', 'id'), COALESCE(MAX(id), 1), MAX(id) IS NOT NULL) FROM {0}",
+            table
+        ))
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Every account starts with a placeholder profile until its owner fills it in.
+async fn create_default_profile(conn: &mut PgConnection, user_id: i32) -> Result<(), AppError> {
+    sqlx::query("INSERT INTO profiles (user_id, handle, display_name) VALUES ($1, $2, $3)")
+        .bind(user_id)
+        .bind(default_handle(user_id))
+        .bind(format!("User {}", user_id))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Turns a `blog` row, joined with its author's profile, into a [`Blog`]
+/// with its markdown rendered to HTML.
+fn blog_from_row(blog: &PgRow) -> Blog {
+    let mut rendered = blog_source_from_row(blog);
+    rendered.content = render_content(&rendered.content);
+    rendered
+}
+
+fn blog_source_from_row(blog: &PgRow) -> Blog {
+    Blog {
+        id: blog.get("id"),
+        title: blog.get("title"),
+        author_id: blog.get("author_id"),
+        content: blog.get("content"),
+        publish_date: blog.get("publish_date"),
+        published_at: blog.get("published_at"),
+        updated_at: blog.get("updated_at"),
+        tags: blog.get("tags"),
+        author_name: blog.get::<Option<String>, _>("author_name").unwrap_or_default(),
+        author_handle: blog.get::<Option<String>, _>("author_handle").unwrap_or_default(),
+    }
+}
+
+fn render_content(incoming_content: &str) -> String {
+    let mut parsed_content = Vec::new();
+
+    for line in incoming_content.split("\r\n") {
+        // check for ###
+        if line.starts_with("###") {
+            let new_string = line.replace("###", "<h3>") + "</h3>";
+            parsed_content.push(new_string);
+        }
+
+        //check for ##
+        else if line.starts_with("##") {
+            let new_string = line.replace("##", "<h2>") + "</h2>";
+            parsed_content.push(new_string);
+        }
+
+        //check for #
+        else if line.starts_with('#') {
+            let new_string = line.replace('#', "<h1>") + "</h1>";
+            parsed_content.push(new_string);
+        }
+
+        //check for ** **
+        else if line.starts_with("**") && line.ends_with("**") {
+            let start_tag = "<strong>";
+            let end_tag = "</strong>";
+
+            let new_string = start_tag.to_string() + &line[2..line.len() - 2] + end_tag;
+            parsed_content.push(new_string);
+        }
+
+        //check for * *
+        else if line.starts_with('*') && line.ends_with('*') {
+            let start_tag = "<i>";
+            let end_tag = "</i>";
+
+            let new_string = start_tag.to_string() + &line[1..line.len() - 1] + end_tag;
+            parsed_content.push(new_string);
+        }
+
+        //check for ~~ ~~
+        else if line.starts_with("~~") && line.ends_with("~~") {
+            let start_tag = "<s>";
+            let end_tag = "</s>";
+
+            let new_string = start_tag.to_string() + &line[2..line.len() - 2] + end_tag;
+            parsed_content.push(new_string);
+        }
+
+        //check for ---
+        else if line.starts_with("---") {
+            parsed_content.push(line.replace("---", "<hr>"));
+        }
+
+        else {
+            parsed_content.push(line.to_string());
+        }
+    }
+
+    parsed_content.join("<br>")
 }
@@ -11,10 +11,25 @@ pub enum AppError {
     Event(EventError),
     Database(sqlx::Error),
     MissingCredentials,
-    InvalidPassword,
-    UserDoesNotExist,
+    InvalidCredentials,
     UserAlreadyExists,
     InvalidToken,
+    EmailNotVerified,
+    InvalidSecondFactor,
+    InvalidPasskey,
+    SingleSignOnFailed,
+    NotFound,
+    Forbidden,
+    InvalidCsrfToken,
+    InvalidInvite,
+    InvalidProfile(String),
+    InvalidPost(String),
+    HandleTaken,
+    InvalidSignature,
+    InvalidWebmention(String),
+    InvalidMicropub(String),
+    InvalidImport(String),
+    TooManyRequests,
     InternalServerError,
     #[allow(dead_code)]
     Any(anyhow::Error),
@@ -27,6 +42,21 @@ pub enum EventError {
     InvalidId,
 }
 
+impl AppError {
+    /// A WordPress export or archive we can't take, with why.
+    pub fn invalid_import(message: impl Into<String>) -> Self {
+        AppError::InvalidImport(message.into())
+    }
+
+    pub fn invalid_micropub(message: impl Into<String>) -> Self {
+        AppError::InvalidMicropub(message.into())
+    }
+
+    pub fn invalid_webmention(message: impl Into<String>) -> Self {
+        AppError::InvalidWebmention(message.into())
+    }
+}
+
 impl From<reqwest::Error> for AppError {
     fn from(value: ReqwestError) -> Self {
         AppError::RequestAPI(value)
@@ -54,16 +84,65 @@ impl IntoResponse for AppError {
                 StatusCode::UNAUTHORIZED,
                 "Your credentials were missing or otherwise incorrect".to_string(),
             ),
-            AppError::UserDoesNotExist => (
+            AppError::InvalidCredentials => (
                 StatusCode::UNAUTHORIZED,
-                "Your account does not exist!".to_string(),
+                "Invalid email or password".to_string(),
             ),
             AppError::UserAlreadyExists => (
                 StatusCode::UNAUTHORIZED,
                 "There is already an account with that email address in the system".to_string(),
             ),
             AppError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid Token".to_string()),
-            AppError::InvalidPassword => (StatusCode::UNAUTHORIZED, "Invalid Password".to_string()),
+            AppError::EmailNotVerified => (
+                StatusCode::FORBIDDEN,
+                "Please verify your email address before doing that".to_string(),
+            ),
+            AppError::InvalidSecondFactor => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid authentication code".to_string(),
+            ),
+            AppError::InvalidPasskey => (
+                StatusCode::UNAUTHORIZED,
+                "That passkey could not be verified".to_string(),
+            ),
+            AppError::SingleSignOnFailed => (
+                StatusCode::UNAUTHORIZED,
+                "Signing in with your identity provider failed".to_string(),
+            ),
+            AppError::NotFound => (
+                StatusCode::NOT_FOUND,
+                "The requested page could not be found".to_string(),
+            ),
+            AppError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "You are not allowed to do that".to_string(),
+            ),
+            AppError::InvalidCsrfToken => (
+                StatusCode::FORBIDDEN,
+                "Your form has expired or came from another site, please reload the page and try again"
+                    .to_string(),
+            ),
+            AppError::InvalidInvite => (
+                StatusCode::FORBIDDEN,
+                "Registration needs a valid invite code".to_string(),
+            ),
+            AppError::InvalidProfile(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidPost(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::HandleTaken => (
+                StatusCode::CONFLICT,
+                "That handle is already taken".to_string(),
+            ),
+            AppError::InvalidSignature => (
+                StatusCode::UNAUTHORIZED,
+                "The request's HTTP signature could not be verified".to_string(),
+            ),
+            AppError::InvalidWebmention(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidMicropub(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::InvalidImport(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::TooManyRequests => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests, please wait a moment and try again".to_string(),
+            ),
             AppError::InternalServerError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Something terrible happened".to_string(),
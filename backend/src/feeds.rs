@@ -0,0 +1,597 @@
+//! RSS 2.0, Atom and JSON Feed feeds of the newest posts, for the whole blog,
+//! one author or one tag. Every link in a feed is absolute, built from the
+//! [`FeedSource`]'s base URL, normally `PUBLIC_URL`, as feed readers fetch
+//! them without any page to resolve them against.
+
+use atom_syndication as atom;
+use chrono::{DateTime, Utc};
+use rss::extension::atom::{AtomExtension, Link};
+use rss::extension::dublincore::DublinCoreExtension;
+use serde_derive::Serialize;
+use tracing::error;
+
+use crate::error::AppError;
+use crate::get_public_url;
+use crate::models::blog::Blog;
+use crate::models::profiles::Profile;
+
+pub const SITE_TITLE: &str = "Rust Blog";
+
+/// How many posts a feed lists.
+pub const FEED_LENGTH: i64 = 20;
+
+pub const RSS_CONTENT_TYPE: &str = "application/rss+xml; charset=utf-8";
+pub const ATOM_CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+pub const JSON_FEED_CONTENT_TYPE: &str = "application/feed+json; charset=utf-8";
+
+pub const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+/// How long a post's summary can get, in characters.
+pub const SUMMARY_LENGTH: usize = 200;
+
+/// What a feed is of: its title, the page it mirrors and where it lives.
+pub struct FeedSource {
+    /// The blog's address, which every link in the feed starts with.
+    pub base_url: String,
+    pub title: String,
+    pub description: String,
+    /// The HTML page listing the same posts.
+    pub page_url: String,
+    pub rss_url: String,
+    pub atom_url: String,
+    pub json_url: String,
+    pub author_name: String,
+    pub author_url: String,
+}
+
+impl FeedSource {
+    pub fn site(base_url: &str) -> Self {
+        let base = base_url.to_string();
+        FeedSource {
+            base_url: base.clone(),
+            title: SITE_TITLE.to_string(),
+            description: format!("The newest posts on {}", SITE_TITLE),
+            page_url: format!("{}/all_blogs", base),
+            rss_url: format!("{}/feed.xml", base),
+            atom_url: format!("{}/atom.xml", base),
+            json_url: format!("{}/feed.json", base),
+            author_name: SITE_TITLE.to_string(),
+            author_url: base,
+        }
+    }
+
+    pub fn author(base_url: &str, profile: &Profile) -> Self {
+        let page_url = author_url_at(base_url, &profile.handle);
+        FeedSource {
+            base_url: base_url.to_string(),
+            title: format!("{} - {}", profile.display_name, SITE_TITLE),
+            description: format!("The newest posts by {}", profile.display_name),
+            rss_url: format!("{}/feed.xml", page_url),
+            atom_url: format!("{}/atom.xml", page_url),
+            json_url: format!("{}/feed.json", page_url),
+            author_name: profile.display_name.clone(),
+            author_url: page_url.clone(),
+            page_url,
+        }
+    }
+
+    pub fn tag(base_url: &str, tag: &str) -> Self {
+        let page_url = tag_url_at(base_url, tag);
+        FeedSource {
+            base_url: base_url.to_string(),
+            title: format!("#{} - {}", tag, SITE_TITLE),
+            description: format!("The newest posts tagged {}", tag),
+            rss_url: format!("{}/feed.xml", page_url),
+            atom_url: format!("{}/atom.xml", page_url),
+            json_url: format!("{}/feed.json", page_url),
+            author_name: SITE_TITLE.to_string(),
+            author_url: base_url.to_string(),
+            page_url,
+        }
+    }
+}
+
+pub fn post_url(blog: &Blog) -> String {
+    post_url_at(&get_public_url(), blog)
+}
+
+pub fn author_url(handle: &str) -> String {
+    author_url_at(&get_public_url(), handle)
+}
+
+pub fn tag_url(tag: &str) -> String {
+    tag_url_at(&get_public_url(), tag)
+}
+
+pub fn post_url_at(base_url: &str, blog: &Blog) -> String {
+    format!("{}/posts/{}", base_url, blog.id)
+}
+
+pub fn author_url_at(base_url: &str, handle: &str) -> String {
+    format!("{}/authors/{}", base_url, handle)
+}
+
+pub fn tag_url_at(base_url: &str, tag: &str) -> String {
+    format!("{}/tags/{}", base_url, tag)
+}
+
+/// The start of a post's text with its markup stripped, cut at a word
+/// boundary and marked with an ellipsis if there was more.
+pub fn summary(html: &str) -> String {
+    shorten(&plain_text(html), SUMMARY_LENGTH)
+}
+
+/// `html` with its tags dropped and entities decoded.
+pub fn plain_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for ch in html.chars() {
+        match ch {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(ch),
+            _ => {}
+        }
+    }
+
+    html_escape::decode_html_entities(&text).into_owned()
+}
+
+/// At most `length` characters of `text`, cut at a word boundary and marked
+/// with an ellipsis if there was more.
+pub fn shorten(text: &str, length: usize) -> String {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut summary = String::new();
+    for word in &words {
+        if summary.chars().count() + word.chars().count() + 1 > length {
+            summary.push('…');
+            return summary;
+        }
+        if !summary.is_empty() {
+            summary.push(' ');
+        }
+        summary.push_str(word);
+    }
+
+    summary
+}
+
+/// When anything in the feed last changed, or now for a feed with no posts.
+fn last_updated(blogs: &[Blog]) -> DateTime<Utc> {
+    blogs
+        .iter()
+        .map(|blog| blog.updated_at)
+        .max()
+        .unwrap_or_else(Utc::now)
+}
+
+fn self_link(href: &str, mime_type: &str) -> Link {
+    Link {
+        href: href.to_string(),
+        rel: "self".to_string(),
+        mime_type: Some(mime_type.to_string()),
+        ..Default::default()
+    }
+}
+
+fn into_xml(bytes: Vec<u8>) -> Result<String, AppError> {
+    String::from_utf8(bytes).map_err(|err| {
+        error!("Feed was not valid UTF-8: {}", err);
+        AppError::InternalServerError
+    })
+}
+
+pub fn rss_feed(source: &FeedSource, blogs: &[Blog]) -> Result<String, AppError> {
+    let items: Vec<rss::Item> = blogs
+        .iter()
+        .map(|blog| {
+            let link = post_url_at(&source.base_url, blog);
+            rss::Item {
+                title: Some(blog.title.clone()),
+                link: Some(link.clone()),
+                description: Some(blog.content.clone()),
+                guid: Some(rss::Guid {
+                    value: link,
+                    permalink: true,
+                }),
+                pub_date: Some(blog.published_at.to_rfc2822()),
+                categories: blog
+                    .tags
+                    .iter()
+                    .map(|tag| rss::Category {
+                        name: tag.clone(),
+                        domain: None,
+                    })
+                    .collect(),
+                dublin_core_ext: Some(DublinCoreExtension {
+                    creators: vec![blog.author_name.clone()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    let channel = rss::Channel {
+        title: source.title.clone(),
+        link: source.page_url.clone(),
+        description: source.description.clone(),
+        language: Some("en".to_string()),
+        last_build_date: Some(last_updated(blogs).to_rfc2822()),
+        generator: Some(SITE_TITLE.to_string()),
+        atom_ext: Some(AtomExtension {
+            links: vec![self_link(&source.rss_url, "application/rss+xml")],
+        }),
+        items,
+        ..Default::default()
+    };
+
+    let bytes = channel.write_to(Vec::new()).map_err(|err| {
+        error!("Could not write RSS feed: {}", err);
+        AppError::InternalServerError
+    })?;
+    into_xml(bytes)
+}
+
+pub fn atom_feed(source: &FeedSource, blogs: &[Blog]) -> Result<String, AppError> {
+    let entries: Vec<atom::Entry> = blogs
+        .iter()
+        .map(|blog| {
+            let link = post_url_at(&source.base_url, blog);
+            atom::Entry {
+                id: link.clone(),
+                title: atom::Text::plain(blog.title.clone()),
+                updated: blog.updated_at.into(),
+                published: Some(blog.published_at.into()),
+                authors: vec![atom::Person {
+                    name: blog.author_name.clone(),
+                    email: None,
+                    uri: Some(author_url_at(&source.base_url, &blog.author_handle)),
+                }],
+                links: vec![atom::Link {
+                    href: link,
+                    rel: "alternate".to_string(),
+                    mime_type: Some("text/html".to_string()),
+                    ..Default::default()
+                }],
+                categories: blog
+                    .tags
+                    .iter()
+                    .map(|tag| atom::Category {
+                        term: tag.clone(),
+                        scheme: Some(format!("{}/tags/", source.base_url)),
+                        label: None,
+                    })
+                    .collect(),
+                content: Some(atom::Content {
+                    value: Some(blog.content.clone()),
+                    content_type: Some("html".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    let feed = atom::Feed {
+        id: source.atom_url.clone(),
+        title: atom::Text::plain(source.title.clone()),
+        subtitle: Some(atom::Text::plain(source.description.clone())),
+        updated: last_updated(blogs).into(),
+        authors: vec![atom::Person {
+            name: source.author_name.clone(),
+            email: None,
+            uri: Some(source.author_url.clone()),
+        }],
+        links: vec![
+            self_link(&source.atom_url, "application/atom+xml"),
+            atom::Link {
+                href: source.page_url.clone(),
+                rel: "alternate".to_string(),
+                mime_type: Some("text/html".to_string()),
+                ..Default::default()
+            },
+        ],
+        lang: Some("en".to_string()),
+        entries,
+        ..Default::default()
+    };
+
+    let bytes = feed.write_to(Vec::new()).map_err(|err| {
+        error!("Could not write Atom feed: {}", err);
+        AppError::InternalServerError
+    })?;
+    into_xml(bytes)
+}
+
+/// A [JSON Feed 1.1](https://jsonfeed.org/version/1.1) document.
+#[derive(Serialize)]
+pub struct JsonFeed {
+    pub version: &'static str,
+    pub title: String,
+    pub home_page_url: String,
+    pub feed_url: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_url: Option<String>,
+    pub language: &'static str,
+    pub authors: Vec<JsonFeedAuthor>,
+    pub items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+pub struct JsonFeedAuthor {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct JsonFeedItem {
+    pub id: String,
+    pub url: String,
+    pub title: String,
+    pub content_html: String,
+    pub summary: String,
+    pub date_published: String,
+    pub date_modified: String,
+    pub authors: Vec<JsonFeedAuthor>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// One page of a JSON Feed. `next_url` points at the page after it, if there
+/// are older posts.
+pub fn json_feed(source: &FeedSource, blogs: &[Blog], feed_url: String, next_url: Option<String>) -> JsonFeed {
+    let items = blogs
+        .iter()
+        .map(|blog| {
+            let link = post_url_at(&source.base_url, blog);
+            JsonFeedItem {
+                id: link.clone(),
+                url: link,
+                title: blog.title.clone(),
+                content_html: blog.content.clone(),
+                summary: summary(&blog.content),
+                date_published: blog.published_at.to_rfc3339(),
+                date_modified: blog.updated_at.to_rfc3339(),
+                authors: vec![JsonFeedAuthor {
+                    name: blog.author_name.clone(),
+                    url: author_url_at(&source.base_url, &blog.author_handle),
+                }],
+                tags: blog.tags.clone(),
+            }
+        })
+        .collect();
+
+    JsonFeed {
+        version: JSON_FEED_VERSION,
+        title: source.title.clone(),
+        home_page_url: source.page_url.clone(),
+        feed_url,
+        description: source.description.clone(),
+        next_url,
+        language: "en",
+        authors: vec![JsonFeedAuthor {
+            name: source.author_name.clone(),
+            url: source.author_url.clone(),
+        }],
+        items,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use quick_xml::encoding::Decoder;
+    use quick_xml::events::{BytesStart, Event};
+    use quick_xml::Reader;
+
+    use super::*;
+
+    const BASE_URL: &str = "https://blog.example";
+
+    /// Just enough of an XML tree to check which elements a feed has.
+    #[derive(Debug, Default)]
+    struct Element {
+        name: String,
+        attributes: Vec<(String, String)>,
+        text: String,
+        children: Vec<Element>,
+    }
+
+    impl Element {
+        fn attribute(&self, name: &str) -> Option<&str> {
+            self.attributes
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn child(&self, name: &str) -> &Element {
+            self.children
+                .iter()
+                .find(|child| child.name == name)
+                .unwrap_or_else(|| panic!("<{}> has no <{}>", self.name, name))
+        }
+
+        fn all(&self, name: &str) -> Vec<&Element> {
+            self.children.iter().filter(|child| child.name == name).collect()
+        }
+    }
+
+    fn element(start: &BytesStart, decoder: Decoder) -> Element {
+        Element {
+            name: String::from_utf8_lossy(start.name().as_ref()).into_owned(),
+            attributes: start
+                .attributes()
+                .map(|attribute| {
+                    let attribute = attribute.unwrap();
+                    (
+                        String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+                        attribute.decode_and_unescape_value(decoder).unwrap().into_owned(),
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn parse(xml: &str) -> Element {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        let mut stack = vec![Element::default()];
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(start) => stack.push(element(&start, reader.decoder())),
+                Event::Empty(start) => {
+                    let empty = element(&start, reader.decoder());
+                    stack.last_mut().unwrap().children.push(empty);
+                }
+                Event::Text(text) => stack.last_mut().unwrap().text.push_str(&text.unescape().unwrap()),
+                Event::CData(text) => stack.last_mut().unwrap().text.push_str(&text.decode().unwrap()),
+                Event::End(_) => {
+                    let done = stack.pop().unwrap();
+                    stack.last_mut().unwrap().children.push(done);
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        stack.pop().unwrap().children.pop().unwrap()
+    }
+
+    fn assert_absolute(url: &str) {
+        let parsed = url::Url::parse(url).unwrap_or_else(|_| panic!("{} isn't an absolute URL", url));
+        assert_eq!(parsed.origin().ascii_serialization(), BASE_URL, "{}", url);
+    }
+
+    fn blogs() -> Vec<Blog> {
+        let mut first = Blog::new(
+            "Tags & <angle> brackets".to_string(),
+            1,
+            "<h1>Hello</h1><br><p>Fish & chips</p>".to_string(),
+            "2024-03-01".to_string(),
+        );
+        first.id = 12;
+        first.published_at = Utc.with_ymd_and_hms(2024, 3, 1, 9, 30, 0).unwrap();
+        first.updated_at = Utc.with_ymd_and_hms(2024, 3, 2, 10, 0, 0).unwrap();
+        first.tags = vec!["rust".to_string()];
+        first.author_name = "Alice".to_string();
+        first.author_handle = "alice".to_string();
+
+        let mut second = Blog::new("Second".to_string(), 1, "Plain".to_string(), "2024-02-01".to_string());
+        second.id = 3;
+        second.published_at = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+        second.updated_at = second.published_at;
+        second.author_name = "Alice".to_string();
+        second.author_handle = "alice".to_string();
+
+        vec![first, second]
+    }
+
+    #[test]
+    fn rss_has_the_required_channel_and_item_elements() {
+        let blogs = blogs();
+        let rss = parse(&rss_feed(&FeedSource::site(BASE_URL), &blogs).unwrap());
+
+        assert_eq!(rss.name, "rss");
+        assert_eq!(rss.attribute("version"), Some("2.0"));
+        let channel = rss.child("channel");
+        assert_eq!(channel.child("title").text, SITE_TITLE);
+        assert_eq!(channel.child("link").text, format!("{}/all_blogs", BASE_URL));
+        assert!(!channel.child("description").text.is_empty());
+
+        let self_link = channel.child("atom:link");
+        assert_eq!(self_link.attribute("rel"), Some("self"));
+        assert_eq!(self_link.attribute("href"), Some(format!("{}/feed.xml", BASE_URL).as_str()));
+
+        let items = channel.all("item");
+        assert_eq!(items.len(), 2);
+        for (item, blog) in items.iter().zip(&blogs) {
+            assert_eq!(item.child("title").text, blog.title);
+            let guid = item.child("guid");
+            assert_eq!(guid.text, format!("{}/posts/{}", BASE_URL, blog.id));
+            assert_ne!(guid.attribute("isPermaLink"), Some("false"));
+            assert_absolute(&guid.text);
+            assert_absolute(&item.child("link").text);
+
+            let pub_date = DateTime::parse_from_rfc2822(&item.child("pubDate").text)
+                .expect("pubDate has to be RFC 2822");
+            assert_eq!(pub_date, blog.published_at);
+        }
+    }
+
+    #[test]
+    fn rss_descriptions_carry_html_as_text() {
+        let blogs = blogs();
+        let rss = parse(&rss_feed(&FeedSource::site(BASE_URL), &blogs).unwrap());
+
+        let description = rss.child("channel").all("item")[0].child("description");
+        assert!(description.children.is_empty(), "the HTML was written as markup");
+        assert_eq!(description.text, blogs[0].content);
+    }
+
+    #[test]
+    fn atom_has_the_required_feed_and_entry_elements() {
+        let blogs = blogs();
+        let feed = parse(&atom_feed(&FeedSource::site(BASE_URL), &blogs).unwrap());
+
+        assert_eq!(feed.name, "feed");
+        assert_eq!(feed.attribute("xmlns"), Some("http://www.w3.org/2005/Atom"));
+        assert_eq!(feed.child("id").text, format!("{}/atom.xml", BASE_URL));
+        assert_eq!(feed.child("title").text, SITE_TITLE);
+        let updated = DateTime::parse_from_rfc3339(&feed.child("updated").text).unwrap();
+        assert_eq!(updated, blogs[0].updated_at);
+
+        let links = feed.all("link");
+        let rel = |rel: &str| {
+            links
+                .iter()
+                .find(|link| link.attribute("rel") == Some(rel))
+                .and_then(|link| link.attribute("href"))
+                .unwrap_or_else(|| panic!("no rel=\"{}\" link", rel))
+                .to_string()
+        };
+        assert_eq!(rel("self"), format!("{}/atom.xml", BASE_URL));
+        assert_eq!(rel("alternate"), format!("{}/all_blogs", BASE_URL));
+
+        let entries = feed.all("entry");
+        assert_eq!(entries.len(), 2);
+        for (entry, blog) in entries.iter().zip(&blogs) {
+            assert_eq!(entry.child("id").text, format!("{}/posts/{}", BASE_URL, blog.id));
+            assert_absolute(&entry.child("id").text);
+            assert_eq!(entry.child("title").text, blog.title);
+            let updated = DateTime::parse_from_rfc3339(&entry.child("updated").text).unwrap();
+            assert_eq!(updated, blog.updated_at);
+
+            let link = entry.child("link");
+            assert_eq!(link.attribute("rel"), Some("alternate"));
+            assert_absolute(link.attribute("href").unwrap());
+            assert_absolute(entry.child("author").child("uri").text.as_str());
+        }
+    }
+
+    #[test]
+    fn atom_content_is_typed_html() {
+        let blogs = blogs();
+        let feed = parse(&atom_feed(&FeedSource::site(BASE_URL), &blogs).unwrap());
+
+        let content = feed.all("entry")[0].child("content");
+        assert_eq!(content.attribute("type"), Some("html"));
+        assert!(content.children.is_empty(), "the HTML was written as markup");
+        assert_eq!(content.text, blogs[0].content);
+    }
+
+    #[test]
+    fn empty_feeds_are_still_valid() {
+        let rss = parse(&rss_feed(&FeedSource::site(BASE_URL), &[]).unwrap());
+        assert!(rss.child("channel").all("item").is_empty());
+        let feed = parse(&atom_feed(&FeedSource::site(BASE_URL), &[]).unwrap());
+        assert!(DateTime::parse_from_rfc3339(&feed.child("updated").text).is_ok());
+    }
+}
@@ -1,20 +1,80 @@
 use argon2::Config;
-use axum::extract::{State};
-use axum::response::{Html, Response};
+use axum::body::{boxed, BoxBody};
+use axum::extract::multipart::MultipartError;
+use axum::extract::{ConnectInfo, Multipart, Path, Query, RawQuery, State};
+use axum::response::{AppendHeaders, Html, IntoResponse, Response};
+use axum::body::Bytes;
 use axum::{Form, Json};
-use http::header::{LOCATION, SET_COOKIE};
-use http::{HeaderValue, StatusCode};
+use chrono::Utc;
+use http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
 use hyper::Body;
-use jsonwebtoken::Header;
+use once_cell::sync::Lazy;
+use rand::RngCore;
 use serde_json::Value;
+use std::net::SocketAddr;
 use tera::Context;
-use tracing::error;
+use tokio::io::AsyncSeekExt;
+use tokio_util::io::ReaderStream;
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
+use crate::activitypub::{self, ACTIVITY_CONTENT_TYPE, JRD_CONTENT_TYPE};
+use crate::archive::{self, ARCHIVE_CONTENT_TYPE};
+use crate::cookies::COOKIE_POLICY;
 use crate::db::Store;
+use crate::keys;
 use crate::error::AppError;
-use crate::get_timestamp_after_8_hours;
-use crate::models::users::{Claims, OptionalClaims, User, UserSignup, KEYS};
-use crate::models::blog::{Blog};
+use crate::feeds::{
+    self, FeedSource, ATOM_CONTENT_TYPE, FEED_LENGTH, JSON_FEED_CONTENT_TYPE, RSS_CONTENT_TYPE,
+};
+use crate::mail::Email;
+use crate::webmention;
+use crate::wordpress;
+use crate::meta::PostMeta;
+use crate::micropub::{self, MicropubRequest};
+use crate::sitemap::{self, SitemapUrl, SITEMAP_CONTENT_TYPE};
+use crate::oidc::{self, OidcCallback, OidcConfig, OidcFlowClaims, OIDC_FLOW_COOKIE, OIDC_FLOW_PURPOSE};
+use crate::webauthn::{self, RelyingParty};
+use crate::{
+    get_public_url, get_timestamp_after, ACCESS_TOKEN_LIFETIME, EMAIL_VERIFICATION_LIFETIME,
+    OIDC_FLOW_LIFETIME, PASSWORD_RESET_LIFETIME, REFRESH_REUSE_GRACE, REFRESH_TOKEN_LIFETIME,
+    SECOND_FACTOR_LIFETIME, VERIFICATION_RESEND_COOLDOWN, WEBAUTHN_CHALLENGE_LIFETIME,
+};
+use crate::models::api_tokens::{RevokeApiToken, API_TOKEN_PREFIX, API_TOKEN_SCOPES};
+use crate::models::federation::WebfingerQuery;
+use crate::models::invites::{
+    CreateInvite, InviteOnlyForm, RevokeInvite, INVITE_CODE_PREFIX, INVITE_ONLY,
+};
+use crate::models::passkeys::{
+    PasskeyAssertion, PasskeyRegistration, AUTHENTICATION_CHALLENGE, REGISTRATION_CHALLENGE,
+};
+use crate::models::profiles::{Profile, ProfileForm};
+use crate::models::roles::{
+    is_permitted, EditAnyPost, EditProfile, ManageUsers, RequirePermission, Role, WritePosts,
+};
+use crate::models::throttle::{
+    account_key, client_ip, ip_key, webmention_key, ThrottlePolicy, UnlockAccount, ACCOUNT_POLICY,
+    IP_POLICY, WEBMENTION_POLICY,
+};
+use crate::models::tokens::{generate_token, hash_token, REFRESH_COOKIE};
+use crate::models::webmentions::{
+    ModerateWebmention, ModerationAction, WebmentionForm, WebmentionStatus, MODERATION_PAGE_SIZE,
+};
+use crate::models::two_factor::{
+    build_totp, generate_recovery_codes, generate_secret, matching_step, qr_code_svg,
+    RequireAdmin2faForm, SecondFactorClaims, SecondFactorForm, REQUIRE_ADMIN_2FA,
+    SECOND_FACTOR_COOKIE, SECOND_FACTOR_PURPOSE,
+};
+use crate::models::users::{
+    ChangeEmailForm, Claims, EmailChangeClaims, EmailVerificationClaims, ForgotPassword,
+    OptionalClaims, ResetPassword, ResetQuery, SetRole, User, UserSignup,
+    VerifyEmailQuery, ACCESS_COOKIE, EMAIL_CHANGE_PURPOSE, EMAIL_VERIFICATION_PURPOSE,
+};
+use crate::models::blog::{normalize_tag, Blog, MAX_TITLE_LENGTH};
+use crate::models::page::PageQuery;
 
 use crate::template::TEMPLATES;
 
@@ -36,6 +96,10 @@ pub async fn root(
         context.insert("is_logged_in", &false);
         "index.html"
     };
+    context.insert(
+        "oidc_provider",
+        &OidcConfig::from_env().map(|config| config.provider_name),
+    );
 
     let rendered = TEMPLATES
         .render(template_name, &context)
@@ -58,62 +122,150 @@ pub async fn register(
       return Err(AppError::MissingCredentials);
   }
 
+  let invite_code = credentials
+      .invite_code
+      .take()
+      .map(|code| code.trim().to_string())
+      .filter(|code| !code.is_empty());
+  // The first account can't have been invited by anyone.
+  if invite_code.is_none() && invite_only(&database).await? && database.has_users().await? {
+      return Err(AppError::InvalidInvite);
+  }
+
   let existing_user = database.get_user(&credentials.email).await;
 
   if existing_user.is_ok() {
       return Err(AppError::UserAlreadyExists);
   }
 
-  let hash_config = Config::default();
-  let salt = std::env::var("SALT").expect("Missing SALT");
-  let hashed_password = match argon2::hash_encoded(
-      credentials.password.as_bytes(),
-      salt.as_bytes(),
-      &hash_config,
-  ) {
-      Ok(result) => result,
-      Err(_) => {
-          return Err(AppError::Any(anyhow::anyhow!("Password hashing failed")));
+  let email = credentials.email.clone();
+
+  credentials.password = hash_password(&credentials.password)?;
+
+  let new_user = match invite_code {
+      Some(code) => {
+          let secret = code.strip_prefix(INVITE_CODE_PREFIX).unwrap_or(&code);
+          database
+              .create_invited_user(&credentials, &hash_token(secret))
+              .await?;
+          Json(serde_json::json!({"message": "User created successfully!"}))
       }
+      None => database.create_user(credentials).await?,
   };
 
-  credentials.password = hashed_password;
+  let user = database.get_user(&email).await?;
+  if let Err(err) = send_verification_email(&database, &user).await {
+      error!("Could not send verification email: {:?}", err);
+  }
 
-  let new_user = database.create_user(credentials).await?;
   Ok(new_user)
 }
 
+/// Hashes a password the same way for registration and password resets.
+/// Hashes with a random salt of its own, which the encoded hash carries, so
+/// [`argon2::verify_encoded`] needs nothing else to check a password.
+pub fn hash_password(password: &str) -> Result<String, AppError> {
+  let hash_config = Config::default();
+  let mut salt = [0u8; 16];
+  rand::thread_rng().fill_bytes(&mut salt);
+  argon2::hash_encoded(password.as_bytes(), &salt, &hash_config)
+      .map_err(|_| AppError::Any(anyhow::anyhow!("Password hashing failed")))
+}
+
 pub async fn login(
     State(database): State<Store>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     Form(creds): Form<User>,
 ) -> Result<Response<Body>, AppError> {
     if creds.email.is_empty() || creds.password.is_empty() {
         return Err(AppError::MissingCredentials);
     }
 
-    let existing_user = database.get_user(&creds.email).await?;
-    let is_password_correct =
-        match argon2::verify_encoded(&existing_user.password, creds.password.as_bytes()) {
-            Ok(result) => result,
-            Err(_) => {
-                return Err(AppError::InternalServerError);
-            }
-        };
-
-    if !is_password_correct {
-        return Err(AppError::InvalidPassword);
+    let peer = connect_info.map(|ConnectInfo(addr)| addr);
+    let mut throttle_keys = vec![(account_key(&creds.email), &ACCOUNT_POLICY)];
+    if let Some(ip) = client_ip(&headers, peer) {
+        throttle_keys.push((ip_key(&ip), &IP_POLICY));
     }
+    check_login_throttle(&database, &throttle_keys).await?;
 
-    let claims = Claims {
-        email: creds.email.to_owned(),
-        exp: get_timestamp_after_8_hours(),
-        is_admin: existing_user.is_admin,
+    // Unknown emails still pay for a password check so the response time
+    // doesn't give away which accounts exist.
+    let existing_user = database.find_user(&creds.email).await?;
+    let password_hash = existing_user
+        .as_ref()
+        .map_or(DUMMY_PASSWORD_HASH.as_str(), |user| user.password.as_str());
+    let is_password_correct = argon2::verify_encoded(password_hash, creds.password.as_bytes())
+        .map_err(|_| AppError::InternalServerError)?;
+
+    let user = match existing_user {
+        Some(user) if is_password_correct => user,
+        _ => {
+            record_login_failure(&database, &throttle_keys).await?;
+            return Err(AppError::InvalidCredentials);
+        }
     };
 
-    let token = jsonwebtoken::encode(&Header::default(), &claims, &KEYS.encoding)
-        .map_err(|_| AppError::MissingCredentials)?;
+    database.clear_login_throttle(&throttle_keys[0].0).await?;
+    finish_login(&database, &user).await
+}
+
+/// Hash of a password nobody knows, checked against when the email has no account.
+static DUMMY_PASSWORD_HASH: Lazy<String> = Lazy::new(|| {
+    let mut password = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut password);
+    hash_password(&hex::encode(password)).expect("Could not hash the dummy password")
+});
+
+async fn check_login_throttle(
+    database: &Store,
+    keys: &[(String, &ThrottlePolicy)],
+) -> Result<(), AppError> {
+    let keys: Vec<String> = keys.iter().map(|(key, _)| key.clone()).collect();
+    if let Some(throttle) = database.get_active_throttle(&keys).await? {
+        info!("Refused attempt from throttled {}", throttle.key);
+        return Err(AppError::TooManyRequests);
+    }
+
+    Ok(())
+}
+
+async fn record_login_failure(
+    database: &Store,
+    keys: &[(String, &ThrottlePolicy)],
+) -> Result<(), AppError> {
+    for (key, policy) in keys {
+        let failures = database.record_login_failure(key, policy).await?;
+        // Every failure from here on starts the lockout over.
+        if failures >= policy.lockout_after {
+            warn!(
+                "Locked out {} for {} minutes after {} failed logins",
+                key,
+                policy.lockout.as_secs() / 60,
+                failures
+            );
+        }
+    }
 
-    let cookie = cookie::Cookie::build("jwt", token).http_only(true).finish();
+    Ok(())
+}
+
+/// The last step of every login that has proven who the user is: ask for a
+/// second factor if they need one, otherwise start a session.
+async fn finish_login(database: &Store, user: &User) -> Result<Response<Body>, AppError> {
+    let has_totp = database
+        .get_totp(user.id)
+        .await?
+        .is_some_and(|totp| totp.enabled);
+    if has_totp {
+        return second_factor_redirect(user, false, "/login/2fa");
+    }
+    if admin_needs_2fa(database, user).await? {
+        return second_factor_redirect(user, true, "/2fa/setup");
+    }
+
+    let (access_cookie, refresh_cookie) =
+        issue_session(database, user, Uuid::new_v4()).await?;
 
     let mut response = Response::builder()
         .status(StatusCode::FOUND)
@@ -123,25 +275,373 @@ pub async fn login(
     response
         .headers_mut()
         .insert(LOCATION, HeaderValue::from_static("/"));
-    response.headers_mut().insert(
-        SET_COOKIE,
-        HeaderValue::from_str(&cookie.to_string()).unwrap(),
-    );
+    for cookie in [access_cookie, refresh_cookie] {
+        response.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(&cookie.to_string()).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Exchanges a refresh token for a new access token and a new refresh token.
+pub async fn refresh_token(
+    State(database): State<Store>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, AppError> {
+    let raw_token = COOKIE_POLICY
+        .read(&headers, &REFRESH_COOKIE)
+        .ok_or(AppError::InvalidToken)?;
+    let cookies = rotate_session(&database, &raw_token).await?;
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(
+            serde_json::json!({"message": "Token refreshed"}).to_string(),
+        ))
+        .unwrap();
+
+    for cookie in cookies {
+        response.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(&cookie.to_string()).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Swaps a refresh token for a new access cookie and a new refresh cookie.
+/// Every refresh token can be exchanged exactly once; presenting one a second
+/// time means it was stolen, so the whole family descending from that login
+/// is revoked. The exception is a token exchanged in the last
+/// [`REFRESH_REUSE_GRACE`], which only gets a new access cookie, as the
+/// request that exchanged it has the new refresh cookie on its way.
+pub async fn rotate_session(
+    database: &Store,
+    raw_token: &str,
+) -> Result<Vec<cookie::Cookie<'static>>, AppError> {
+    let token_hash = hash_token(raw_token);
+    let stored = database
+        .get_refresh_token(&token_hash)
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+    let exchanged = !stored.is_spent() && database.mark_refresh_token_used(stored.id).await?;
+    if !exchanged {
+        // Read it again, another request may have exchanged it just now.
+        let stored = database
+            .get_refresh_token(&token_hash)
+            .await?
+            .ok_or(AppError::InvalidToken)?;
+        if !stored.was_just_used(REFRESH_REUSE_GRACE) {
+            let revoked = database.revoke_token_family(stored.family_id).await?;
+            warn!(
+                "Refresh token reuse detected for user {}, revoked {} token(s) in family {}",
+                stored.user_id, revoked, stored.family_id
+            );
+            return Err(AppError::InvalidToken);
+        }
+    }
+
+    if stored.is_expired() {
+        return Err(AppError::InvalidToken);
+    }
+
+    let user = database.get_user_by_id(stored.user_id).await?;
+    if admin_needs_2fa(database, &user).await? {
+        return Err(AppError::InvalidToken);
+    }
+
+    if exchanged {
+        let (access_cookie, refresh_cookie) =
+            issue_session(database, &user, stored.family_id).await?;
+        Ok(vec![access_cookie, refresh_cookie])
+    } else {
+        Ok(vec![access_cookie(&user)?])
+    }
+}
+
+/// Builds a short-lived access token cookie and stores a fresh refresh token
+/// belonging to `family_id`.
+async fn issue_session(
+    database: &Store,
+    user: &User,
+    family_id: Uuid,
+) -> Result<(cookie::Cookie<'static>, cookie::Cookie<'static>), AppError> {
+    let access_cookie = access_cookie(user)?;
+
+    let (refresh_token, refresh_hash) = generate_token();
+    let refresh_expires = Utc::now()
+        + chrono::Duration::from_std(REFRESH_TOKEN_LIFETIME)
+            .map_err(|_| AppError::InternalServerError)?;
+    database
+        .create_refresh_token(user.id, family_id, &refresh_hash, refresh_expires)
+        .await?;
+
+    let refresh_cookie =
+        COOKIE_POLICY.build(&REFRESH_COOKIE, refresh_token, Some(REFRESH_TOKEN_LIFETIME));
+
+    Ok((access_cookie, refresh_cookie))
+}
+
+fn access_cookie(user: &User) -> Result<cookie::Cookie<'static>, AppError> {
+    let claims = Claims {
+        sub: user.id,
+        email: user.email.to_owned(),
+        exp: get_timestamp_after(ACCESS_TOKEN_LIFETIME),
+        role: user.role,
+        email_verified: user.email_verified,
+        scopes: None,
+    };
+
+    let token = keys::sign(&claims)?;
+
+    Ok(COOKIE_POLICY.build(&ACCESS_COOKIE, token, Some(ACCESS_TOKEN_LIFETIME)))
+}
+
+/// Emails a signed link that confirms the user owns their address, unless one
+/// was sent very recently.
+async fn send_verification_email(database: &Store, user: &User) -> Result<(), AppError> {
+    let cooldown = VERIFICATION_RESEND_COOLDOWN.as_secs() as i64;
+    if !database.claim_verification_send(user.id, cooldown).await? {
+        return Err(AppError::TooManyRequests);
+    }
+
+    let claims = EmailVerificationClaims {
+        sub: user.id,
+        email: user.email.to_owned(),
+        purpose: EMAIL_VERIFICATION_PURPOSE.to_string(),
+        exp: get_timestamp_after(EMAIL_VERIFICATION_LIFETIME),
+    };
+    let token = keys::sign(&claims)?;
+
+    let link = format!("{}/verify_email?token={}", get_public_url(), token);
+    database
+        .mailer
+        .send(Email {
+            to: user.email.to_owned(),
+            subject: "Confirm your Rust Blog email address".to_string(),
+            body: format!(
+                "Welcome to the Rust Blog!\n\n\
+                 Please confirm your email address within the next 24 hours by following this link:\n{}",
+                link
+            ),
+        })
+        .await
+}
+
+pub async fn verify_email(
+    State(database): State<Store>,
+    OptionalClaims(claims): OptionalClaims,
+    Query(query): Query<VerifyEmailQuery>,
+) -> Result<Response<Body>, AppError> {
+    let verification = keys::verify::<EmailVerificationClaims>(&query.token)?;
+
+    if verification.purpose != EMAIL_VERIFICATION_PURPOSE {
+        return Err(AppError::InvalidToken);
+    }
+
+    let mut user = database.get_user_by_id(verification.sub).await?;
+    if user.email != verification.email {
+        return Err(AppError::InvalidToken);
+    }
+
+    database.mark_email_verified(user.id).await?;
+    user.email_verified = true;
+
+    let mut response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/")
+        .body(Body::empty())
+        .unwrap();
+
+    // Swap the current session's access token for one that already carries
+    // the new claim. A personal access token never gets a session from this.
+    if claims.is_some_and(|claims| claims.is_session() && claims.sub == user.id) {
+        response.headers_mut().insert(
+            SET_COOKIE,
+            HeaderValue::from_str(&access_cookie(&user)?.to_string()).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+pub async fn resend_verification(
+    State(database): State<Store>,
+    claims: Claims,
+) -> Result<Response<Body>, AppError> {
+    claims.require_session()?;
+    let user = database.get_user_by_id(claims.sub).await?;
+    if !user.email_verified {
+        send_verification_email(&database, &user).await?;
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/")
+        .body(Body::empty())
+        .unwrap();
 
     Ok(response)
 }
 
+pub async fn change_email_page(claims: Claims) -> Result<Html<String>, AppError> {
+    claims.require_session()?;
+    let mut context = Context::new();
+    context.insert("email", &claims.email);
+    render_template("change_email.html", &context)
+}
+
+/// Sends a confirmation link to the new address and a heads-up to the old
+/// one. The page looks the same whether or not the new address already has
+/// an account, so this can't be used to find out who is registered.
+pub async fn change_email(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(form): Form<ChangeEmailForm>,
+) -> Result<Html<String>, AppError> {
+    claims.require_session()?;
+    let new_email = form.new_email.trim().to_string();
+    if !new_email.contains('@') || form.password.is_empty() {
+        return Err(AppError::MissingCredentials);
+    }
+
+    let user = database.get_user_by_id(claims.sub).await?;
+    let is_password_correct = argon2::verify_encoded(&user.password, form.password.as_bytes())
+        .map_err(|_| AppError::InternalServerError)?;
+    if !is_password_correct {
+        return Err(AppError::InvalidCredentials);
+    }
+    if new_email == user.email {
+        return Err(AppError::MissingCredentials);
+    }
+
+    let cooldown = VERIFICATION_RESEND_COOLDOWN.as_secs() as i64;
+    if !database.claim_email_change_send(user.id, cooldown).await? {
+        return Err(AppError::TooManyRequests);
+    }
+
+    let email = if database.find_user(&new_email).await?.is_some() {
+        Email {
+            to: new_email.to_owned(),
+            subject: "Someone tried to move a Rust Blog account to this address".to_string(),
+            body: "Someone asked to change the email address of a Rust Blog account to this one, \
+                   but this address already has an account, so nothing was changed.\n\n\
+                   If it was you, log in to your existing account instead."
+                .to_string(),
+        }
+    } else {
+        let claims = EmailChangeClaims {
+            sub: user.id,
+            email: user.email.to_owned(),
+            new_email: new_email.to_owned(),
+            purpose: EMAIL_CHANGE_PURPOSE.to_string(),
+            exp: get_timestamp_after(EMAIL_VERIFICATION_LIFETIME),
+        };
+        let token = keys::sign(&claims)?;
+        let link = format!("{}/account/email/confirm?token={}", get_public_url(), token);
+
+        Email {
+            to: new_email.to_owned(),
+            subject: "Confirm your new Rust Blog email address".to_string(),
+            body: format!(
+                "Someone asked to use this address for their Rust Blog account.\n\n\
+                 If it was you, confirm it within the next 24 hours by following this link:\n{}",
+                link
+            ),
+        }
+    };
+    database.mailer.send(email).await?;
+
+    let notice = Email {
+        to: user.email.to_owned(),
+        subject: "Your Rust Blog email address is being changed".to_string(),
+        body: format!(
+            "Someone asked to change the email address of your Rust Blog account to {}.\n\n\
+             Nothing changes until the new address is confirmed. \
+             If this wasn't you, reset your password straight away.",
+            new_email
+        ),
+    };
+    if let Err(err) = database.mailer.send(notice).await {
+        error!("Could not send email change notice: {:?}", err);
+    }
+
+    let mut context = Context::new();
+    context.insert("email", &user.email);
+    context.insert("sent_to", &new_email);
+    render_template("change_email.html", &context)
+}
+
+/// Switches the account over once the new address is confirmed. Sessions
+/// carry the old address, so every one of them is ended and the user logs
+/// in again with the new one.
+pub async fn confirm_email_change(
+    State(database): State<Store>,
+    Query(query): Query<VerifyEmailQuery>,
+) -> Result<Response, AppError> {
+    let change = keys::verify::<EmailChangeClaims>(&query.token)?;
+    if change.purpose != EMAIL_CHANGE_PURPOSE {
+        return Err(AppError::InvalidToken);
+    }
+
+    if !database
+        .change_email(change.sub, &change.email, &change.new_email)
+        .await?
+    {
+        return Err(AppError::InvalidToken);
+    }
+    database.revoke_user_refresh_tokens(change.sub).await?;
+    info!("User {} changed their email address", change.sub);
+
+    let mut context = Context::new();
+    context.insert("changed", &true);
+    let page = render_template("change_email.html", &context)?;
+
+    let cookies = [
+        (SET_COOKIE, COOKIE_POLICY.clear(&ACCESS_COOKIE).to_string()),
+        (SET_COOKIE, COOKIE_POLICY.clear(&REFRESH_COOKIE).to_string()),
+    ];
+
+    Ok((AppendHeaders(cookies), page).into_response())
+}
+
 pub async fn post_blog(
     State(mut am_database) : State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<WritePosts>,
     Form(blog): Form<Blog>,
 ) -> Result<Json<Blog>, AppError> {
+    if !claims.email_verified {
+        return Err(AppError::EmailNotVerified);
+    }
+    if blog.title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(AppError::InvalidPost(format!(
+            "The title can be at most {} characters",
+            MAX_TITLE_LENGTH
+        )));
+    }
+
+    let author = am_database.get_user_by_id(claims.sub).await?;
     let blog = am_database
-    .post_blog(blog.title, blog.email, blog.content, blog.publish_date)
+    .post_blog(blog.title, author.id, blog.content, blog.publish_date, blog.tags, None)
     .await?;
+    announce_post(&am_database, blog.id).await;
 
     Ok(Json(blog))
 }
 
+/// Tells followers and linked pages about a new post. Followers missing a
+/// post shouldn't stop it from being published here.
+async fn announce_post(database: &Store, blog_id: i32) {
+    if let Err(err) = activitypub::publish(database, &get_public_url(), blog_id).await {
+        error!("Could not send post {} to followers: {:?}", blog_id, err);
+    }
+    tokio::spawn(webmention::send_for_post(database.clone(), blog_id));
+}
+
 pub async fn make_blog (
     State(_am_database): State<Store>,
     OptionalClaims(claims): OptionalClaims,
@@ -151,6 +651,7 @@ pub async fn make_blog (
 
     let template_name = if let Some(claims_data) = claims {
         error!("Setting claims and is_logged_in is TRUE now");
+        context.insert("can_write", &is_permitted::<WritePosts>(&claims_data));
         context.insert("claims", &claims_data);
         context.insert("is_logged_in", &true);
         "make_blog.html"
@@ -199,9 +700,1457 @@ pub async fn all_blogs(
     Ok(Html(rendered))
 }
 
-pub async fn protected(claims: Claims) -> Result<String, AppError> {
-  Ok(format!(
-      "Your claim data is: {}",
-      claims
-  ))
+pub async fn profile_page(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<EditProfile>,
+) -> Result<Html<String>, AppError> {
+    let user = database.get_user_by_id(claims.sub).await?;
+
+    let mut context = Context::new();
+    context.insert("profile", &database.get_profile(user.id).await?);
+    render_template("profile_edit.html", &context)
+}
+
+pub async fn update_profile(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<EditProfile>,
+    Form(form): Form<ProfileForm>,
+) -> Result<Response<Body>, AppError> {
+    let user = database.get_user_by_id(claims.sub).await?;
+    let profile = form.into_profile(user.id)?;
+    database.update_profile(&profile).await?;
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, format!("/authors/{}", profile.handle))
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+/// An author's public page: their profile and everything they have posted.
+pub async fn author_page(
+    State(database): State<Store>,
+    Path(handle): Path<String>,
+) -> Result<Html<String>, AppError> {
+    let profile = database
+        .get_profile_by_handle(&handle)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let mut context = Context::new();
+    context.insert("blogs", &database.get_blogs_by_author(profile.user_id).await?);
+    context.insert("actor_url", &activitypub::actor_url(&get_public_url(), profile.user_id));
+    context.insert("fediverse_handle", &activitypub::fediverse_handle(&get_public_url(), &profile.handle));
+    context.insert("profile", &profile);
+    render_template("author.html", &context)
+}
+
+pub async fn post_page(
+    State(database): State<Store>,
+    Path(id): Path<i32>,
+) -> Result<Html<String>, AppError> {
+    let blog = database.get_blog(id).await?.ok_or(AppError::NotFound)?;
+    let author = database.get_profile(blog.author_id).await?;
+
+    let mut context = Context::new();
+    context.insert("meta", &PostMeta::new(&blog, &author));
+    context.insert("webmentions", &database.get_verified_webmentions(blog.id).await?);
+    context.insert("comments", &database.get_comments(blog.id).await?);
+    context.insert("blog", &blog);
+    render_template("post.html", &context)
+}
+
+pub async fn tag_page(
+    State(database): State<Store>,
+    Path(tag): Path<String>,
+) -> Result<Html<String>, AppError> {
+    let tag = normalize_tag(&tag);
+    let blogs = database.get_recent_blogs(None, Some(&tag), i64::MAX, 0).await?;
+
+    let mut context = Context::new();
+    context.insert("blogs", &blogs);
+    context.insert("tag", &tag);
+    render_template("tag.html", &context)
+}
+
+pub async fn rss_feed(State(database): State<Store>) -> Result<Response<Body>, AppError> {
+    let blogs = database.get_recent_blogs(None, None, FEED_LENGTH, 0).await?;
+    typed_response(RSS_CONTENT_TYPE, feeds::rss_feed(&FeedSource::site(&get_public_url()), &blogs)?)
+}
+
+pub async fn atom_feed(State(database): State<Store>) -> Result<Response<Body>, AppError> {
+    let blogs = database.get_recent_blogs(None, None, FEED_LENGTH, 0).await?;
+    typed_response(ATOM_CONTENT_TYPE, feeds::atom_feed(&FeedSource::site(&get_public_url()), &blogs)?)
+}
+
+pub async fn author_rss_feed(
+    State(database): State<Store>,
+    Path(handle): Path<String>,
+) -> Result<Response<Body>, AppError> {
+    let (source, blogs) = author_feed(&database, &handle).await?;
+    typed_response(RSS_CONTENT_TYPE, feeds::rss_feed(&source, &blogs)?)
+}
+
+pub async fn author_atom_feed(
+    State(database): State<Store>,
+    Path(handle): Path<String>,
+) -> Result<Response<Body>, AppError> {
+    let (source, blogs) = author_feed(&database, &handle).await?;
+    typed_response(ATOM_CONTENT_TYPE, feeds::atom_feed(&source, &blogs)?)
+}
+
+pub async fn tag_rss_feed(
+    State(database): State<Store>,
+    Path(tag): Path<String>,
+) -> Result<Response<Body>, AppError> {
+    let tag = normalize_tag(&tag);
+    let blogs = database.get_recent_blogs(None, Some(&tag), FEED_LENGTH, 0).await?;
+    typed_response(RSS_CONTENT_TYPE, feeds::rss_feed(&FeedSource::tag(&get_public_url(), &tag), &blogs)?)
+}
+
+pub async fn tag_atom_feed(
+    State(database): State<Store>,
+    Path(tag): Path<String>,
+) -> Result<Response<Body>, AppError> {
+    let tag = normalize_tag(&tag);
+    let blogs = database.get_recent_blogs(None, Some(&tag), FEED_LENGTH, 0).await?;
+    typed_response(ATOM_CONTENT_TYPE, feeds::atom_feed(&FeedSource::tag(&get_public_url(), &tag), &blogs)?)
+}
+
+/// The newest posts as a JSON Feed, a page at a time. Every page but the last
+/// links to the next one in `next_url`.
+pub async fn json_feed(
+    State(database): State<Store>,
+    Query(query): Query<PageQuery>,
+) -> Result<Response<Body>, AppError> {
+    let page = query.page();
+    let offset = query.offset(FEED_LENGTH).ok_or(AppError::NotFound)?;
+    let mut blogs = database
+        .get_recent_blogs(None, None, FEED_LENGTH + 1, offset)
+        .await?;
+    if blogs.is_empty() && page > 1 {
+        return Err(AppError::NotFound);
+    }
+
+    let source = FeedSource::site(&get_public_url());
+    let next_url = if blogs.len() as i64 > FEED_LENGTH {
+        blogs.truncate(FEED_LENGTH as usize);
+        page.checked_add(1)
+            .map(|next| format!("{}?page={}", source.json_url, next))
+    } else {
+        None
+    };
+    let feed_url = if page == 1 {
+        source.json_url.clone()
+    } else {
+        format!("{}?page={}", source.json_url, page)
+    };
+
+    let feed = feeds::json_feed(&source, &blogs, feed_url, next_url);
+    typed_response(JSON_FEED_CONTENT_TYPE, serde_json::to_string(&feed)?)
+}
+
+pub async fn sitemap(State(database): State<Store>) -> Result<Response<Body>, AppError> {
+    let urls = sitemap_urls(&database).await?;
+
+    let body = if sitemap::needs_index(&urls) {
+        sitemap::sitemap_index(&urls)
+    } else {
+        sitemap::urlset(&urls)
+    };
+    typed_response(SITEMAP_CONTENT_TYPE, body)
+}
+
+/// `/sitemaps/:n.xml`, one of the sitemaps a sitemap index points to.
+pub async fn sitemap_page(
+    State(database): State<Store>,
+    Path(file): Path<String>,
+) -> Result<Response<Body>, AppError> {
+    let n = file
+        .strip_suffix(".xml")
+        .and_then(|n| n.parse::<usize>().ok())
+        .ok_or(AppError::NotFound)?;
+    let urls = sitemap_urls(&database).await?;
+    let page = sitemap::page(&urls, n).ok_or(AppError::NotFound)?;
+
+    typed_response(SITEMAP_CONTENT_TYPE, sitemap::urlset(page))
+}
+
+pub async fn robots_txt() -> Result<Response<Body>, AppError> {
+    typed_response("text/plain; charset=utf-8", sitemap::robots_txt())
+}
+
+/// The listings, then every author page, tag page and post.
+async fn sitemap_urls(database: &Store) -> Result<Vec<SitemapUrl>, AppError> {
+    let posts = database.get_post_updates().await?;
+    let newest = posts.iter().map(|(_, updated_at)| *updated_at).max();
+
+    let mut urls = vec![SitemapUrl::new("/", None), SitemapUrl::new("/all_blogs", newest)];
+    for (handle, updated_at) in database.get_author_updates().await? {
+        urls.push(SitemapUrl::new(&format!("/authors/{}", handle), Some(updated_at)));
+    }
+    for (tag, updated_at) in database.get_tag_updates().await? {
+        urls.push(SitemapUrl::new(&format!("/tags/{}", tag), Some(updated_at)));
+    }
+    for (id, updated_at) in posts {
+        urls.push(SitemapUrl::new(&format!("/posts/{}", id), Some(updated_at)));
+    }
+
+    Ok(urls)
+}
+
+pub async fn webfinger(
+    State(database): State<Store>,
+    Query(query): Query<WebfingerQuery>,
+) -> Result<Response<Body>, AppError> {
+    let handle = activitypub::parse_acct(&get_public_url(), &query.resource).ok_or(AppError::NotFound)?;
+    let profile = database
+        .get_profile_by_handle(&handle)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    typed_response(JRD_CONTENT_TYPE, activitypub::webfinger(&get_public_url(), &profile).to_string())
+}
+
+pub async fn actor(
+    State(database): State<Store>,
+    Path(user_id): Path<i32>,
+) -> Result<Response<Body>, AppError> {
+    let profile = actor_profile(&database, user_id).await?;
+    let key = activitypub::actor_key(&database, user_id).await?;
+
+    let actor = activitypub::document(activitypub::actor(&get_public_url(), &profile, &key));
+    typed_response(ACTIVITY_CONTENT_TYPE, actor.to_string())
+}
+
+pub async fn actor_outbox(
+    State(database): State<Store>,
+    Path(user_id): Path<i32>,
+) -> Result<Response<Body>, AppError> {
+    actor_profile(&database, user_id).await?;
+    let blogs = database
+        .get_recent_blogs(Some(user_id), None, FEED_LENGTH, 0)
+        .await?;
+    let total = database.count_blogs_by_author(user_id).await?;
+
+    let outbox = activitypub::document(activitypub::outbox(&get_public_url(), user_id, &blogs, total));
+    typed_response(ACTIVITY_CONTENT_TYPE, outbox.to_string())
+}
+
+pub async fn actor_followers(
+    State(database): State<Store>,
+    Path(user_id): Path<i32>,
+) -> Result<Response<Body>, AppError> {
+    actor_profile(&database, user_id).await?;
+    let total = database.count_followers(user_id).await?;
+
+    let followers = activitypub::document(activitypub::followers(&get_public_url(), user_id, total));
+    typed_response(ACTIVITY_CONTENT_TYPE, followers.to_string())
+}
+
+/// Takes activities from other servers. Only signed requests get in.
+pub async fn actor_inbox(
+    State(database): State<Store>,
+    Path(user_id): Path<i32>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, AppError> {
+    actor_profile(&database, user_id).await?;
+
+    let target = uri.path_and_query().map(|target| target.as_str()).unwrap_or("/");
+    let sender =
+        activitypub::verify_request(&database.remote, &method, target, &headers, &body).await?;
+    let activity: Value = serde_json::from_slice(&body)?;
+    activitypub::handle_activity(&database, &get_public_url(), user_id, sender, activity).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn activitypub_post(
+    State(database): State<Store>,
+    Path(id): Path<i32>,
+) -> Result<Response<Body>, AppError> {
+    let blog = database.get_blog(id).await?.ok_or(AppError::NotFound)?;
+
+    let article = activitypub::document(activitypub::article(&get_public_url(), &blog));
+    typed_response(ACTIVITY_CONTENT_TYPE, article.to_string())
+}
+
+/// Takes a Webmention from another site. The source is checked in the
+/// background, so the sender only hears that it was accepted. Anyone can
+/// send these, so each address only gets to send so many.
+pub async fn receive_webmention(
+    State(database): State<Store>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Form(form): Form<WebmentionForm>,
+) -> Result<(StatusCode, String), AppError> {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr);
+    if let Some(ip) = client_ip(&headers, peer) {
+        let throttle_key = webmention_key(&ip);
+        check_login_throttle(&database, &[(throttle_key.clone(), &WEBMENTION_POLICY)]).await?;
+        database.record_login_failure(&throttle_key, &WEBMENTION_POLICY).await?;
+    }
+
+    if let Some(webmention) = webmention::receive(&database, &form.source, &form.target).await? {
+        tokio::spawn(webmention::verify(database.clone(), webmention));
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        "Thanks, the mention will show up once its source has been checked".to_string(),
+    ))
+}
+
+/// Micropub queries: `q=config`, `q=syndicate-to` and `q=source`.
+pub async fn micropub_query(
+    State(database): State<Store>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Value>, AppError> {
+    let claims = micropub::authenticate(&database, &headers, None).await?;
+    let query = micropub::parse_query(&query.unwrap_or_default());
+
+    match query.q.as_str() {
+        "config" => Ok(Json(micropub::config())),
+        "syndicate-to" => Ok(Json(serde_json::json!({"syndicate-to": []}))),
+        "source" => {
+            let url = query
+                .url
+                .ok_or_else(|| AppError::invalid_micropub("q=source needs a url"))?;
+            let user = database.get_user_by_id(claims.sub).await?;
+            let blog = editable_post(&database, &claims, user.id, &url).await?;
+            Ok(Json(micropub::source(&blog, &query.properties)))
+        }
+        _ => Err(AppError::invalid_micropub("unsupported query")),
+    }
+}
+
+/// Creates, updates or deletes a post from a Micropub client, mapping onto
+/// the same post operations as the form.
+pub async fn micropub(
+    State(mut database): State<Store>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response<Body>, AppError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let (request, body_token) = if content_type.starts_with("application/json") {
+        (micropub::parse_json(&body)?, None)
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        micropub::parse_form(&body)?
+    } else {
+        return Err(AppError::invalid_micropub("send the request form-encoded or as JSON"));
+    };
+
+    let claims = micropub::authenticate(&database, &headers, body_token).await?;
+    let user = database.get_user_by_id(claims.sub).await?;
+
+    let response = match request {
+        MicropubRequest::Create(properties) => {
+            if !is_permitted::<WritePosts>(&claims) {
+                return Err(AppError::Forbidden);
+            }
+            if !claims.email_verified {
+                return Err(AppError::EmailNotVerified);
+            }
+
+            let entry = micropub::new_entry(&properties)?;
+            let publish_date = entry
+                .published_at
+                .unwrap_or_else(Utc::now)
+                .format("%Y-%m-%d")
+                .to_string();
+            let blog = database
+                .post_blog(entry.title, user.id, entry.content, publish_date, entry.tags, entry.published_at)
+                .await?;
+            announce_post(&database, blog.id).await;
+
+            Response::builder()
+                .status(StatusCode::CREATED)
+                .header(LOCATION, feeds::post_url(&blog))
+                .body(Body::empty())
+        }
+        MicropubRequest::Update { url, replace, add, delete } => {
+            let mut blog = editable_post(&database, &claims, user.id, &url).await?;
+            micropub::apply_update(&mut blog, &replace, &add, &delete)?;
+            database.update_blog(&blog).await?;
+            tokio::spawn(webmention::send_for_post(database.clone(), blog.id));
+
+            Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty())
+        }
+        MicropubRequest::Delete { url } => {
+            let blog = editable_post(&database, &claims, user.id, &url).await?;
+            database.delete_blog(blog.id).await?;
+
+            Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty())
+        }
+    };
+
+    response.map_err(|_| AppError::InternalServerError)
+}
+
+/// Takes one file in the `file` field and answers with where it can be found.
+pub async fn micropub_media(
+    State(database): State<Store>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response<Body>, AppError> {
+    let invalid = |err: MultipartError| AppError::invalid_micropub(err.body_text());
+
+    let mut file = None;
+    let mut body_token = None;
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        match field.name() {
+            Some("file") => {
+                let content_type = field.content_type().unwrap_or_default().to_string();
+                file = Some((content_type, field.bytes().await.map_err(invalid)?));
+            }
+            Some("access_token") => body_token = Some(field.text().await.map_err(invalid)?),
+            _ => {}
+        }
+    }
+
+    let claims = micropub::authenticate(&database, &headers, body_token).await?;
+    if !is_permitted::<WritePosts>(&claims) {
+        return Err(AppError::Forbidden);
+    }
+    let (content_type, bytes) =
+        file.ok_or_else(|| AppError::invalid_micropub("the upload needs a file"))?;
+    let url = micropub::save_media(&content_type, &bytes).await?;
+
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .header(LOCATION, url)
+        .body(Body::empty())
+        .map_err(|_| AppError::InternalServerError)
+}
+
+/// Serves a file uploaded through the media endpoint.
+pub async fn media_file(Path(file): Path<String>) -> Result<Response<BoxBody>, AppError> {
+    let uri: Uri = format!("/{}", file).parse().map_err(|_| AppError::NotFound)?;
+    let request = http::Request::builder()
+        .uri(uri)
+        .body(Body::empty())
+        .map_err(|_| AppError::InternalServerError)?;
+
+    match ServeDir::new(micropub::media_dir()).oneshot(request).await {
+        Ok(response) => Ok(response.map(boxed)),
+        Err(_) => Err(AppError::InternalServerError),
+    }
+}
+
+/// A post's source, if the user may change it: their own posts need
+/// [`WritePosts`], anyone else's [`EditAnyPost`].
+async fn editable_post(
+    database: &Store,
+    claims: &Claims,
+    user_id: i32,
+    url: &str,
+) -> Result<Blog, AppError> {
+    let blog = database
+        .get_blog_source(micropub::post_id(url)?)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let allowed = if blog.author_id == user_id {
+        is_permitted::<WritePosts>(claims)
+    } else {
+        is_permitted::<EditAnyPost>(claims)
+    };
+    if !allowed {
+        return Err(AppError::Forbidden);
+    }
+
+    Ok(blog)
+}
+
+/// The profile behind an actor, or a 404 for ids nobody has.
+async fn actor_profile(database: &Store, user_id: i32) -> Result<Profile, AppError> {
+    database.get_profile(user_id).await.map_err(|err| match err {
+        AppError::Database(sqlx::Error::RowNotFound) => AppError::NotFound,
+        err => err,
+    })
+}
+
+async fn author_feed(database: &Store, handle: &str) -> Result<(FeedSource, Vec<Blog>), AppError> {
+    let profile = database
+        .get_profile_by_handle(handle)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let blogs = database
+        .get_recent_blogs(Some(profile.user_id), None, FEED_LENGTH, 0)
+        .await?;
+
+    Ok((FeedSource::author(&get_public_url(), &profile), blogs))
+}
+
+fn typed_response(content_type: &'static str, body: String) -> Result<Response<Body>, AppError> {
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .map_err(|_| AppError::InternalServerError)
+}
+
+pub async fn protected(claims: Claims) -> Result<String, AppError> {
+  Ok(format!(
+      "Your claim data is: {}",
+      claims
+  ))
+}
+
+pub async fn forgot_password_page() -> Result<Html<String>, AppError> {
+    render_template("forgot_password.html", &Context::new())
+}
+
+/// Emails a reset link if the account exists. The response is identical either
+/// way so this form can't be used to find out who has an account.
+pub async fn forgot_password(
+    State(database): State<Store>,
+    Form(request): Form<ForgotPassword>,
+) -> Result<Html<String>, AppError> {
+    if let Ok(user) = database.get_user(&request.email).await {
+        let (token, token_hash) = generate_token();
+        let expires_at = Utc::now()
+            + chrono::Duration::from_std(PASSWORD_RESET_LIFETIME)
+                .map_err(|_| AppError::InternalServerError)?;
+        database
+            .create_password_reset(user.id, &token_hash, expires_at)
+            .await?;
+
+        let link = format!("{}/reset_password?token={}", get_public_url(), token);
+        let email = Email {
+            to: user.email,
+            subject: "Reset your Rust Blog password".to_string(),
+            body: format!(
+                "Someone asked to reset the password for your account.\n\n\
+                 Follow this link within the next hour to choose a new one:\n{}\n\n\
+                 If this wasn't you, you can ignore this email.",
+                link
+            ),
+        };
+        if let Err(err) = database.mailer.send(email).await {
+            error!("Could not send password reset email: {:?}", err);
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("sent", &true);
+    render_template("forgot_password.html", &context)
+}
+
+pub async fn reset_password_page(
+    State(database): State<Store>,
+    Query(query): Query<ResetQuery>,
+) -> Result<Html<String>, AppError> {
+    let mut context = Context::new();
+    let is_valid = database
+        .password_reset_is_valid(&hash_token(&query.token))
+        .await?;
+    context.insert("is_valid", &is_valid);
+    context.insert("token", &query.token);
+    render_template("reset_password.html", &context)
+}
+
+pub async fn reset_password(
+    State(database): State<Store>,
+    Form(reset): Form<ResetPassword>,
+) -> Result<Response<Body>, AppError> {
+    if reset.password.is_empty() || reset.password != reset.confirm_password {
+        return Err(AppError::MissingCredentials);
+    }
+
+    let user_id = database
+        .consume_password_reset(&hash_token(&reset.token))
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+    let hashed_password = hash_password(&reset.password)?;
+    database.update_password(user_id, &hashed_password).await?;
+    database.revoke_user_refresh_tokens(user_id).await?;
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+/// True when admins are required to use two-factor authentication and this
+/// admin hasn't set it up yet.
+async fn admin_needs_2fa(database: &Store, user: &User) -> Result<bool, AppError> {
+    if user.role != Role::Admin {
+        return Ok(false);
+    }
+
+    let required = database.get_setting(REQUIRE_ADMIN_2FA).await?.as_deref() == Some("true");
+    let has_totp = database
+        .get_totp(user.id)
+        .await?
+        .is_some_and(|totp| totp.enabled);
+
+    Ok(required && !has_totp)
+}
+
+/// Sends someone who got their password right on to the second factor step
+/// without giving them a session yet.
+fn second_factor_redirect(user: &User, enrol: bool, location: &str) -> Result<Response<Body>, AppError> {
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .header(SET_COOKIE, second_factor_cookie(user, enrol)?.to_string())
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+fn second_factor_cookie(user: &User, enrol: bool) -> Result<cookie::Cookie<'static>, AppError> {
+    let claims = SecondFactorClaims {
+        sub: user.id,
+        purpose: SECOND_FACTOR_PURPOSE.to_string(),
+        enrol,
+        exp: get_timestamp_after(SECOND_FACTOR_LIFETIME),
+    };
+    let token = keys::sign(&claims)?;
+
+    Ok(COOKIE_POLICY.build(&SECOND_FACTOR_COOKIE, token, Some(SECOND_FACTOR_LIFETIME)))
+}
+
+fn pending_second_factor(headers: &HeaderMap) -> Option<SecondFactorClaims> {
+    let token = COOKIE_POLICY.read(headers, &SECOND_FACTOR_COOKIE)?;
+    let claims = keys::verify::<SecondFactorClaims>(&token).ok()?;
+
+    if claims.purpose == SECOND_FACTOR_PURPOSE {
+        Some(claims)
+    } else {
+        None
+    }
+}
+
+fn clear_second_factor_cookie() -> cookie::Cookie<'static> {
+    COOKIE_POLICY.clear(&SECOND_FACTOR_COOKIE)
+}
+
+/// Accepts either a current TOTP code or one of the user's unused recovery codes.
+async fn verify_second_factor(database: &Store, user: &User, code: &str) -> Result<(), AppError> {
+    let totp = database
+        .get_totp(user.id)
+        .await?
+        .filter(|totp| totp.enabled)
+        .ok_or(AppError::InvalidSecondFactor)?;
+
+    let generator = build_totp(&totp.secret, &user.email)?;
+    let now = Utc::now().timestamp() as u64;
+    if let Some(step) = matching_step(&generator, code, totp.last_used_step, now) {
+        if database.use_totp_step(user.id, step).await? {
+            return Ok(());
+        }
+    }
+
+    let recovery_code = code.trim().to_lowercase();
+    if database
+        .use_recovery_code(user.id, &hash_token(&recovery_code))
+        .await?
+    {
+        warn!("User {} logged in with a recovery code", user.id);
+        return Ok(());
+    }
+
+    Err(AppError::InvalidSecondFactor)
+}
+
+pub async fn second_factor_page() -> Result<Html<String>, AppError> {
+    render_template("login_2fa.html", &Context::new())
+}
+
+pub async fn second_factor(
+    State(database): State<Store>,
+    headers: HeaderMap,
+    Form(form): Form<SecondFactorForm>,
+) -> Result<Response<Body>, AppError> {
+    let pending = pending_second_factor(&headers)
+        .filter(|pending| !pending.enrol)
+        .ok_or(AppError::InvalidToken)?;
+    let user = database.get_user_by_id(pending.sub).await?;
+
+    // Codes are short enough to guess, so they count towards the same lockout as passwords.
+    let throttle_keys = [(account_key(&user.email), &ACCOUNT_POLICY)];
+    check_login_throttle(&database, &throttle_keys).await?;
+    if let Err(err) = verify_second_factor(&database, &user, &form.code).await {
+        record_login_failure(&database, &throttle_keys).await?;
+        return Err(err);
+    }
+    database.clear_login_throttle(&throttle_keys[0].0).await?;
+
+    let (access_cookie, refresh_cookie) =
+        issue_session(&database, &user, Uuid::new_v4()).await?;
+
+    let mut response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/")
+        .body(Body::empty())
+        .unwrap();
+    for cookie in [access_cookie, refresh_cookie, clear_second_factor_cookie()] {
+        response.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(&cookie.to_string()).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Works out who is setting up two-factor authentication: either a logged in
+/// user, or someone half way through a login that requires enrolment first.
+async fn enrolling_user(
+    database: &Store,
+    claims: Option<Claims>,
+    headers: &HeaderMap,
+) -> Result<(User, bool), AppError> {
+    if let Some(claims) = claims {
+        claims.require_session()?;
+        return Ok((database.get_user_by_id(claims.sub).await?, false));
+    }
+
+    let pending = pending_second_factor(headers)
+        .filter(|pending| pending.enrol)
+        .ok_or(AppError::InvalidToken)?;
+
+    Ok((database.get_user_by_id(pending.sub).await?, true))
+}
+
+pub async fn two_factor_setup_page(
+    State(database): State<Store>,
+    OptionalClaims(claims): OptionalClaims,
+    headers: HeaderMap,
+) -> Result<Html<String>, AppError> {
+    let (user, _) = enrolling_user(&database, claims, &headers).await?;
+
+    let mut context = Context::new();
+    let is_enabled = database
+        .get_totp(user.id)
+        .await?
+        .is_some_and(|totp| totp.enabled);
+    context.insert("is_enabled", &is_enabled);
+
+    if !is_enabled {
+        let secret = generate_secret();
+        database.save_pending_totp(user.id, &secret).await?;
+        let totp = build_totp(&secret, &user.email)?;
+        context.insert("qr_code", &qr_code_svg(&totp)?);
+        context.insert("secret", &secret);
+    }
+
+    render_template("two_factor_setup.html", &context)
+}
+
+pub async fn two_factor_setup(
+    State(database): State<Store>,
+    OptionalClaims(claims): OptionalClaims,
+    headers: HeaderMap,
+    Form(form): Form<SecondFactorForm>,
+) -> Result<Response, AppError> {
+    let (user, is_login) = enrolling_user(&database, claims, &headers).await?;
+
+    let totp = database
+        .get_totp(user.id)
+        .await?
+        .filter(|totp| !totp.enabled)
+        .ok_or(AppError::InvalidSecondFactor)?;
+    let generator = build_totp(&totp.secret, &user.email)?;
+    let now = Utc::now().timestamp() as u64;
+    let step = matching_step(&generator, &form.code, totp.last_used_step, now)
+        .ok_or(AppError::InvalidSecondFactor)?;
+    if !database.use_totp_step(user.id, step).await? {
+        return Err(AppError::InvalidSecondFactor);
+    }
+
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| hash_token(code)).collect();
+    database.enable_totp(user.id, &hashes).await?;
+
+    let mut context = Context::new();
+    context.insert("recovery_codes", &recovery_codes);
+    let page = render_template("recovery_codes.html", &context)?;
+
+    // Someone who was made to enrol during login is finished logging in now.
+    let mut cookies = Vec::new();
+    if is_login {
+        let (access_cookie, refresh_cookie) =
+            issue_session(&database, &user, Uuid::new_v4()).await?;
+        cookies.push((SET_COOKIE, access_cookie.to_string()));
+        cookies.push((SET_COOKIE, refresh_cookie.to_string()));
+        cookies.push((SET_COOKIE, clear_second_factor_cookie().to_string()));
+    }
+
+    Ok((AppendHeaders(cookies), page).into_response())
+}
+
+pub async fn two_factor_disable(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(form): Form<SecondFactorForm>,
+) -> Result<Response<Body>, AppError> {
+    claims.require_session()?;
+    let user = database.get_user_by_id(claims.sub).await?;
+    if user.role == Role::Admin && database.get_setting(REQUIRE_ADMIN_2FA).await?.as_deref() == Some("true") {
+        return Err(AppError::Forbidden);
+    }
+
+    verify_second_factor(&database, &user, &form.code).await?;
+    database.disable_totp(user.id).await?;
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+pub async fn admin_security_page(
+    State(database): State<Store>,
+    _admin: RequirePermission<ManageUsers>,
+) -> Result<Html<String>, AppError> {
+    let mut context = Context::new();
+    let required = database.get_setting(REQUIRE_ADMIN_2FA).await?.as_deref() == Some("true");
+    context.insert("require_admin_2fa", &required);
+    render_template("admin_security.html", &context)
+}
+
+pub async fn set_require_admin_2fa(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<RequireAdmin2faForm>,
+) -> Result<Response<Body>, AppError> {
+    database
+        .set_setting(REQUIRE_ADMIN_2FA, &form.enabled.to_string())
+        .await?;
+    info!("{} set {} to {}", claims.email, REQUIRE_ADMIN_2FA, form.enabled);
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/admin/security")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+async fn new_webauthn_challenge(
+    database: &Store,
+    user_id: Option<i32>,
+    purpose: &str,
+) -> Result<String, AppError> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let challenge = webauthn::encode(&bytes);
+
+    let expires_at = Utc::now()
+        + chrono::Duration::from_std(WEBAUTHN_CHALLENGE_LIFETIME)
+            .map_err(|_| AppError::InternalServerError)?;
+    database
+        .create_webauthn_challenge(&challenge, user_id, purpose, expires_at)
+        .await?;
+
+    Ok(challenge)
+}
+
+/// Starts adding a passkey to the logged in account. The JSON is handed
+/// straight to `navigator.credentials.create()` by static/passkeys.js.
+pub async fn passkey_register_start(
+    State(database): State<Store>,
+    claims: Claims,
+) -> Result<Json<Value>, AppError> {
+    claims.require_session()?;
+    let user = database.get_user_by_id(claims.sub).await?;
+    let rp = RelyingParty::from_env();
+    let challenge = new_webauthn_challenge(&database, Some(user.id), REGISTRATION_CHALLENGE).await?;
+
+    let exclude_credentials: Vec<Value> = database
+        .get_passkeys(user.id)
+        .await?
+        .into_iter()
+        .map(|passkey| serde_json::json!({"type": "public-key", "id": passkey.credential_id}))
+        .collect();
+
+    Ok(Json(serde_json::json!({
+        "publicKey": {
+            "challenge": challenge,
+            "rp": {"id": rp.id, "name": rp.name},
+            "user": {
+                "id": webauthn::encode(user.id.to_string().as_bytes()),
+                "name": user.email,
+                "displayName": user.email,
+            },
+            "pubKeyCredParams": [{"type": "public-key", "alg": webauthn::ES256}],
+            "timeout": WEBAUTHN_CHALLENGE_LIFETIME.as_millis() as u64,
+            "attestation": "none",
+            "authenticatorSelection": {
+                "residentKey": "required",
+                "userVerification": "required",
+            },
+            "excludeCredentials": exclude_credentials,
+        }
+    })))
+}
+
+pub async fn passkey_register_finish(
+    State(database): State<Store>,
+    claims: Claims,
+    Json(registration): Json<PasskeyRegistration>,
+) -> Result<Json<Value>, AppError> {
+    claims.require_session()?;
+    let user = database.get_user_by_id(claims.sub).await?;
+    let rp = RelyingParty::from_env();
+
+    let client_data = webauthn::decode(&registration.client_data_json)?;
+    let challenge = webauthn::verify_client_data(&rp, &client_data, "webauthn.create")?;
+    let challenge_owner = database
+        .take_webauthn_challenge(&challenge, REGISTRATION_CHALLENGE)
+        .await?
+        .ok_or(AppError::InvalidPasskey)?;
+    if challenge_owner != Some(user.id) {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    let credential =
+        webauthn::verify_registration(&rp, &webauthn::decode(&registration.attestation_object)?)?;
+    if credential.credential_id != registration.id.trim_end_matches('=')
+        || database.get_passkey(&credential.credential_id).await?.is_some()
+    {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    let name = registration
+        .name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "Passkey".to_string());
+    database
+        .create_passkey(
+            user.id,
+            &credential.credential_id,
+            &credential.public_key,
+            credential.sign_count,
+            &name,
+        )
+        .await?;
+    info!("{} registered a passkey named {}", user.email, name);
+
+    Ok(Json(serde_json::json!({"message": "Passkey added"})))
+}
+
+/// Starts a usernameless passkey login; the browser offers whichever
+/// discoverable credentials it holds for this site.
+pub async fn passkey_login_start(State(database): State<Store>) -> Result<Json<Value>, AppError> {
+    let rp = RelyingParty::from_env();
+    let challenge = new_webauthn_challenge(&database, None, AUTHENTICATION_CHALLENGE).await?;
+
+    Ok(Json(serde_json::json!({
+        "publicKey": {
+            "challenge": challenge,
+            "rpId": rp.id,
+            "timeout": WEBAUTHN_CHALLENGE_LIFETIME.as_millis() as u64,
+            "userVerification": "required",
+            "allowCredentials": [],
+        }
+    })))
+}
+
+/// Finishes a passkey login and hands out the same cookies as [`login`].
+pub async fn passkey_login_finish(
+    State(database): State<Store>,
+    Json(assertion): Json<PasskeyAssertion>,
+) -> Result<Response<Body>, AppError> {
+    let rp = RelyingParty::from_env();
+
+    let client_data = webauthn::decode(&assertion.client_data_json)?;
+    let challenge = webauthn::verify_client_data(&rp, &client_data, "webauthn.get")?;
+    database
+        .take_webauthn_challenge(&challenge, AUTHENTICATION_CHALLENGE)
+        .await?
+        .ok_or(AppError::InvalidPasskey)?;
+
+    let passkey = database
+        .get_passkey(assertion.id.trim_end_matches('='))
+        .await?
+        .ok_or(AppError::InvalidPasskey)?;
+    let sign_count = webauthn::verify_assertion(
+        &rp,
+        &passkey.public_key,
+        passkey.sign_count,
+        &webauthn::decode(&assertion.authenticator_data)?,
+        &client_data,
+        &webauthn::decode(&assertion.signature)?,
+    )?;
+    database
+        .update_passkey_sign_count(passkey.id, sign_count)
+        .await?;
+
+    let user = database.get_user_by_id(passkey.user_id).await?;
+    let (location, cookies) = if admin_needs_2fa(&database, &user).await? {
+        ("/2fa/setup", vec![second_factor_cookie(&user, true)?])
+    } else {
+        let (access_cookie, refresh_cookie) =
+            issue_session(&database, &user, Uuid::new_v4()).await?;
+        ("/", vec![access_cookie, refresh_cookie])
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(
+            serde_json::json!({"redirect": location}).to_string(),
+        ))
+        .unwrap();
+    for cookie in cookies {
+        response.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(&cookie.to_string()).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Sends the browser to the identity provider to sign in.
+pub async fn oidc_login(State(database): State<Store>) -> Result<Response<Body>, AppError> {
+    let config = OidcConfig::from_env().ok_or(AppError::NotFound)?;
+    let metadata = oidc::discover(&database.http_client, &config).await?;
+
+    let flow = OidcFlowClaims {
+        state: oidc::random_token(),
+        nonce: oidc::random_token(),
+        pkce_verifier: oidc::random_token(),
+        purpose: OIDC_FLOW_PURPOSE.to_string(),
+        exp: get_timestamp_after(OIDC_FLOW_LIFETIME),
+    };
+    let location = oidc::authorization_url(&config, &metadata, &flow)?;
+    let token = keys::sign(&flow)?;
+    let cookie = COOKIE_POLICY.build(&OIDC_FLOW_COOKIE, token, Some(OIDC_FLOW_LIFETIME));
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .header(SET_COOKIE, cookie.to_string())
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Where the identity provider sends the browser back to. Identities are
+/// matched by issuer and subject first; the first time someone signs in they
+/// are linked to the account with the same verified email address, or a new
+/// account is made for them.
+pub async fn oidc_callback(
+    State(database): State<Store>,
+    headers: HeaderMap,
+    Query(callback): Query<OidcCallback>,
+) -> Result<Response<Body>, AppError> {
+    let config = OidcConfig::from_env().ok_or(AppError::NotFound)?;
+    finish_oidc_login(&database, &config, &headers, callback).await
+}
+
+/// Checks the provider's answer against the flow cookie and signs in the
+/// account the identity belongs to, making or linking one if it's new.
+pub async fn finish_oidc_login(
+    database: &Store,
+    config: &OidcConfig,
+    headers: &HeaderMap,
+    callback: OidcCallback,
+) -> Result<Response<Body>, AppError> {
+    if let Some(err) = callback.error {
+        warn!("Identity provider returned an error: {}", err);
+        return Err(AppError::SingleSignOnFailed);
+    }
+
+    let flow_token = COOKIE_POLICY
+        .read(headers, &OIDC_FLOW_COOKIE)
+        .ok_or(AppError::SingleSignOnFailed)?;
+    let flow = keys::verify::<OidcFlowClaims>(&flow_token)
+        .map_err(|_| AppError::SingleSignOnFailed)?;
+    if flow.purpose != OIDC_FLOW_PURPOSE || callback.state.as_deref() != Some(flow.state.as_str()) {
+        return Err(AppError::SingleSignOnFailed);
+    }
+    let code = callback.code.ok_or(AppError::SingleSignOnFailed)?;
+
+    let metadata = oidc::discover(&database.http_client, config).await?;
+    let identity =
+        oidc::exchange_code(&database.http_client, config, &metadata, &code, &flow).await?;
+
+    let user = match database.get_oidc_identity(&identity.iss, &identity.sub).await? {
+        Some(user_id) => database.get_user_by_id(user_id).await?,
+        None => {
+            let email = identity
+                .email
+                .as_deref()
+                .filter(|_| identity.email_verified)
+                .ok_or(AppError::SingleSignOnFailed)?;
+
+            let user = match database.get_user(email).await {
+                Ok(user) => user,
+                Err(_) if invite_only(database).await? => {
+                    return Err(AppError::InvalidInvite);
+                }
+                Err(_) => {
+                    // Nobody knows this password, so the account can only be
+                    // used through the identity provider until it is reset.
+                    let password = hash_password(&oidc::random_token())?;
+                    database.create_verified_user(email, &password).await?
+                }
+            };
+            database
+                .link_oidc_identity(user.id, &identity.iss, &identity.sub, Some(email))
+                .await?;
+            info!("Linked {} identity {} to {}", identity.iss, identity.sub, user.email);
+            user
+        }
+    };
+
+    if !user.email_verified && identity.email_verified && identity.email.as_deref() == Some(user.email.as_str()) {
+        database.mark_email_verified(user.id).await?;
+    }
+
+    let mut response = finish_login(database, &user).await?;
+    let clear_flow = COOKIE_POLICY.clear(&OIDC_FLOW_COOKIE);
+    response.headers_mut().append(
+        SET_COOKIE,
+        HeaderValue::from_str(&clear_flow.to_string()).unwrap(),
+    );
+
+    Ok(response)
+}
+
+pub async fn admin_users_page(
+    State(database): State<Store>,
+    _admin: RequirePermission<ManageUsers>,
+) -> Result<Html<String>, AppError> {
+    let mut context = Context::new();
+    context.insert("users", &database.list_users().await?);
+    context.insert("roles", &Role::ALL);
+    context.insert("lockouts", &database.list_lockouts().await?);
+    render_template("admin_users.html", &context)
+}
+
+pub async fn admin_webmentions_page(
+    State(database): State<Store>,
+    _admin: RequirePermission<ManageUsers>,
+) -> Result<Html<String>, AppError> {
+    let mut context = Context::new();
+    context.insert("webmentions", &database.list_webmentions(MODERATION_PAGE_SIZE).await?);
+    render_template("admin_webmentions.html", &context)
+}
+
+pub async fn moderate_webmention(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<ModerateWebmention>,
+) -> Result<Response<Body>, AppError> {
+    let found = match form.action {
+        ModerationAction::Show => {
+            database
+                .set_webmention_status(form.id, WebmentionStatus::Verified)
+                .await?
+        }
+        ModerationAction::Hide => {
+            database
+                .set_webmention_status(form.id, WebmentionStatus::Hidden)
+                .await?
+        }
+        ModerationAction::Delete => database.delete_webmention(form.id).await?,
+    };
+    if !found {
+        return Err(AppError::NotFound);
+    }
+    info!("{} moderated webmention {}", claims.email, form.id);
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/admin/webmentions")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+pub async fn admin_import_page(
+    _admin: RequirePermission<ManageUsers>,
+) -> Result<Html<String>, AppError> {
+    render_template("admin_import.html", &Context::new())
+}
+
+/// Imports the WordPress export uploaded in the `wxr` field and shows what
+/// it did.
+pub async fn import_wordpress(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    mut multipart: Multipart,
+) -> Result<Html<String>, AppError> {
+    let invalid = |err: MultipartError| AppError::invalid_import(err.body_text());
+
+    let mut xml = None;
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        if field.name() == Some("wxr") {
+            xml = Some(field.text().await.map_err(invalid)?);
+        }
+    }
+    let xml = xml.ok_or_else(|| AppError::invalid_import("Choose an export to import"))?;
+
+    let export = wordpress::parse(&xml)?;
+    let report = wordpress::import(&database, &export).await?;
+    info!("{} imported a WordPress export", claims.email);
+
+    let mut context = Context::new();
+    context.insert("report", &report);
+    render_template("admin_import.html", &context)
+}
+
+/// Downloads everything as an export archive: posts as Markdown, the
+/// uploaded media, and the users, tags and comments.
+pub async fn export_archive(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+) -> Result<Response<Body>, AppError> {
+    // The zip is written to a temporary file, which goes away once the
+    // download is done, rather than held in memory.
+    let file = tokio::task::spawn_blocking(tempfile::tempfile)
+        .await
+        .map_err(|err| AppError::Any(err.into()))?
+        .map_err(|err| AppError::Any(err.into()))?;
+    let mut file = tokio::fs::File::from_std(archive::export(&database, file).await?);
+    file.rewind().await.map_err(|err| AppError::Any(err.into()))?;
+    let length = file.metadata().await.map_err(|err| AppError::Any(err.into()))?.len();
+    info!("{} exported the blog", claims.email);
+
+    Response::builder()
+        .header(CONTENT_TYPE, ARCHIVE_CONTENT_TYPE)
+        .header(CONTENT_LENGTH, length)
+        .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", archive::file_name()),
+        )
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .map_err(|_| AppError::InternalServerError)
+}
+
+pub async fn set_user_role(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<SetRole>,
+) -> Result<Response<Body>, AppError> {
+    database.set_user_role(form.user_id, form.role).await?;
+    info!("{} set the role of user {} to {}", claims.email, form.user_id, form.role);
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/admin/users")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+pub async fn unlock_account(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<UnlockAccount>,
+) -> Result<Response<Body>, AppError> {
+    if !database.clear_login_throttle(&form.key).await? {
+        return Err(AppError::NotFound);
+    }
+    info!("{} lifted the login lockout on {}", claims.email, form.key);
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/admin/users")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Whether new accounts need an invite code.
+async fn invite_only(database: &Store) -> Result<bool, AppError> {
+    Ok(database.get_setting(INVITE_ONLY).await?.as_deref() == Some("true"))
+}
+
+pub async fn invites_page(
+    State(database): State<Store>,
+    _admin: RequirePermission<ManageUsers>,
+) -> Result<Html<String>, AppError> {
+    render_invites(&database, None).await
+}
+
+/// Creates an invite and shows its code, which is only stored hashed.
+pub async fn create_invite(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<CreateInvite>,
+) -> Result<Html<String>, AppError> {
+    if form.max_uses < 1 || !(1..=365).contains(&form.expires_in_days) {
+        return Err(AppError::MissingCredentials);
+    }
+
+    let user = database.get_user_by_id(claims.sub).await?;
+    let (secret, code_hash) = generate_token();
+    let code = format!("{}{}", INVITE_CODE_PREFIX, secret);
+    let expires_at = Utc::now() + chrono::Duration::days(form.expires_in_days);
+    database
+        .create_invite(user.id, &code_hash, form.role, form.max_uses, expires_at)
+        .await?;
+    info!(
+        "{} created an invite with the {} role, usable {} time(s)",
+        claims.email, form.role, form.max_uses
+    );
+
+    render_invites(&database, Some(&code)).await
+}
+
+pub async fn revoke_invite(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<RevokeInvite>,
+) -> Result<Response<Body>, AppError> {
+    database.revoke_invite(form.id).await?;
+    info!("{} revoked invite {}", claims.email, form.id);
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/admin/invites")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+pub async fn set_invite_only(
+    State(database): State<Store>,
+    RequirePermission { claims, .. }: RequirePermission<ManageUsers>,
+    Form(form): Form<InviteOnlyForm>,
+) -> Result<Response<Body>, AppError> {
+    database
+        .set_setting(INVITE_ONLY, &form.enabled.to_string())
+        .await?;
+    info!("{} set {} to {}", claims.email, INVITE_ONLY, form.enabled);
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/admin/invites")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+async fn render_invites(database: &Store, new_code: Option<&str>) -> Result<Html<String>, AppError> {
+    let mut context = Context::new();
+    context.insert("invite_only", &invite_only(database).await?);
+    context.insert("invites", &database.list_invites().await?);
+    context.insert("redemptions", &database.list_invite_redemptions().await?);
+    context.insert("roles", &Role::ALL);
+    context.insert("new_code", &new_code);
+    render_template("admin_invites.html", &context)
+}
+
+pub async fn api_tokens_page(
+    State(database): State<Store>,
+    claims: Claims,
+) -> Result<Html<String>, AppError> {
+    claims.require_session()?;
+    render_api_tokens(&database, &claims, None).await
+}
+
+/// Creates a personal access token. The form is read as a list of pairs
+/// because every ticked scope checkbox sends its own `scope` field.
+pub async fn create_api_token(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> Result<Html<String>, AppError> {
+    claims.require_session()?;
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let name = field("name").ok_or(AppError::MissingCredentials)?;
+    let scopes: Vec<String> = fields
+        .iter()
+        .filter(|(key, value)| key == "scope" && API_TOKEN_SCOPES.contains(&value.as_str()))
+        .map(|(_, value)| value.to_string())
+        .collect();
+    if scopes.is_empty() {
+        return Err(AppError::MissingCredentials);
+    }
+    let expires_at = match field("expires_in_days") {
+        Some(days) => {
+            let days: i64 = days.parse().map_err(|_| AppError::MissingCredentials)?;
+            Some(Utc::now() + chrono::Duration::days(days))
+        }
+        None => None,
+    };
+
+    let user = database.get_user_by_id(claims.sub).await?;
+    let (secret, token_hash) = generate_token();
+    let token = format!("{}{}", API_TOKEN_PREFIX, secret);
+    database
+        .create_api_token(user.id, &name, &token_hash, &scopes, expires_at)
+        .await?;
+    info!("{} created personal access token {}", user.email, name);
+
+    render_api_tokens(&database, &claims, Some(&token)).await
+}
+
+pub async fn revoke_api_token(
+    State(database): State<Store>,
+    claims: Claims,
+    Form(form): Form<RevokeApiToken>,
+) -> Result<Response<Body>, AppError> {
+    claims.require_session()?;
+    let user = database.get_user_by_id(claims.sub).await?;
+    database.revoke_api_token(user.id, form.id).await?;
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, "/tokens")
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(response)
+}
+
+async fn render_api_tokens(
+    database: &Store,
+    claims: &Claims,
+    new_token: Option<&str>,
+) -> Result<Html<String>, AppError> {
+    let user = database.get_user_by_id(claims.sub).await?;
+
+    let mut context = Context::new();
+    context.insert("tokens", &database.list_api_tokens(user.id).await?);
+    context.insert("scopes", &API_TOKEN_SCOPES);
+    context.insert("new_token", &new_token);
+    render_template("api_tokens.html", &context)
+}
+
+fn render_template(template_name: &str, context: &Context) -> Result<Html<String>, AppError> {
+    TEMPLATES
+        .render(template_name, context)
+        .map(Html)
+        .map_err(|err| {
+            error!("Template rendering error: {}", err);
+            AppError::InternalServerError
+        })
 }
\ No newline at end of file
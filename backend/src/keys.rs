@@ -0,0 +1,284 @@
+//! The keys every JWT we issue is signed with.
+//!
+//! Keys live in the `signing_keys` table and are told apart by the `kid` in
+//! each token's header. One key signs new tokens; keys retired by a rotation
+//! keep verifying for [`SIGNING_KEY_GRACE_PERIOD`] so nobody is logged out.
+//! Each server keeps an in-memory [`KeyRing`] that it reloads every minute,
+//! which is how a rotation from `backend rotate-keys` reaches running servers.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::{EncodePrivateKey, EncodePublicKey};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::SIGNING_KEY_GRACE_PERIOD;
+
+/// Tokens signed before keys had ids are checked against the key imported
+/// from `JWT_SECRET` under this id.
+pub const LEGACY_KID: &str = "legacy";
+
+/// How often a running server picks up rotations made elsewhere.
+const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(sqlx::FromRow)]
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: String,
+    pub private_key: String,
+    pub public_key: String,
+    pub created_at: DateTime<Utc>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+struct VerifyingKey {
+    kid: String,
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+}
+
+pub struct KeyRing {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    verifying: Vec<VerifyingKey>,
+}
+
+static KEY_RING: Lazy<RwLock<Option<KeyRing>>> = Lazy::new(|| RwLock::new(None));
+
+/// The algorithms we can generate keys for, by their JWT names.
+pub fn parse_algorithm(name: &str) -> Option<Algorithm> {
+    match name {
+        "HS256" => Some(Algorithm::HS256),
+        "RS256" => Some(Algorithm::RS256),
+        "EdDSA" => Some(Algorithm::EdDSA),
+        _ => None,
+    }
+}
+
+fn algorithm_name(algorithm: Algorithm) -> &'static str {
+    match algorithm {
+        Algorithm::RS256 => "RS256",
+        Algorithm::EdDSA => "EdDSA",
+        _ => "HS256",
+    }
+}
+
+/// The algorithm for keys created without one being asked for, from `JWT_ALGORITHM`.
+pub fn default_algorithm() -> Algorithm {
+    std::env::var("JWT_ALGORITHM")
+        .ok()
+        .and_then(|name| parse_algorithm(&name))
+        .unwrap_or(Algorithm::EdDSA)
+}
+
+fn key_error(message: &str, err: impl std::fmt::Display) -> AppError {
+    error!("{}: {}", message, err);
+    AppError::InternalServerError
+}
+
+/// Signs and verifies with one fixed HS256 key, without a database. Every
+/// test installs the same key, so tests running at once can share the ring.
+#[cfg(test)]
+pub fn install_test_key() {
+    const TEST_KID: &str = "test";
+    const TEST_SECRET: &[u8] = b"only ever used by the tests";
+
+    let ring = KeyRing {
+        kid: TEST_KID.to_string(),
+        algorithm: Algorithm::HS256,
+        encoding: EncodingKey::from_secret(TEST_SECRET),
+        verifying: vec![VerifyingKey {
+            kid: TEST_KID.to_string(),
+            algorithm: Algorithm::HS256,
+            decoding: DecodingKey::from_secret(TEST_SECRET),
+        }],
+    };
+    *KEY_RING.write().unwrap() = Some(ring);
+}
+
+/// Makes a new key pair, stored as PEM, or a random secret for HS256.
+pub fn generate_key(algorithm: Algorithm) -> Result<SigningKey, AppError> {
+    let mut kid = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut kid);
+
+    let (private_key, public_key) = match algorithm {
+        Algorithm::HS256 => {
+            let mut secret = [0u8; 64];
+            rand::thread_rng().fill_bytes(&mut secret);
+            (hex::encode(secret), String::new())
+        }
+        Algorithm::EdDSA => {
+            let key = ed25519_dalek::SigningKey::generate(&mut OsRng);
+            let private_key = key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|err| key_error("Could not encode Ed25519 key", err))?;
+            let public_key = key
+                .verifying_key()
+                .to_public_key_pem(LineEnding::LF)
+                .map_err(|err| key_error("Could not encode Ed25519 key", err))?;
+            (private_key.to_string(), public_key)
+        }
+        Algorithm::RS256 => {
+            use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey};
+
+            let key = rsa::RsaPrivateKey::new(&mut OsRng, 2048)
+                .map_err(|err| key_error("Could not generate RSA key", err))?;
+            let private_key = key
+                .to_pkcs8_pem(rsa::pkcs8::LineEnding::LF)
+                .map_err(|err| key_error("Could not encode RSA key", err))?;
+            let public_key = key
+                .to_public_key()
+                .to_public_key_pem(rsa::pkcs8::LineEnding::LF)
+                .map_err(|err| key_error("Could not encode RSA key", err))?;
+            (private_key.to_string(), public_key)
+        }
+        other => return Err(key_error("Unsupported signing algorithm", format!("{:?}", other))),
+    };
+
+    Ok(SigningKey {
+        kid: hex::encode(kid),
+        algorithm: algorithm_name(algorithm).to_string(),
+        private_key,
+        public_key,
+        created_at: Utc::now(),
+        retired_at: None,
+    })
+}
+
+fn encoding_key(key: &SigningKey, algorithm: Algorithm) -> Result<EncodingKey, AppError> {
+    match algorithm {
+        Algorithm::HS256 => Ok(EncodingKey::from_secret(key.private_key.as_bytes())),
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(key.private_key.as_bytes())
+            .map_err(|err| key_error("Invalid Ed25519 private key", err)),
+        _ => EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+            .map_err(|err| key_error("Invalid RSA private key", err)),
+    }
+}
+
+fn decoding_key(key: &SigningKey, algorithm: Algorithm) -> Result<DecodingKey, AppError> {
+    match algorithm {
+        Algorithm::HS256 => Ok(DecodingKey::from_secret(key.private_key.as_bytes())),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(key.public_key.as_bytes())
+            .map_err(|err| key_error("Invalid Ed25519 public key", err)),
+        _ => DecodingKey::from_rsa_pem(key.public_key.as_bytes())
+            .map_err(|err| key_error("Invalid RSA public key", err)),
+    }
+}
+
+/// Reads the keys that are still allowed to verify tokens and swaps them in.
+/// A database with no current key gets one: the old `JWT_SECRET` if there is
+/// one, so tokens issued before keys were stored keep working, or a new key.
+pub async fn load_keys(store: &Store) -> Result<(), AppError> {
+    if !store.has_current_signing_key().await? {
+        let key = match std::env::var("JWT_SECRET") {
+            Ok(secret) if !secret.is_empty() => SigningKey {
+                kid: LEGACY_KID.to_string(),
+                algorithm: "HS256".to_string(),
+                private_key: secret,
+                public_key: String::new(),
+                created_at: Utc::now(),
+                retired_at: None,
+            },
+            _ => generate_key(default_algorithm())?,
+        };
+        info!("Creating signing key {} ({})", key.kid, key.algorithm);
+        store.add_signing_key(&key, SIGNING_KEY_GRACE_PERIOD).await?;
+    }
+
+    let keys = store.get_signing_keys(SIGNING_KEY_GRACE_PERIOD).await?;
+    let current = keys
+        .iter()
+        .find(|key| key.retired_at.is_none())
+        .ok_or_else(|| key_error("No current signing key", "every key is retired"))?;
+    let current_algorithm = parse_algorithm(&current.algorithm)
+        .ok_or_else(|| key_error("Unknown signing algorithm", &current.algorithm))?;
+
+    let mut verifying = Vec::new();
+    for key in &keys {
+        let Some(algorithm) = parse_algorithm(&key.algorithm) else {
+            error!("Skipping signing key {} with unknown algorithm {}", key.kid, key.algorithm);
+            continue;
+        };
+        verifying.push(VerifyingKey {
+            kid: key.kid.clone(),
+            algorithm,
+            decoding: decoding_key(key, algorithm)?,
+        });
+    }
+
+    let ring = KeyRing {
+        kid: current.kid.clone(),
+        algorithm: current_algorithm,
+        encoding: encoding_key(current, current_algorithm)?,
+        verifying,
+    };
+    *KEY_RING.write().unwrap() = Some(ring);
+
+    Ok(())
+}
+
+/// Makes a new key the signing key. The old one keeps verifying tokens for
+/// the grace period, and keys retired longer ago than that are deleted.
+pub async fn rotate_keys(store: &Store, algorithm: Algorithm) -> Result<String, AppError> {
+    let key = generate_key(algorithm)?;
+    store.add_signing_key(&key, SIGNING_KEY_GRACE_PERIOD).await?;
+    info!("Rotated to signing key {} ({})", key.kid, key.algorithm);
+
+    load_keys(store).await?;
+    Ok(key.kid)
+}
+
+/// Reloads the key ring in the background so rotations reach this server.
+pub fn spawn_key_refresh(store: Store) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(KEY_REFRESH_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(err) = load_keys(&store).await {
+                error!("Could not reload signing keys: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Signs `claims` with the current key, naming it in the `kid` header.
+pub fn sign<T: Serialize>(claims: &T) -> Result<String, AppError> {
+    let ring = KEY_RING.read().unwrap();
+    let ring = ring
+        .as_ref()
+        .ok_or_else(|| key_error("Signing keys not loaded", "call load_keys first"))?;
+
+    let mut header = Header::new(ring.algorithm);
+    header.kid = Some(ring.kid.clone());
+    jsonwebtoken::encode(&header, claims, &ring.encoding)
+        .map_err(|err| key_error("Could not sign token", err))
+}
+
+/// Checks a token against the key its `kid` names, with that key's algorithm
+/// only, and returns its claims.
+pub fn verify<T: DeserializeOwned>(token: &str) -> Result<T, AppError> {
+    let header = jsonwebtoken::decode_header(token).map_err(|_| AppError::InvalidToken)?;
+    let kid = header.kid.as_deref().unwrap_or(LEGACY_KID);
+
+    let ring = KEY_RING.read().unwrap();
+    let key = ring
+        .as_ref()
+        .and_then(|ring| ring.verifying.iter().find(|key| key.kid == kid))
+        .ok_or(AppError::InvalidToken)?;
+
+    jsonwebtoken::decode::<T>(token, &key.decoding, &Validation::new(key.algorithm))
+        .map(|data| data.claims)
+        .map_err(|_| AppError::InvalidToken)
+}
@@ -9,7 +9,11 @@ pub fn get_layers() -> (
 ) {
     let cors_layer = CorsLayer::new()
         .allow_origin(Any)
-        .allow_headers([http::header::CONTENT_TYPE])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            http::header::HeaderName::from_static(crate::csrf::CSRF_HEADER),
+        ])
         .allow_methods([
             Method::GET,
             Method::POST,
@@ -5,8 +5,9 @@ use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::db::new_pool;
+use crate::db::{new_pool, Store};
 use crate::error::AppError;
+use crate::models::roles::Role;
 use crate::routes::main_routes;
 use dotenvy::dotenv;
 use http::{Request, StatusCode, Uri};
@@ -18,13 +19,32 @@ use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+pub mod activitypub;
+pub mod archive;
+pub mod cookies;
+pub mod csrf;
 pub mod db;
 pub mod error;
+pub mod feeds;
 pub mod handlers;
+pub mod keys;
 pub mod layers;
+pub mod mail;
+pub mod markdown_import;
+pub mod meta;
+pub mod micropub;
 pub mod models;
+pub mod oidc;
+pub mod remote;
 pub mod routes;
+pub mod session;
+pub mod sitemap;
 pub mod template;
+#[cfg(test)]
+mod test_support;
+pub mod webauthn;
+pub mod webmention;
+pub mod wordpress;
 
 pub async fn run_backend() {
     dotenv().ok();
@@ -32,16 +52,168 @@ pub async fn run_backend() {
 
     let addr = get_host_from_env();
 
-    let app = main_routes::app(new_pool().await).await;
+    let pool = new_pool().await;
+    let store = Store::with_pool(pool.clone());
+    keys::load_keys(&store)
+        .await
+        .expect("Could not load the JWT signing keys");
+    keys::spawn_key_refresh(store.clone());
+    activitypub::spawn_delivery_worker(store, get_public_url());
+
+    let app = main_routes::app(pool).await;
 
     info!("Listening...");
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
+/// `backend rotate-keys [HS256|RS256|EdDSA]`: starts signing tokens with a new
+/// key. Running servers pick it up within a minute and keep accepting tokens
+/// signed with the old key for [`SIGNING_KEY_GRACE_PERIOD`].
+pub async fn run_rotate_keys(algorithm: Option<String>) {
+    dotenv().ok();
+    init_logging();
+
+    let algorithm = match algorithm {
+        Some(name) => keys::parse_algorithm(&name)
+            .unwrap_or_else(|| panic!("Unknown algorithm {}, use HS256, RS256 or EdDSA", name)),
+        None => keys::default_algorithm(),
+    };
+
+    let store = Store::with_pool(new_pool().await);
+    keys::load_keys(&store)
+        .await
+        .expect("Could not load the JWT signing keys");
+    let kid = keys::rotate_keys(&store, algorithm)
+        .await
+        .expect("Could not rotate the JWT signing keys");
+
+    println!("Now signing tokens with key {}", kid);
+}
+
+/// `backend make-admin you@example.com`: gives an existing account the admin
+/// role. Nobody can do that from the site on a fresh install, so the first
+/// admin is made here.
+pub async fn run_make_admin(email: Option<String>) {
+    dotenv().ok();
+    init_logging();
+
+    let email = email.expect("Usage: backend make-admin <email>");
+    let store = Store::with_pool(new_pool().await);
+    let user = store
+        .find_user(&email)
+        .await
+        .expect("Could not look up the account")
+        .unwrap_or_else(|| panic!("There is no account for {}, sign up first", email));
+    store
+        .set_user_role(user.id, Role::Admin)
+        .await
+        .expect("Could not make the account an admin");
+
+    println!("{} is now an admin", email);
+}
+
+/// `backend import-wordpress export.xml`: imports the authors, posts and
+/// comments from a WordPress export. Safe to run again with the same or a
+/// newer export.
+pub async fn run_import_wordpress(path: Option<String>) {
+    dotenv().ok();
+    init_logging();
+
+    let path = path.expect("Usage: backend import-wordpress <export.xml>");
+    let xml = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Could not read {}: {}", path, err));
+    let export = wordpress::parse(&xml).unwrap_or_else(|err| panic!("Could not import {}: {:?}", path, err));
+
+    let store = Store::with_pool(new_pool().await);
+    let report = wordpress::import(&store, &export)
+        .await
+        .unwrap_or_else(|err| panic!("Could not import {}: {:?}", path, err));
+
+    print!("{}", report);
+}
+
+/// `backend import-markdown <dir> [--author <handle or email>] [--apply]`:
+/// imports a directory of Markdown files with front matter. Without
+/// `--apply` it only prints what would change.
+pub async fn run_import_markdown(args: Vec<String>) {
+    dotenv().ok();
+    init_logging();
+
+    let usage = "Usage: backend import-markdown <dir> [--author <handle or email>] [--apply]";
+    let mut dir = None;
+    let mut author = None;
+    let mut apply = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--apply" => apply = true,
+            "--author" => author = Some(args.next().expect(usage)),
+            _ if dir.is_none() && !arg.starts_with("--") => dir = Some(arg),
+            _ => panic!("{}", usage),
+        }
+    }
+    let dir = dir.expect(usage);
+
+    let (posts, skipped) = markdown_import::read_dir(std::path::Path::new(&dir))
+        .unwrap_or_else(|err| panic!("Could not import {}: {:?}", dir, err));
+    let store = Store::with_pool(new_pool().await);
+    let plan = markdown_import::plan(&store, posts, author.as_deref(), skipped)
+        .await
+        .unwrap_or_else(|err| panic!("Could not import {}: {:?}", dir, err));
+
+    print!("{}", markdown_import::describe(&plan));
+    if !apply {
+        println!("Dry run, nothing was written. Run again with --apply to import.");
+        return;
+    }
+
+    let report = markdown_import::apply(&store, plan)
+        .await
+        .unwrap_or_else(|err| panic!("Could not import {}: {:?}", dir, err));
+    print!("{}", report);
+}
+
+/// `backend export [archive.zip]`: writes an export archive of the posts,
+/// users, comments and media.
+pub async fn run_export(path: Option<String>) {
+    dotenv().ok();
+    init_logging();
+
+    let path = path.unwrap_or_else(archive::file_name);
+    let file = std::fs::File::create(&path)
+        .unwrap_or_else(|err| panic!("Could not create {}: {}", path, err));
+
+    let store = Store::with_pool(new_pool().await);
+    archive::export(&store, file)
+        .await
+        .unwrap_or_else(|err| panic!("Could not export to {}: {:?}", path, err));
+
+    println!("Exported to {}", path);
+}
+
+/// `backend restore archive.zip`: loads an export archive into an empty
+/// database.
+pub async fn run_restore(path: Option<String>) {
+    dotenv().ok();
+    init_logging();
+
+    let path = path.expect("Usage: backend restore <archive.zip>");
+    let file = std::fs::File::open(&path)
+        .unwrap_or_else(|err| panic!("Could not read {}: {}", path, err));
+    let archive = archive::read(file).unwrap_or_else(|err| panic!("Could not restore {}: {:?}", path, err));
+
+    let store = Store::with_pool(new_pool().await);
+    let report = archive::restore(&store, archive)
+        .await
+        .unwrap_or_else(|err| panic!("Could not restore {}: {:?}", path, err));
+
+    print!("{}", report);
+}
+
 fn get_host_from_env() -> SocketAddr {
     let host = std::env::var("API_HOST").unwrap();
     let api_host = IpAddr::from_str(&host).unwrap();
@@ -53,6 +225,17 @@ fn get_host_from_env() -> SocketAddr {
     SocketAddr::from((api_host, api_port))
 }
 
+/// The externally reachable base URL, used to build links in emails.
+pub fn get_public_url() -> String {
+    std::env::var("PUBLIC_URL").unwrap_or_else(|_| {
+        format!(
+            "http://{}:{}",
+            std::env::var("API_HOST").unwrap_or_default(),
+            std::env::var("API_PORT").unwrap_or_default()
+        )
+    })
+}
+
 fn init_logging() {
     // https://github.com/tokio-rs/axum/blob/main/examples/tracing-aka-logging
     tracing_subscriber::registry()
@@ -65,14 +248,44 @@ fn init_logging() {
         .init();
 }
 
-pub fn get_timestamp_after_8_hours() -> u64 {
+/// How long an access token (the `jwt` cookie) is valid for.
+pub const ACCESS_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 15);
+
+/// How long a refresh token is valid for before the user has to log in again.
+pub const REFRESH_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// How long a refresh token that was just exchanged still gets its holder a
+/// new access token, for requests that were already on their way with it.
+pub const REFRESH_REUSE_GRACE: Duration = Duration::from_secs(30);
+
+/// How long a password reset link stays usable.
+pub const PASSWORD_RESET_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// How long an email verification link stays usable.
+pub const EMAIL_VERIFICATION_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Minimum time between two verification emails for the same account.
+pub const VERIFICATION_RESEND_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How long someone has to enter their second factor after their password.
+pub const SECOND_FACTOR_LIFETIME: Duration = Duration::from_secs(60 * 5);
+
+/// How long a passkey ceremony can take between its start and finish requests.
+pub const WEBAUTHN_CHALLENGE_LIFETIME: Duration = Duration::from_secs(60 * 5);
+
+/// How long someone has to finish signing in at the identity provider.
+pub const OIDC_FLOW_LIFETIME: Duration = Duration::from_secs(60 * 10);
+
+/// How long a rotated out signing key keeps verifying tokens. Longer than any
+/// token we sign lives, so a rotation never invalidates one early.
+pub const SIGNING_KEY_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60 * 48);
+
+pub fn get_timestamp_after(lifetime: Duration) -> u64 {
     let now = SystemTime::now();
     let since_epoch = now
         .duration_since(UNIX_EPOCH)
         .expect("Time somehow went backwards");
-    // 8 hours later
-    let eight_hours_from_now = since_epoch + Duration::from_secs(60 * 60 * 8);
-    eight_hours_from_now.as_secs()
+    (since_epoch + lifetime).as_secs()
 }
 
 // https://benw.is/posts/serving-static-files-with-axum
@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::async_trait;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Anything that can deliver an [`Email`]. The server picks an implementation
+/// from `MAIL_TRANSPORT` at startup so dev and tests never need a real SMTP server.
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, username: String, password: String, from: String) -> Self {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+            .expect("Invalid SMTP_HOST")
+            .credentials(Credentials::new(username, password))
+            .build();
+
+        Self { transport, from }
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let message = Message::builder()
+            .from(self.from.parse().map_err(|_| AppError::InternalServerError)?)
+            .to(email.to.parse().map_err(|_| AppError::InternalServerError)?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)
+            .map_err(|_| AppError::InternalServerError)?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| AppError::Any(anyhow::anyhow!("Sending mail failed: {}", err)))?;
+
+        Ok(())
+    }
+}
+
+/// Development stand-in that logs every message and, if a directory is given,
+/// also writes it to disk so tests can read the links back out.
+pub struct FileMailer {
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let rendered = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        info!("Outgoing mail:\n{}", rendered);
+
+        if let Some(dir) = &self.dir {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|err| AppError::Any(err.into()))?;
+            tokio::fs::write(dir.join(format!("{}.eml", Uuid::new_v4())), rendered)
+                .await
+                .map_err(|err| AppError::Any(err.into()))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn transport_from_env() -> Arc<dyn MailTransport> {
+    match std::env::var("MAIL_TRANSPORT").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::new(
+            &std::env::var("SMTP_HOST").expect("Missing SMTP_HOST"),
+            std::env::var("SMTP_USERNAME").expect("Missing SMTP_USERNAME"),
+            std::env::var("SMTP_PASSWORD").expect("Missing SMTP_PASSWORD"),
+            std::env::var("MAIL_FROM").expect("Missing MAIL_FROM"),
+        )),
+        _ => Arc::new(FileMailer::new(
+            std::env::var("MAIL_DIR").ok().map(PathBuf::from),
+        )),
+    }
+}
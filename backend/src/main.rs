@@ -1,6 +1,18 @@
-use backend::run_backend;
+use backend::{
+    run_backend, run_export, run_import_markdown, run_import_wordpress, run_make_admin, run_restore,
+    run_rotate_keys,
+};
 
 #[tokio::main]
 async fn main() {
-    run_backend().await;
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("rotate-keys") => run_rotate_keys(args.next()).await,
+        Some("make-admin") => run_make_admin(args.next()).await,
+        Some("import-wordpress") => run_import_wordpress(args.next()).await,
+        Some("import-markdown") => run_import_markdown(args.collect()).await,
+        Some("export") => run_export(args.next()).await,
+        Some("restore") => run_restore(args.next()).await,
+        _ => run_backend().await,
+    }
 }
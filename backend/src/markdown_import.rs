@@ -0,0 +1,466 @@
+//! Imports a directory of Markdown files with front matter, as kept for
+//! Jekyll, Hugo or Zola: YAML between `---` lines or TOML between `+++`
+//! lines, then the post.
+//!
+//! Each file is keyed by its front matter `slug`, or its path in the
+//! directory when it has none, so importing the directory again updates the
+//! posts that changed. Nothing is written until the plan has been shown:
+//! [`plan`] works out what would change and [`describe`] prints it as a
+//! diff, and only [`apply`] touches the database.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde_json::Value;
+use walkdir::WalkDir;
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::models::blog::{normalize_tags, Blog, MAX_TITLE_LENGTH};
+use crate::models::imports::{ImportOutcome, ImportReport};
+
+/// Past this many lines compared, a changed post is only summarised.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// A post read from one file.
+pub struct MarkdownPost {
+    pub key: String,
+    /// Where the file is, relative to the imported directory.
+    pub path: String,
+    pub title: String,
+    pub content: String,
+    pub tags: Vec<String>,
+    pub published_at: DateTime<Utc>,
+    /// The front matter `author`, a handle or an email address.
+    pub author: Option<String>,
+}
+
+/// A post with what the database has for it now, if anything.
+pub struct PlannedPost {
+    pub key: String,
+    pub path: String,
+    pub blog: Blog,
+    pub existing: Option<Blog>,
+}
+
+impl PlannedPost {
+    pub fn is_unchanged(&self) -> bool {
+        self.existing.as_ref().is_some_and(|existing| {
+            existing.title == self.blog.title
+                && existing.content == self.blog.content
+                && existing.tags == self.blog.tags
+                && existing.published_at == self.blog.published_at
+                && existing.author_id == self.blog.author_id
+        })
+    }
+}
+
+pub struct Plan {
+    pub posts: Vec<PlannedPost>,
+    /// Why each file that won't be imported was left out.
+    pub skipped: Vec<String>,
+}
+
+fn import_key(key: &str) -> String {
+    format!("md:{}", key)
+}
+
+/// Reads every `.md` and `.markdown` file under `dir`. Hidden directories
+/// and files starting with `_`, such as Hugo's `_index.md`, are passed over.
+pub fn read_dir(dir: &Path) -> Result<(Vec<MarkdownPost>, Vec<String>), AppError> {
+    let mut posts = Vec::new();
+    let mut skipped = Vec::new();
+
+    let entries = WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            entry.depth() == 0 || !(name.starts_with('.') || name.starts_with('_'))
+        });
+    for entry in entries {
+        let entry = entry.map_err(|err| AppError::invalid_import(err.to_string()))?;
+        let is_markdown = entry
+            .path()
+            .extension()
+            .is_some_and(|extension| extension == "md" || extension == "markdown");
+        if !entry.file_type().is_file() || !is_markdown {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let text = std::fs::read_to_string(entry.path())
+            .map_err(|err| AppError::invalid_import(format!("Could not read {}: {}", relative, err)))?;
+
+        match parse_file(&relative, &text) {
+            Ok(Some(post)) => posts.push(post),
+            Ok(None) => skipped.push(format!("{} is a draft", relative)),
+            Err(reason) => skipped.push(format!("{} {}", relative, reason)),
+        }
+    }
+
+    Ok((posts, skipped))
+}
+
+/// Splits a file into its front matter, as JSON whatever it was written in,
+/// and the text after it.
+fn split_front_matter(text: &str) -> Result<(Value, &str), String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let (fence, is_toml) = if text.starts_with("---") {
+        ("---", false)
+    } else if text.starts_with("+++") {
+        ("+++", true)
+    } else {
+        return Err("has no front matter".to_string());
+    };
+
+    let after_fence = text[fence.len()..]
+        .strip_prefix("\r\n")
+        .or_else(|| text[fence.len()..].strip_prefix('\n'))
+        .ok_or("has no front matter")?;
+    let mut offset = 0;
+    for line in after_fence.split_inclusive('\n') {
+        if line.trim_end() == fence {
+            let front_matter = &after_fence[..offset];
+            let body = &after_fence[offset + line.len()..];
+            let front_matter = if is_toml {
+                toml::from_str::<toml::Table>(front_matter)
+                    .map(|table| toml_to_json(toml::Value::Table(table)))
+                    .map_err(|err| format!("has front matter that isn't valid TOML: {}", err))?
+            } else if front_matter.trim().is_empty() {
+                Value::Object(Default::default())
+            } else {
+                serde_yaml::from_str::<Value>(front_matter)
+                    .map_err(|err| format!("has front matter that isn't valid YAML: {}", err))?
+            };
+            return Ok((front_matter, body));
+        }
+        offset += line.len();
+    }
+
+    Err(format!("never closes its front matter with {}", fence))
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(text) => Value::String(text),
+        toml::Value::Integer(number) => Value::from(number),
+        toml::Value::Float(number) => Value::from(number),
+        toml::Value::Boolean(flag) => Value::Bool(flag),
+        toml::Value::Datetime(date) => Value::String(date.to_string()),
+        toml::Value::Array(values) => Value::Array(values.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Front matter dates come in many shapes: RFC 3339, Jekyll's
+/// `2020-01-31 12:00:00 +0100`, or just a day.
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z"))
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+                .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S"))
+                .ok()
+                .map(|date| date.and_utc())
+        })
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+                .map(|date| date.and_utc())
+        })
+}
+
+/// Strings, or lists of them, under any of `names`.
+fn strings(front_matter: &Value, names: &[&str]) -> Vec<String> {
+    names
+        .iter()
+        .filter_map(|name| front_matter.pointer(name))
+        .flat_map(|value| match value {
+            Value::String(text) => vec![text.clone()],
+            Value::Array(values) => values
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// A post from one file, or `None` for drafts.
+pub fn parse_file(path: &str, text: &str) -> Result<Option<MarkdownPost>, String> {
+    let (front_matter, body) = split_front_matter(text)?;
+    if !front_matter.is_object() {
+        return Err("has front matter that isn't a map".to_string());
+    }
+
+    let is_draft = front_matter.get("draft").and_then(Value::as_bool) == Some(true)
+        || front_matter.get("published").and_then(Value::as_bool) == Some(false);
+    if is_draft {
+        return Ok(None);
+    }
+
+    let file_stem = path
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+        .trim_end_matches(".markdown")
+        .trim_end_matches(".md");
+    // Jekyll puts the date in the file name: 2020-01-31-title.md.
+    let name_date = file_stem.get(..10).and_then(parse_date);
+    let published_at = strings(&front_matter, &["/date", "/publishDate", "/pubDate"])
+        .first()
+        .and_then(|date| parse_date(date))
+        .or(name_date)
+        .ok_or("has no date in its front matter or file name")?;
+
+    let title = front_matter
+        .get("title")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(file_stem);
+    let tags = strings(
+        &front_matter,
+        &["/tags", "/categories", "/taxonomies/tags", "/taxonomies/categories"],
+    );
+    let key = front_matter
+        .get("slug")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|slug| !slug.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            path.trim_end_matches(".markdown")
+                .trim_end_matches(".md")
+                .to_string()
+        });
+
+    // The markdown renderer splits lines on \r\n, like the form sends them.
+    let content = body
+        .replace("\r\n", "\n")
+        .trim()
+        .replace('\n', "\r\n");
+    if content.is_empty() {
+        return Err("has no text".to_string());
+    }
+
+    Ok(Some(MarkdownPost {
+        key,
+        path: path.to_string(),
+        title: title.chars().take(MAX_TITLE_LENGTH).collect(),
+        content,
+        tags: normalize_tags(tags.iter().map(String::as_str)),
+        published_at,
+        author: front_matter
+            .get("author")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    }))
+}
+
+/// The user an `author` names, by handle or email address.
+async fn find_author(store: &Store, author: &str) -> Result<Option<i32>, AppError> {
+    let author = author.trim();
+    if let Some(profile) = store.get_profile_by_handle(&author.to_lowercase()).await? {
+        return Ok(Some(profile.user_id));
+    }
+
+    Ok(store.find_user(author).await?.map(|user| user.id))
+}
+
+/// Works out what importing `posts` would do, without changing anything.
+/// Posts whose front matter names no author are written by
+/// `default_author`, a handle or email address.
+pub async fn plan(
+    store: &Store,
+    posts: Vec<MarkdownPost>,
+    default_author: Option<&str>,
+    mut skipped: Vec<String>,
+) -> Result<Plan, AppError> {
+    let mut authors: HashMap<String, Option<i32>> = HashMap::new();
+    let mut keys: HashMap<String, String> = HashMap::new();
+    let mut planned = Vec::new();
+
+    for post in posts {
+        if let Some(first) = keys.get(&post.key) {
+            skipped.push(format!("{} has the same slug as {}", post.path, first));
+            continue;
+        }
+        keys.insert(post.key.clone(), post.path.clone());
+
+        let Some(author) = post.author.as_deref().or(default_author) else {
+            skipped.push(format!("{} has no author, pass --author", post.path));
+            continue;
+        };
+        let author_id = match authors.get(author) {
+            Some(author_id) => *author_id,
+            None => {
+                let author_id = find_author(store, author).await?;
+                authors.insert(author.to_string(), author_id);
+                author_id
+            }
+        };
+        let Some(author_id) = author_id else {
+            skipped.push(format!("{} is by {}, who has no account", post.path, author));
+            continue;
+        };
+
+        let mut blog = Blog::new(
+            post.title,
+            author_id,
+            post.content,
+            post.published_at.format("%Y-%m-%d").to_string(),
+        );
+        blog.tags = post.tags;
+        blog.published_at = post.published_at;
+
+        planned.push(PlannedPost {
+            existing: store.get_blog_by_import_key(&import_key(&post.key)).await?,
+            key: post.key,
+            path: post.path,
+            blog,
+        });
+    }
+
+    Ok(Plan {
+        posts: planned,
+        skipped,
+    })
+}
+
+/// The lines that differ between `old` and `new`, marked `-` and `+`.
+fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.split("\r\n").collect();
+    let new: Vec<&str> = new.split("\r\n").collect();
+    if old.len() * new.len() > MAX_DIFF_CELLS {
+        return vec![format!("({} lines -> {} lines)", old.len(), new.len())];
+    }
+
+    // Longest common subsequence, filled from the end so it can be walked
+    // forwards.
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        } else {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        }
+    }
+
+    lines
+}
+
+/// The plan as a diff: `+` for new posts, `~` with what changed for
+/// updated ones, and a count of unchanged ones.
+pub fn describe(plan: &Plan) -> String {
+    let mut out = String::new();
+    let mut unchanged = 0;
+
+    for post in &plan.posts {
+        let blog = &post.blog;
+        match &post.existing {
+            None => out.push_str(&format!(
+                "+ {} \"{}\" ({}, tags: {})\n",
+                post.path,
+                blog.title,
+                blog.published_at.format("%Y-%m-%d"),
+                blog.tags.join(", ")
+            )),
+            Some(_) if post.is_unchanged() => unchanged += 1,
+            Some(existing) => {
+                out.push_str(&format!("~ {} \"{}\"\n", post.path, blog.title));
+                if existing.title != blog.title {
+                    out.push_str(&format!("    title: \"{}\" -> \"{}\"\n", existing.title, blog.title));
+                }
+                if existing.author_id != blog.author_id {
+                    out.push_str(&format!(
+                        "    author: user {} -> user {}\n",
+                        existing.author_id, blog.author_id
+                    ));
+                }
+                if existing.published_at != blog.published_at {
+                    out.push_str(&format!(
+                        "    published: {} -> {}\n",
+                        existing.published_at.to_rfc3339(),
+                        blog.published_at.to_rfc3339()
+                    ));
+                }
+                if existing.tags != blog.tags {
+                    out.push_str(&format!(
+                        "    tags: [{}] -> [{}]\n",
+                        existing.tags.join(", "),
+                        blog.tags.join(", ")
+                    ));
+                }
+                if existing.content != blog.content {
+                    out.push_str("    content:\n");
+                    for line in line_diff(&existing.content, &blog.content) {
+                        out.push_str(&format!("      {}\n", line));
+                    }
+                }
+            }
+        }
+    }
+
+    out.push_str(&format!("= {} unchanged\n", unchanged));
+    for reason in &plan.skipped {
+        out.push_str(&format!("! Skipped: {}\n", reason));
+    }
+
+    out
+}
+
+/// Writes the plan. Unchanged posts aren't touched.
+pub async fn apply(store: &Store, plan: Plan) -> Result<ImportReport, AppError> {
+    let mut report = ImportReport {
+        skipped: plan.skipped,
+        ..Default::default()
+    };
+
+    for post in plan.posts {
+        if post.is_unchanged() {
+            report.count_post(ImportOutcome::Unchanged);
+            continue;
+        }
+        let (_, outcome) = store
+            .upsert_imported_blog(&import_key(&post.key), &post.blog)
+            .await?;
+        report.count_post(outcome);
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,84 @@
+//! What a post page tells link previews and search engines about itself:
+//! OpenGraph and Twitter Card tags plus schema.org `BlogPosting` JSON-LD,
+//! rendered by `post_meta.html`.
+
+use chrono::SecondsFormat;
+use serde_derive::Serialize;
+use serde_json::json;
+
+use crate::feeds::{author_url, post_url, summary, SITE_TITLE};
+use crate::models::blog::Blog;
+use crate::models::profiles::Profile;
+
+#[derive(Serialize)]
+pub struct PostMeta {
+    pub title: String,
+    pub description: String,
+    pub url: String,
+    pub image: Option<String>,
+    pub author_name: String,
+    pub author_url: String,
+    pub published_time: String,
+    pub modified_time: String,
+    pub tags: Vec<String>,
+    pub site_name: &'static str,
+    /// `summary_large_image` when there is an image to show, else `summary`.
+    pub twitter_card: &'static str,
+    /// Ready to go inside a `<script>` tag.
+    pub json_ld: String,
+}
+
+impl PostMeta {
+    /// The preview image is the author's avatar, or `OG_DEFAULT_IMAGE` for
+    /// authors without one.
+    pub fn new(blog: &Blog, author: &Profile) -> Self {
+        let url = post_url(blog);
+        let author_url = author_url(&author.handle);
+        let description = summary(&blog.content);
+        let image = author
+            .avatar_url
+            .clone()
+            .or_else(|| std::env::var("OG_DEFAULT_IMAGE").ok())
+            .filter(|image| image.starts_with("https://") || image.starts_with("http://"));
+        let published_time = blog.published_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let modified_time = blog.updated_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        let json_ld = json!({
+            "@context": "https://schema.org",
+            "@type": "BlogPosting",
+            "headline": blog.title,
+            "description": description,
+            "url": url,
+            "mainEntityOfPage": url,
+            "datePublished": published_time,
+            "dateModified": modified_time,
+            "image": image,
+            "keywords": blog.tags,
+            "author": {
+                "@type": "Person",
+                "name": author.display_name,
+                "url": author_url,
+            },
+            "publisher": {
+                "@type": "Organization",
+                "name": SITE_TITLE,
+            },
+        });
+
+        PostMeta {
+            title: blog.title.clone(),
+            description,
+            twitter_card: if image.is_some() { "summary_large_image" } else { "summary" },
+            url,
+            image,
+            author_name: author.display_name.clone(),
+            author_url,
+            published_time,
+            modified_time,
+            tags: blog.tags.clone(),
+            site_name: SITE_TITLE,
+            // A title containing "</script>" must not end the script early.
+            json_ld: json_ld.to_string().replace("</", "<\\/"),
+        }
+    }
+}
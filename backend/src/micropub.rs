@@ -0,0 +1,447 @@
+//! [Micropub](https://www.w3.org/TR/micropub/): writing, editing and
+//! deleting posts from external editors.
+//!
+//! Clients authenticate with a personal access token (or an access token
+//! JWT), sent as `Authorization: Bearer` or in an `access_token` field, and
+//! never with cookies, so the endpoints can skip CSRF checks. Posts are
+//! h-entries: `name` is the title, `content` the text, `category` the tags
+//! and `photo` images added to the end of the text. Files go to the media
+//! endpoint first and are saved under `MEDIA_DIR`.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde_json::{json, Map, Value};
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::feeds::{plain_text, post_url, shorten};
+use crate::get_public_url;
+use crate::models::blog::{normalize_tags, Blog, MAX_TITLE_LENGTH};
+use crate::models::users::{bearer_token, token_claims, Claims};
+
+/// The biggest file the media endpoint takes.
+pub const MAX_MEDIA_BYTES: usize = 10 * 1024 * 1024;
+
+/// Posts without a `name` are titled with the start of their text, this long.
+const NOTE_TITLE_LENGTH: usize = 60;
+
+/// An h-entry's properties, each a list of values as in microformats2 JSON.
+pub type Properties = Map<String, Value>;
+
+pub enum MicropubRequest {
+    Create(Properties),
+    Update {
+        url: String,
+        replace: Properties,
+        add: Properties,
+        /// Either a list of property names or values to take out of them.
+        delete: Value,
+    },
+    Delete {
+        url: String,
+    },
+}
+
+/// A `GET /micropub` query. `properties[]` can be repeated, which `Query`
+/// can't deserialize.
+#[derive(Default)]
+pub struct MicropubQuery {
+    pub q: String,
+    pub url: Option<String>,
+    pub properties: Vec<String>,
+}
+
+/// The post fields a new h-entry maps to.
+pub struct Entry {
+    pub title: String,
+    pub content: String,
+    pub tags: Vec<String>,
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+pub fn media_endpoint_url() -> String {
+    format!("{}/micropub/media", get_public_url())
+}
+
+/// Where uploaded media is kept, `MEDIA_DIR` or `./media`.
+pub fn media_dir() -> PathBuf {
+    std::env::var("MEDIA_DIR")
+        .unwrap_or_else(|_| "./media".to_string())
+        .into()
+}
+
+/// Works out who is posting from the bearer token in the `Authorization`
+/// header or, failing that, the `access_token` field of the body.
+pub async fn authenticate(
+    store: &Store,
+    headers: &HeaderMap,
+    body_token: Option<String>,
+) -> Result<Claims, AppError> {
+    let token = bearer_token(headers)
+        .or(body_token)
+        .ok_or(AppError::InvalidToken)?;
+
+    token_claims(store, &token).await
+}
+
+pub fn parse_query(raw: &str) -> MicropubQuery {
+    let mut query = MicropubQuery::default();
+    for (key, value) in url::form_urlencoded::parse(raw.as_bytes()) {
+        match key.as_ref() {
+            "q" => query.q = value.into_owned(),
+            "url" => query.url = Some(value.into_owned()),
+            "properties" | "properties[]" => query.properties.push(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    query
+}
+
+/// A form-encoded request, and the `access_token` it carried if any. Only
+/// creating and deleting can be done with a form; updates need JSON.
+pub fn parse_form(body: &[u8]) -> Result<(MicropubRequest, Option<String>), AppError> {
+    let mut properties = Properties::new();
+    let mut access_token = None;
+    let mut action = None;
+    let mut url = None;
+    let mut h = None;
+
+    for (key, value) in url::form_urlencoded::parse(body) {
+        let key = key.trim_end_matches("[]");
+        match key {
+            "access_token" => access_token = Some(value.into_owned()),
+            "action" => action = Some(value.into_owned()),
+            "url" => url = Some(value.into_owned()),
+            "h" => h = Some(value.into_owned()),
+            // Server commands like mp-slug aren't supported and are ignored.
+            _ if key.starts_with("mp-") => {}
+            _ => {
+                let values = properties
+                    .entry(key.to_string())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(values) = values {
+                    values.push(Value::String(value.into_owned()));
+                }
+            }
+        }
+    }
+
+    let request = match action.as_deref() {
+        None => {
+            if h.as_deref().is_some_and(|h| h != "entry") {
+                return Err(AppError::invalid_micropub("only h=entry posts can be created"));
+            }
+            MicropubRequest::Create(properties)
+        }
+        Some("delete") => MicropubRequest::Delete {
+            url: url.ok_or_else(|| AppError::invalid_micropub("delete needs a url"))?,
+        },
+        Some("update") => return Err(AppError::invalid_micropub("updates have to be sent as JSON")),
+        Some(_) => return Err(AppError::invalid_micropub("unsupported action")),
+    };
+
+    Ok((request, access_token))
+}
+
+pub fn parse_json(body: &[u8]) -> Result<MicropubRequest, AppError> {
+    let request: Value =
+        serde_json::from_slice(body).map_err(|_| AppError::invalid_micropub("the body isn't valid JSON"))?;
+    let url = || {
+        request
+            .get("url")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| AppError::invalid_micropub("the action needs a url"))
+    };
+    let object = |name: &str| match request.get(name) {
+        None => Ok(Properties::new()),
+        Some(Value::Object(properties)) => Ok(properties.clone()),
+        Some(_) => Err(AppError::invalid_micropub("replace and add have to be objects")),
+    };
+
+    match request.get("action").and_then(Value::as_str) {
+        None => {
+            let is_entry = request
+                .get("type")
+                .and_then(Value::as_array)
+                .is_some_and(|types| types.iter().any(|kind| kind == "h-entry"));
+            if !is_entry {
+                return Err(AppError::invalid_micropub("only h-entry posts can be created"));
+            }
+            match request.get("properties") {
+                Some(Value::Object(properties)) => Ok(MicropubRequest::Create(properties.clone())),
+                _ => Err(AppError::invalid_micropub("the post has no properties")),
+            }
+        }
+        Some("update") => Ok(MicropubRequest::Update {
+            url: url()?,
+            replace: object("replace")?,
+            add: object("add")?,
+            delete: request.get("delete").cloned().unwrap_or(Value::Null),
+        }),
+        Some("delete") => Ok(MicropubRequest::Delete { url: url()? }),
+        Some(_) => Err(AppError::invalid_micropub("unsupported action")),
+    }
+}
+
+/// The id of the post a Micropub `url` points at, which has to be one of ours.
+pub fn post_id(url: &str) -> Result<i32, AppError> {
+    url.strip_prefix(&format!("{}/posts/", get_public_url()))
+        .and_then(|id| id.trim_end_matches('/').parse::<i32>().ok())
+        .ok_or_else(|| AppError::invalid_micropub("url isn't a post on this site"))
+}
+
+/// A property's values. Microformats2 always uses lists, but a single value
+/// is taken as a list of one.
+fn values(properties: &Properties, name: &str) -> Vec<Value> {
+    match properties.get(name) {
+        Some(Value::Array(values)) => values.clone(),
+        Some(value) => vec![value.clone()],
+        None => Vec::new(),
+    }
+}
+
+/// A plain value, or the `value` of an object like `{"value": .., "alt": ..}`.
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Object(object) => object.get("value").and_then(Value::as_str).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Content as the post's source text. HTML content is kept as it is, like
+/// HTML typed into the form; plain text gets the `\r\n` line endings the
+/// markdown renderer splits on.
+fn content(value: &Value) -> Option<String> {
+    if let Some(html) = value.get("html").and_then(Value::as_str) {
+        return Some(html.to_string());
+    }
+
+    text(value).map(|text| text.replace("\r\n", "\n").replace('\n', "\r\n"))
+}
+
+/// An `<img>` line for each `photo` with an http(s) URL.
+fn photos(values: &[Value]) -> Vec<String> {
+    values
+        .iter()
+        .filter_map(|photo| {
+            let src = text(photo)?;
+            if !src.starts_with("https://") && !src.starts_with("http://") {
+                return None;
+            }
+            let alt = photo.get("alt").and_then(Value::as_str).unwrap_or_default();
+            Some(format!(
+                r#"<img src="{}" alt="{}">"#,
+                html_escape::encode_double_quoted_attribute(&src),
+                html_escape::encode_double_quoted_attribute(alt)
+            ))
+        })
+        .collect()
+}
+
+fn title(value: &Value) -> Result<String, AppError> {
+    let title = text(value).unwrap_or_default().trim().to_string();
+    if title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(AppError::invalid_micropub("name is too long"));
+    }
+
+    Ok(title)
+}
+
+/// Notes have no name, so they are titled with the start of their text.
+fn note_title(content: &str) -> String {
+    let title = shorten(&plain_text(content), NOTE_TITLE_LENGTH);
+    if title.is_empty() {
+        "Untitled note".to_string()
+    } else {
+        title
+    }
+}
+
+fn published(value: &Value) -> Result<DateTime<Utc>, AppError> {
+    text(value)
+        .and_then(|published| DateTime::parse_from_rfc3339(&published).ok())
+        .map(|published| published.with_timezone(&Utc))
+        .ok_or_else(|| AppError::invalid_micropub("published has to be an RFC 3339 date"))
+}
+
+fn tags(values: &[Value]) -> Vec<String> {
+    let tags: Vec<String> = values.iter().filter_map(text).collect();
+    normalize_tags(tags.iter().map(String::as_str))
+}
+
+/// Maps a new h-entry's properties onto a post.
+pub fn new_entry(properties: &Properties) -> Result<Entry, AppError> {
+    let mut content = values(properties, "content")
+        .first()
+        .and_then(content)
+        .unwrap_or_default();
+    for photo in photos(&values(properties, "photo")) {
+        if !content.is_empty() {
+            content.push_str("\r\n");
+        }
+        content.push_str(&photo);
+    }
+    if content.trim().is_empty() {
+        return Err(AppError::invalid_micropub("a post needs content"));
+    }
+
+    let title = match values(properties, "name").first() {
+        Some(name) => title(name)?,
+        None => String::new(),
+    };
+    let published_at = match values(properties, "published").first() {
+        Some(value) => Some(published(value)?),
+        None => None,
+    };
+
+    Ok(Entry {
+        title: if title.is_empty() { note_title(&content) } else { title },
+        tags: tags(&values(properties, "category")),
+        published_at,
+        content,
+    })
+}
+
+/// Applies an update's `replace`, `add` and `delete` to a post's source, in
+/// that order as the spec asks. Properties posts don't have are ignored.
+pub fn apply_update(
+    blog: &mut Blog,
+    replace: &Properties,
+    add: &Properties,
+    delete: &Value,
+) -> Result<(), AppError> {
+    for name in replace.keys() {
+        let values = values(replace, name);
+        match name.as_str() {
+            "name" => {
+                let title = values.first().map(title).transpose()?.unwrap_or_default();
+                blog.title = if title.is_empty() { note_title(&blog.content) } else { title };
+            }
+            "content" => {
+                blog.content = values.first().and_then(content).unwrap_or_default();
+            }
+            "category" => blog.tags = tags(&values),
+            "published" => {
+                if let Some(value) = values.first() {
+                    blog.published_at = published(value)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for name in add.keys() {
+        let values = values(add, name);
+        match name.as_str() {
+            "category" => {
+                let mut tags = blog.tags.clone();
+                tags.extend(values.iter().filter_map(text));
+                blog.tags = normalize_tags(tags.iter().map(String::as_str));
+            }
+            "photo" => {
+                for photo in photos(&values) {
+                    blog.content.push_str("\r\n");
+                    blog.content.push_str(&photo);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match delete {
+        Value::Array(names) => {
+            for name in names.iter().filter_map(Value::as_str) {
+                match name {
+                    "category" => blog.tags.clear(),
+                    "name" => blog.title = note_title(&blog.content),
+                    _ => {}
+                }
+            }
+        }
+        Value::Object(properties) => {
+            if let Some(Value::Array(removed)) = properties.get("category") {
+                let removed = tags(removed);
+                blog.tags.retain(|tag| !removed.contains(tag));
+            }
+        }
+        _ => {}
+    }
+
+    if blog.content.trim().is_empty() {
+        return Err(AppError::invalid_micropub("a post needs content"));
+    }
+
+    Ok(())
+}
+
+/// `q=config`: where media goes and what can be posted.
+pub fn config() -> Value {
+    json!({
+        "media-endpoint": media_endpoint_url(),
+        "syndicate-to": [],
+        "post-types": [
+            {"type": "note", "name": "Note"},
+            {"type": "article", "name": "Article"},
+            {"type": "photo", "name": "Photo"},
+        ],
+    })
+}
+
+/// `q=source`: a post's properties as it was written, all of them or only
+/// those asked for.
+pub fn source(blog: &Blog, wanted: &[String]) -> Value {
+    let mut properties = Properties::new();
+    properties.insert("name".to_string(), json!([blog.title]));
+    properties.insert("content".to_string(), json!([blog.content]));
+    properties.insert("category".to_string(), json!(blog.tags));
+    properties.insert("published".to_string(), json!([blog.published_at.to_rfc3339()]));
+    properties.insert("url".to_string(), json!([post_url(blog)]));
+
+    if wanted.is_empty() {
+        json!({"type": ["h-entry"], "properties": properties})
+    } else {
+        properties.retain(|name, _| wanted.contains(name));
+        json!({"properties": properties})
+    }
+}
+
+/// The file extension we save an upload of `content_type` under. Only images,
+/// audio and video are taken.
+fn media_extension(content_type: &str) -> Option<&'static str> {
+    let extension = match content_type.split(';').next()?.trim() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        _ => return None,
+    };
+
+    Some(extension)
+}
+
+/// Saves an upload under a random name and returns its public URL.
+pub async fn save_media(content_type: &str, bytes: &[u8]) -> Result<String, AppError> {
+    let extension =
+        media_extension(content_type).ok_or_else(|| AppError::invalid_micropub("that type of file isn't supported"))?;
+    let name = format!("{}.{}", uuid::Uuid::new_v4().simple(), extension);
+
+    let dir = media_dir();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|err| AppError::Any(err.into()))?;
+    tokio::fs::write(dir.join(&name), bytes)
+        .await
+        .map_err(|err| AppError::Any(err.into()))?;
+
+    Ok(format!("{}/media/{}", get_public_url(), name))
+}
@@ -0,0 +1,26 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+/// Every personal access token starts with this so they are easy to tell
+/// apart from session JWTs and to spot if one gets pasted somewhere public.
+pub const API_TOKEN_PREFIX: &str = "rbt_";
+
+/// Scopes a personal access token can be limited to. Each one matches the
+/// `SCOPE` of a [`Permission`](crate::models::roles::Permission).
+pub const API_TOKEN_SCOPES: [&str; 4] = ["posts:write", "posts:edit", "profile", "admin"];
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApiToken {
+    pub id: i32,
+}
@@ -0,0 +1,61 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::roles::Role;
+
+/// `manifest.json` in an export archive: everything besides the posts'
+/// text and the media files, which sit next to it in the zip.
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub users: Vec<ArchiveUser>,
+    pub tags: Vec<ArchiveTag>,
+    pub posts: Vec<ArchivePost>,
+    pub comments: Vec<ArchiveComment>,
+}
+
+/// An account and its profile. Password hashes, second factors and keys
+/// stay behind, so restored users set a new password with "forgot password".
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ArchiveUser {
+    pub id: i32,
+    pub email: String,
+    pub role: Role,
+    pub email_verified: bool,
+    pub handle: String,
+    pub display_name: String,
+    pub bio: String,
+    pub website: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveTag {
+    pub name: String,
+    pub posts: usize,
+}
+
+/// Where a post's Markdown file is, with what its front matter can't hold.
+#[derive(Serialize, Deserialize)]
+pub struct ArchivePost {
+    pub id: i32,
+    pub file: String,
+    pub author_id: i32,
+    pub publish_date: String,
+    pub updated_at: DateTime<Utc>,
+    /// The key it was imported from WordPress or Markdown under, so those
+    /// imports still recognise it after a restore.
+    pub import_key: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ArchiveComment {
+    pub post_id: i32,
+    pub author_name: String,
+    pub author_email: Option<String>,
+    pub author_url: Option<String>,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    pub import_key: Option<String>,
+}
@@ -1,21 +1,90 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 
+/// What the `blog.title` column holds, in characters.
+pub const MAX_TITLE_LENGTH: usize = 255;
+pub const MAX_TAGS: usize = 10;
+pub const MAX_TAG_LENGTH: usize = 40;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Blog {
+    #[serde(default)]
+    pub id: i32,
     pub title: String,
-    pub email: String,
+    #[serde(default)]
+    pub author_id: i32,
     pub content: String,
     pub publish_date: String,
+    #[serde(default = "Utc::now")]
+    pub published_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+    /// Sent by the form as one comma separated field.
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub author_name: String,
+    #[serde(default)]
+    pub author_handle: String,
 }
 
 impl Blog {
   #[allow(dead_code)]
-  pub fn new(title: String, email: String, content: String, publish_date: String) -> Self {
+  pub fn new(title: String, author_id: i32, content: String, publish_date: String) -> Self {
     Blog {
+      id: 0,
       title,
-      email,
+      author_id,
       content,
-      publish_date
+      publish_date,
+      published_at: Utc::now(),
+      updated_at: Utc::now(),
+      tags: Vec::new(),
+      author_name: String::new(),
+      author_handle: String::new(),
     }
   }
 }
+
+/// Tidies a tag into the form used in `/tags/:tag` URLs: lowercase, with runs
+/// of anything but letters and digits turned into single dashes.
+pub fn normalize_tag(tag: &str) -> String {
+    let mut slug = String::new();
+    for ch in tag.trim().to_lowercase().chars() {
+        if ch.is_alphanumeric() {
+            slug.push(ch);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.trim_end_matches('-').chars().take(MAX_TAG_LENGTH).collect()
+}
+
+/// Normalizes tags, dropping empty ones and duplicates and keeping at most
+/// [`MAX_TAGS`].
+pub fn normalize_tags<'a>(tags: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags.into_iter().map(normalize_tag) {
+        if !tag.is_empty() && !normalized.contains(&tag) && normalized.len() < MAX_TAGS {
+            normalized.push(tag);
+        }
+    }
+
+    normalized
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TagsInput {
+    Joined(String),
+    List(Vec<String>),
+}
+
+fn deserialize_tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match TagsInput::deserialize(deserializer)? {
+        TagsInput::Joined(tags) => normalize_tags(tags.split(',')),
+        TagsInput::List(tags) => normalize_tags(tags.iter().map(String::as_str)),
+    })
+}
@@ -0,0 +1,14 @@
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+
+/// A reader's comment on a post. The content is plain text.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Comment {
+    pub id: i32,
+    pub blog_id: i32,
+    pub author_name: String,
+    pub author_email: Option<String>,
+    pub author_url: Option<String>,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,34 @@
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+#[derive(sqlx::FromRow)]
+pub struct ActorKey {
+    pub user_id: i32,
+    pub private_key: String,
+    pub public_key: String,
+}
+
+/// Someone on another server following one of our authors.
+#[derive(sqlx::FromRow)]
+pub struct Follower {
+    pub id: i32,
+    pub user_id: i32,
+    pub actor_id: String,
+    pub inbox: String,
+    pub shared_inbox: Option<String>,
+}
+
+/// An activity to post to `inbox`, signed with `user_id`'s actor key.
+#[derive(sqlx::FromRow)]
+pub struct Delivery {
+    pub id: i64,
+    pub user_id: i32,
+    pub inbox: String,
+    pub activity: Value,
+    pub attempts: i32,
+}
+
+#[derive(Deserialize)]
+pub struct WebfingerQuery {
+    pub resource: String,
+}
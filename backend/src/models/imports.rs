@@ -0,0 +1,56 @@
+use std::fmt;
+
+use serde_derive::Serialize;
+
+/// What importing one post did to the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Created,
+    Updated,
+    /// It was imported before and nothing about it has changed since.
+    Unchanged,
+}
+
+/// What an import created or changed, for the admin page and the command line.
+#[derive(Default, Serialize)]
+pub struct ImportReport {
+    pub users_created: usize,
+    pub posts_created: usize,
+    pub posts_updated: usize,
+    pub posts_unchanged: usize,
+    pub comments_created: usize,
+    /// Uploaded files, which only come with an export archive.
+    pub media_created: usize,
+    /// Why each thing that wasn't imported was left out.
+    pub skipped: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn count_post(&mut self, outcome: ImportOutcome) {
+        match outcome {
+            ImportOutcome::Created => self.posts_created += 1,
+            ImportOutcome::Updated => self.posts_updated += 1,
+            ImportOutcome::Unchanged => self.posts_unchanged += 1,
+        }
+    }
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Users created: {}", self.users_created)?;
+        writeln!(
+            f,
+            "Posts created: {}, updated: {}, unchanged: {}",
+            self.posts_created, self.posts_updated, self.posts_unchanged
+        )?;
+        writeln!(f, "Comments created: {}", self.comments_created)?;
+        if self.media_created > 0 {
+            writeln!(f, "Media files created: {}", self.media_created)?;
+        }
+        for reason in &self.skipped {
+            writeln!(f, "Skipped: {}", reason)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::roles::Role;
+
+/// Setting that closes `/users` to anyone without an invite code.
+pub const INVITE_ONLY: &str = "invite_only";
+
+/// Invite codes start with this so they are easy to recognise when shared.
+pub const INVITE_CODE_PREFIX: &str = "inv_";
+
+/// An invite as shown on the admin page. The code itself is only stored hashed.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Invite {
+    pub id: i32,
+    pub role: Role,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Not yet used up, expired or revoked.
+    pub outstanding: bool,
+}
+
+/// Someone who signed up with an invite.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct InviteRedemption {
+    pub invite_id: i32,
+    pub email: String,
+    pub redeemed_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateInvite {
+    pub role: Role,
+    pub max_uses: i32,
+    pub expires_in_days: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInvite {
+    pub id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct InviteOnlyForm {
+    #[serde(default)]
+    pub enabled: bool,
+}
@@ -1,5 +1,18 @@
+pub mod api_tokens;
+pub mod archive;
+pub mod comments;
+pub mod federation;
+pub mod imports;
+pub mod invites;
 pub mod page;
+pub mod passkeys;
+pub mod profiles;
+pub mod roles;
 pub mod users;
 pub mod blog;
+pub mod throttle;
+pub mod tokens;
+pub mod two_factor;
+pub mod webmentions;
 
 pub use blog::*;
\ No newline at end of file
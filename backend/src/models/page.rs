@@ -7,6 +7,24 @@ use crate::models::blog::{Blog};
 pub struct PagePackage {
 }
 
+/// `?page=` on paginated listings, counting from 1.
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub page: Option<i64>,
+}
+
+impl PageQuery {
+    pub fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    /// How many items come before this page, or `None` if that many can't be
+    /// counted, so no listing has that page.
+    pub fn offset(&self, per_page: i64) -> Option<i64> {
+        (self.page() - 1).checked_mul(per_page)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BlogPage {
     pub blog_page: Blog,
@@ -0,0 +1,35 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+pub const REGISTRATION_CHALLENGE: &str = "registration";
+pub const AUTHENTICATION_CHALLENGE: &str = "authentication";
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct Passkey {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The parts of a `PublicKeyCredential` returned by `navigator.credentials.create()`,
+/// with every binary field base64url encoded by the browser script.
+#[derive(Deserialize)]
+pub struct PasskeyRegistration {
+    pub id: String,
+    pub name: Option<String>,
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// The parts of a `PublicKeyCredential` returned by `navigator.credentials.get()`.
+#[derive(Deserialize)]
+pub struct PasskeyAssertion {
+    pub id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
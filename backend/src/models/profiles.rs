@@ -0,0 +1,100 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+pub const MAX_HANDLE_LENGTH: usize = 32;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+pub const MAX_BIO_LENGTH: usize = 2000;
+pub const MAX_URL_LENGTH: usize = 255;
+
+/// The public face of an account. Pages show this instead of the email address.
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct Profile {
+    pub user_id: i32,
+    pub handle: String,
+    pub display_name: String,
+    pub bio: String,
+    pub website: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProfileForm {
+    pub handle: String,
+    pub display_name: String,
+    #[serde(default)]
+    pub bio: String,
+    #[serde(default)]
+    pub website: String,
+    #[serde(default)]
+    pub avatar_url: String,
+}
+
+/// The handle every account starts with. Nobody else may pick it, so it is
+/// always free when the account is created.
+pub fn default_handle(user_id: i32) -> String {
+    format!("user{}", user_id)
+}
+
+impl ProfileForm {
+    /// Checks and tidies the submitted fields into the profile for `user_id`.
+    pub fn into_profile(self, user_id: i32) -> Result<Profile, AppError> {
+        let handle = self.handle.trim().to_lowercase();
+        let is_valid_handle = (3..=MAX_HANDLE_LENGTH).contains(&handle.len())
+            && handle
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_valid_handle {
+            return Err(AppError::InvalidProfile(
+                "Handles are 3 to 32 letters, numbers, dashes or underscores".to_string(),
+            ));
+        }
+        let looks_like_default = handle
+            .strip_prefix("user")
+            .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()));
+        if looks_like_default && handle != default_handle(user_id) {
+            return Err(AppError::HandleTaken);
+        }
+
+        let display_name = self.display_name.trim().to_string();
+        if display_name.is_empty() || display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            return Err(AppError::InvalidProfile(
+                "Display names are 1 to 100 characters".to_string(),
+            ));
+        }
+
+        let bio = self.bio.trim().to_string();
+        if bio.chars().count() > MAX_BIO_LENGTH {
+            return Err(AppError::InvalidProfile(
+                "Bios are at most 2000 characters".to_string(),
+            ));
+        }
+
+        Ok(Profile {
+            user_id,
+            handle,
+            display_name,
+            bio,
+            website: optional_url(&self.website)?,
+            avatar_url: optional_url(&self.avatar_url)?,
+        })
+    }
+}
+
+/// Blank means none. Anything else has to be an http(s) URL so it can't be
+/// turned into a `javascript:` link.
+fn optional_url(value: &str) -> Result<Option<String>, AppError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    match url::Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && value.len() <= MAX_URL_LENGTH => {
+            Ok(Some(url.to_string()))
+        }
+        _ => Err(AppError::InvalidProfile(
+            "Links have to be http or https URLs".to_string(),
+        )),
+    }
+}
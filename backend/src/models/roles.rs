@@ -0,0 +1,136 @@
+use std::marker::PhantomData;
+
+use axum::async_trait;
+use axum::extract::{FromRef, FromRequestParts};
+use http::request::Parts;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::models::users::Claims;
+
+/// What an account is allowed to do. Each role can do everything the roles
+/// before it can, so they are ordered from least to most trusted.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    sqlx::Type,
+    derive_more::Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    #[display(fmt = "reader")]
+    Reader,
+    #[display(fmt = "author")]
+    Author,
+    #[display(fmt = "editor")]
+    Editor,
+    #[display(fmt = "admin")]
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Reader, Role::Author, Role::Editor, Role::Admin];
+
+    /// The role given to new accounts.
+    pub const DEFAULT: Role = Role::Author;
+}
+
+/// Something a handler can require of the logged in user with [`RequirePermission`].
+pub trait Permission: Send + Sync {
+    /// The personal access token scope that allows using this permission.
+    const SCOPE: &'static str;
+
+    fn is_granted_to(role: Role) -> bool;
+}
+
+/// Write posts and edit your own.
+pub struct WritePosts;
+
+/// Edit posts written by anyone.
+pub struct EditAnyPost;
+
+/// Change your own author profile.
+pub struct EditProfile;
+
+/// Change other users' roles and site wide settings.
+pub struct ManageUsers;
+
+impl Permission for WritePosts {
+    const SCOPE: &'static str = "posts:write";
+
+    fn is_granted_to(role: Role) -> bool {
+        role >= Role::Author
+    }
+}
+
+impl Permission for EditAnyPost {
+    const SCOPE: &'static str = "posts:edit";
+
+    fn is_granted_to(role: Role) -> bool {
+        role >= Role::Editor
+    }
+}
+
+impl Permission for EditProfile {
+    const SCOPE: &'static str = "profile";
+
+    fn is_granted_to(role: Role) -> bool {
+        role >= Role::Reader
+    }
+}
+
+impl Permission for ManageUsers {
+    const SCOPE: &'static str = "admin";
+
+    fn is_granted_to(role: Role) -> bool {
+        role >= Role::Admin
+    }
+}
+
+/// Whether the user's role grants `P` and, for personal access tokens, the
+/// token was given `P`'s scope.
+pub fn is_permitted<P: Permission>(claims: &Claims) -> bool {
+    P::is_granted_to(claims.role) && claims.has_scope(P::SCOPE)
+}
+
+/// Extracts the logged in user's [`Claims`], rejecting the request with
+/// [`AppError::Forbidden`] unless their role grants `P` and, for personal
+/// access tokens, the token was given `P`'s scope.
+pub struct RequirePermission<P: Permission> {
+    pub claims: Claims,
+    permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<S, P> FromRequestParts<S> for RequirePermission<P>
+where
+    S: Send + Sync,
+    Store: FromRef<S>,
+    P: Permission,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
+
+        if !is_permitted::<P>(&claims) {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(RequirePermission {
+            claims,
+            permission: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,113 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde_derive::{Deserialize, Serialize};
+
+/// How hard to clamp down on repeated failed logins for one kind of key.
+pub struct ThrottlePolicy {
+    /// Failures allowed before any delay kicks in.
+    pub free_attempts: i32,
+    /// Failures after which the key is locked out instead of just slowed down.
+    pub lockout_after: i32,
+    pub lockout: Duration,
+}
+
+/// Failures for one email address, whether or not an account exists for it,
+/// so a lockout never gives away which addresses are registered.
+pub const ACCOUNT_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 3,
+    lockout_after: 10,
+    lockout: Duration::from_secs(60 * 15),
+};
+
+/// Failures from one IP address across every account it tries.
+pub const IP_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 10,
+    lockout_after: 50,
+    lockout: Duration::from_secs(60 * 60),
+};
+
+/// Webmentions from one IP address. Each one has us fetch a page, so they
+/// are limited like failed logins even though nothing failed.
+pub const WEBMENTION_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 20,
+    lockout_after: 60,
+    lockout: Duration::from_secs(60 * 60),
+};
+
+impl ThrottlePolicy {
+    /// How long the key has to wait after its `failures`th failure. The wait
+    /// doubles with every failure past the free ones until it turns into a
+    /// full lockout.
+    pub fn delay_after(&self, failures: i32) -> Option<Duration> {
+        if failures >= self.lockout_after {
+            Some(self.lockout)
+        } else if failures > self.free_attempts {
+            let exponent = (failures - self.free_attempts - 1).min(16) as u32;
+            Some(Duration::from_secs(2u64.pow(exponent)).min(self.lockout))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct LoginThrottle {
+    pub key: String,
+    pub failures: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockAccount {
+    pub key: String,
+}
+
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+pub fn ip_key(ip: &IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+pub fn webmention_key(ip: &IpAddr) -> String {
+    format!("webmention:{}", ip)
+}
+
+/// How many reverse proxies in front of us append to `X-Forwarded-For`:
+/// `TRUST_PROXY=true` for one, or the number of them.
+fn trusted_proxies() -> usize {
+    match std::env::var("TRUST_PROXY").as_deref() {
+        Ok("true") => 1,
+        Ok(hops) => hops.parse().unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// The address a request came from. `X-Forwarded-For` is only believed when
+/// `TRUST_PROXY` is set, since anyone can send it. Each proxy appends the
+/// address it got the request from, so with `n` of them the client is `n`
+/// entries from the right; whatever is further left came from the client.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+    let hops = trusted_proxies();
+    if hops > 0 {
+        let forwarded: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let client = forwarded
+            .len()
+            .checked_sub(hops)
+            .and_then(|index| forwarded[index].trim().parse().ok());
+        if client.is_some() {
+            return client;
+        }
+    }
+
+    peer.map(|addr| addr.ip())
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::cookies::CookieSpec;
+
+/// The cookie holding the long-lived refresh token. It goes with every
+/// request so [`crate::session::refresh_session`] can swap it for a new
+/// access token once the old one runs out.
+pub const REFRESH_COOKIE: CookieSpec = CookieSpec {
+    name: "refresh",
+    path: None,
+    same_site: None,
+};
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl RefreshToken {
+    /// A token that has already been exchanged or revoked must never be accepted again.
+    pub fn is_spent(&self) -> bool {
+        self.used_at.is_some() || self.revoked_at.is_some()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+
+    /// Exchanged within `grace`, so presenting it again is most likely a
+    /// request that raced the exchange, like two tabs loading at once,
+    /// rather than a stolen token being replayed.
+    pub fn was_just_used(&self, grace: Duration) -> bool {
+        let grace = chrono::Duration::from_std(grace).unwrap_or_else(|_| chrono::Duration::zero());
+        self.revoked_at.is_none() && self.used_at.is_some_and(|used_at| Utc::now() - used_at < grace)
+    }
+}
+
+/// Generates a new opaque token, returning the raw value handed to the client
+/// and the hash that gets stored in the database.
+pub fn generate_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
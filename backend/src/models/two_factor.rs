@@ -0,0 +1,114 @@
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::cookies::CookieSpec;
+use crate::error::AppError;
+
+/// Name of the cookie that remembers who passed the password step of a login
+/// while they still owe us a second factor.
+pub const SECOND_FACTOR_COOKIE: CookieSpec = CookieSpec {
+    name: "mfa_pending",
+    path: None,
+    same_site: None,
+};
+
+pub const SECOND_FACTOR_PURPOSE: &str = "second_factor";
+
+/// Setting key that forces every admin account to use two-factor authentication.
+pub const REQUIRE_ADMIN_2FA: &str = "require_admin_2fa";
+
+const TOTP_ISSUER: &str = "Rust Blog";
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct TotpSecret {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: i64,
+}
+
+/// Payload of the `mfa_pending` cookie. `enrol` is set when the account has
+/// to set up a second factor before it is allowed in.
+#[derive(Serialize, Deserialize)]
+pub struct SecondFactorClaims {
+    pub sub: i32,
+    pub purpose: String,
+    pub enrol: bool,
+    pub exp: u64,
+}
+
+#[derive(Deserialize)]
+pub struct SecondFactorForm {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct RequireAdmin2faForm {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Returns a new random base32 encoded secret.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    match Secret::Raw(bytes.to_vec()).to_encoded() {
+        Secret::Encoded(encoded) => encoded,
+        Secret::Raw(_) => unreachable!(),
+    }
+}
+
+pub fn build_totp(secret: &str, email: &str) -> Result<TOTP, AppError> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        TOTP_STEP,
+        bytes,
+        Some(TOTP_ISSUER.to_string()),
+        email.to_string(),
+    )
+    .map_err(|err| AppError::Any(anyhow::anyhow!("Could not build TOTP: {:?}", err)))
+}
+
+/// Checks `code` against the current time step and one step either side to
+/// allow for clock drift. Returns the matching step, which must be newer than
+/// `last_used_step` so that a code can't be replayed.
+pub fn matching_step(totp: &TOTP, code: &str, last_used_step: i64, now: u64) -> Option<i64> {
+    let current = (now / TOTP_STEP) as i64;
+    (current - 1..=current + 1)
+        .filter(|step| *step > last_used_step)
+        .find(|step| totp.generate(*step as u64 * TOTP_STEP) == code.trim())
+}
+
+/// Renders the `otpauth://` URL for an authenticator app as an SVG QR code.
+pub fn qr_code_svg(totp: &TOTP) -> Result<String, AppError> {
+    let code = QrCode::new(totp.get_url().as_bytes())
+        .map_err(|err| AppError::Any(anyhow::anyhow!("Could not build QR code: {}", err)))?;
+
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+/// Generates a fresh set of human friendly one-time recovery codes.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
@@ -1,20 +1,32 @@
-use axum::extract::FromRequestParts;
+use axum::extract::{FromRef, FromRequestParts};
 
 use axum::async_trait;
 use cookie::Cookie;
+use http::header::{AUTHORIZATION, COOKIE};
 use http::request::Parts;
-use jsonwebtoken::{decode, DecodingKey, EncodingKey, Validation};
-use once_cell::sync::Lazy;
+use http::HeaderMap;
 use std::convert::Infallible;
 
+use crate::cookies::{CookieSpec, COOKIE_POLICY};
+use crate::db::Store;
+use crate::keys;
 use crate::error::AppError;
+use crate::models::api_tokens::API_TOKEN_PREFIX;
+use crate::models::roles::Role;
+use crate::models::tokens::hash_token;
+use crate::{get_timestamp_after, ACCESS_TOKEN_LIFETIME};
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
+    #[serde(default)]
+    pub id: i32,
     pub email: String,
     pub password: String,
-    pub is_admin: bool,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub email_verified: bool,
 }
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
@@ -22,7 +34,41 @@ pub struct UserSignup {
     pub email: String,
     pub password: String,
     pub confirm_password: String,
-    pub is_admin: bool,
+    /// Needed while registration is invite-only, and picks the new account's role.
+    #[serde(default)]
+    pub invite_code: Option<String>,
+}
+
+/// A user as shown on the admin pages, without their password hash.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct UserSummary {
+    pub id: i32,
+    pub email: String,
+    pub role: Role,
+    pub email_verified: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SetRole {
+    pub user_id: i32,
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+    pub confirm_password: String,
 }
 
 pub struct LoggedInUser {
@@ -30,89 +76,200 @@ pub struct LoggedInUser {
 }
 
 #[derive(Serialize, Deserialize, derive_more::Display)]
-#[display(fmt = "email: {}, exp: {}, is_admin: {}", email, exp, is_admin)]
+#[display(
+    fmt = "sub: {}, email: {}, exp: {}, role: {}, email_verified: {}",
+    sub,
+    email,
+    exp,
+    role,
+    email_verified
+)]
 pub struct Claims {
+    /// The user's id. Look the user up by this; the email can change while
+    /// the token is still valid.
+    pub sub: i32,
+    pub email: String,
+    pub exp: u64,
+    pub role: Role,
+    pub email_verified: bool,
+    /// Only set when the request was made with a personal access token, in
+    /// which case it limits what the token may be used for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+impl Claims {
+    /// Sessions can do anything the user's role allows; personal access
+    /// tokens only what their scopes allow.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.iter().any(|granted| granted == scope))
+    }
+
+    /// True when the request came from a browser session rather than a
+    /// personal access token.
+    pub fn is_session(&self) -> bool {
+        self.scopes.is_none()
+    }
+
+    /// Rejects personal access tokens, whatever their scopes, for handlers
+    /// that manage how the user signs in: passwords, email, second factors
+    /// and the tokens themselves. A leaked token must not be able to mint
+    /// more credentials or lock the owner out.
+    pub fn require_session(&self) -> Result<(), AppError> {
+        if self.is_session() {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+}
+
+/// Payload of the signed link sent to confirm an email address. The address is
+/// included so a link stops working if the account's email changes.
+#[derive(Serialize, Deserialize)]
+pub struct EmailVerificationClaims {
+    pub sub: i32,
     pub email: String,
+    pub purpose: String,
     pub exp: u64,
-    pub is_admin: bool,
+}
+
+pub const EMAIL_VERIFICATION_PURPOSE: &str = "verify_email";
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+/// Signed into the link sent to the address a user wants to move to. It only
+/// works while the account still has the address it was requested from.
+#[derive(Serialize, Deserialize)]
+pub struct EmailChangeClaims {
+    pub sub: i32,
+    pub email: String,
+    pub new_email: String,
+    pub purpose: String,
+    pub exp: u64,
+}
+
+pub const EMAIL_CHANGE_PURPOSE: &str = "change_email";
+
+#[derive(Deserialize)]
+pub struct ChangeEmailForm {
+    pub new_email: String,
+    pub password: String,
 }
 
 #[async_trait]
 impl<S> FromRequestParts<S> for Claims
 where
     S: Send + Sync,
+    Store: FromRef<S>,
 {
     type Rejection = AppError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let jwt_token = parts
-            .headers
-            .get("cookie")
-            .and_then(|value| Cookie::parse(value.to_str().unwrap_or_default()).ok())
-            .and_then(|cookie| {
-                if cookie.name() == "jwt" {
-                    Some(cookie.value().to_string())
-                } else {
-                    None
-                }
-            })
-            .ok_or(AppError::InvalidToken)?;
-
-        let token_data = decode::<Claims>(&jwt_token, &KEYS.decoding, &Validation::default())
-            .map_err(|_| AppError::InvalidToken)?;
-
-        Ok(token_data.claims)
+    /// A browser session or a personal access token. Handlers check what a
+    /// token may do with [`RequirePermission`] or [`Claims::has_scope`], or
+    /// turn tokens away with [`Claims::require_session`].
+    ///
+    /// [`RequirePermission`]: crate::models::roles::RequirePermission
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        authenticate(&parts.headers, &Store::from_ref(state)).await
     }
 }
 
+/// The claims if the request is authenticated, like [`Claims`].
 pub struct OptionalClaims(pub Option<Claims>);
 
 #[async_trait]
 impl<S> FromRequestParts<S> for OptionalClaims
 where
     S: Send + Sync,
+    Store: FromRef<S>,
 {
     type Rejection = Infallible;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let jwt_token = parts
-            .headers
-            .get("cookie")
-            .and_then(|value| Cookie::parse(value.to_str().unwrap_or_default()).ok())
-            .and_then(|cookie| {
-                if cookie.name() == "jwt" {
-                    Some(cookie.value().to_string())
-                } else {
-                    None
-                }
-            });
-
-        if let Some(jwt) = jwt_token {
-            if let Ok(token_data) = decode::<Claims>(&jwt, &KEYS.decoding, &Validation::default()) {
-                return Ok(OptionalClaims(Some(token_data.claims)));
-            }
-        }
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = authenticate(&parts.headers, &Store::from_ref(state)).await.ok();
 
-        Ok(OptionalClaims(None))
+        Ok(OptionalClaims(claims))
     }
 }
 
-pub struct Keys {
-    pub encoding: EncodingKey,
-    pub decoding: DecodingKey,
+/// Works out who is making a request. Scripts send a personal access token
+/// (or an access token JWT) as `Authorization: Bearer`; browsers send the
+/// `jwt` cookie.
+async fn authenticate(headers: &HeaderMap, store: &Store) -> Result<Claims, AppError> {
+    match bearer_token(headers) {
+        Some(token) => token_claims(store, &token).await,
+        None => {
+            let token = COOKIE_POLICY
+                .read(headers, &ACCESS_COOKIE)
+                .ok_or(AppError::InvalidToken)?;
+            keys::verify::<Claims>(&token)
+        }
+    }
 }
 
-impl Keys {
-    pub fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
-        }
+/// The claims behind a bearer token, either a personal access token or an
+/// access token JWT.
+pub async fn token_claims(store: &Store, token: &str) -> Result<Claims, AppError> {
+    if token.starts_with(API_TOKEN_PREFIX) {
+        api_token_claims(store, token).await
+    } else {
+        keys::verify::<Claims>(token)
+    }
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim().to_string())
+    } else {
+        None
     }
 }
 
-pub static KEYS: Lazy<Keys> = Lazy::new(|| {
-    let secret = std::env::var("JWT_SECRET").expect("MISSING JWT SECRET!");
+async fn api_token_claims(store: &Store, raw_token: &str) -> Result<Claims, AppError> {
+    let (token, user) = store
+        .use_api_token(&hash_token(raw_token))
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+    let exp = token
+        .expires_at
+        .map(|expires_at| expires_at.timestamp() as u64)
+        .unwrap_or_else(|| get_timestamp_after(ACCESS_TOKEN_LIFETIME));
+
+    Ok(Claims {
+        sub: user.id,
+        email: user.email,
+        exp,
+        role: user.role,
+        email_verified: user.email_verified,
+        scopes: Some(token.scopes),
+    })
+}
+
+/// The cookie holding the short-lived access token for browser sessions.
+pub const ACCESS_COOKIE: CookieSpec = CookieSpec {
+    name: "jwt",
+    path: None,
+    same_site: None,
+};
 
-    Keys::new(secret.as_bytes())
-});
+/// Finds a cookie by name across every `Cookie` header on the request.
+pub fn read_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| Cookie::parse(pair.trim()).ok())
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+}
@@ -0,0 +1,67 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+/// How many of the newest mentions the moderation page lists.
+pub const MODERATION_PAGE_SIZE: i64 = 200;
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    sqlx::Type,
+    derive_more::Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum WebmentionStatus {
+    /// Waiting for the source to be fetched.
+    #[display(fmt = "pending")]
+    Pending,
+    /// The source links to the post, so it is shown under it.
+    #[display(fmt = "verified")]
+    Verified,
+    /// The source couldn't be fetched or doesn't link to the post.
+    #[display(fmt = "invalid")]
+    Invalid,
+    /// Verified, but taken down by an admin.
+    #[display(fmt = "hidden")]
+    Hidden,
+}
+
+/// Another page saying it links to one of our posts.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Webmention {
+    pub id: i32,
+    pub blog_id: i32,
+    pub source: String,
+    pub target: String,
+    pub status: WebmentionStatus,
+    pub source_title: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What a sender posts to `/webmention`.
+#[derive(Deserialize)]
+pub struct WebmentionForm {
+    pub source: String,
+    pub target: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Show,
+    Hide,
+    Delete,
+}
+
+#[derive(Deserialize)]
+pub struct ModerateWebmention {
+    pub id: i32,
+    pub action: ModerationAction,
+}
@@ -0,0 +1,499 @@
+//! A small OpenID Connect relying party: discovery, the authorization code
+//! flow with PKCE, and ID token validation against the provider's JWKS.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use cookie::SameSite;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::cookies::CookieSpec;
+use crate::error::AppError;
+use crate::get_public_url;
+
+/// Name of the cookie that carries the state, nonce and PKCE verifier
+/// between leaving for the provider and coming back.
+pub const OIDC_FLOW_COOKIE: CookieSpec = CookieSpec {
+    name: "oidc_flow",
+    path: Some("/oidc"),
+    // It has to come back with the provider's cross-site redirect to the
+    // callback, which SameSite=Strict would stop.
+    same_site: Some(SameSite::Lax),
+};
+
+pub const OIDC_FLOW_PURPOSE: &str = "oidc_flow";
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+    pub provider_name: String,
+}
+
+impl OidcConfig {
+    /// Single sign-on is turned off unless `OIDC_ISSUER` is set.
+    pub fn from_env() -> Option<Self> {
+        let issuer = std::env::var("OIDC_ISSUER").ok().filter(|issuer| !issuer.is_empty())?;
+
+        Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id: std::env::var("OIDC_CLIENT_ID").expect("Missing OIDC_CLIENT_ID"),
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").expect("Missing OIDC_CLIENT_SECRET"),
+            redirect_url: std::env::var("OIDC_REDIRECT_URL")
+                .unwrap_or_else(|_| format!("{}/oidc/callback", get_public_url())),
+            provider_name: std::env::var("OIDC_PROVIDER_NAME")
+                .unwrap_or_else(|_| "single sign-on".to_string()),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// What we remember about a login in progress, signed and stored in the
+/// `oidc_flow` cookie.
+#[derive(Serialize, Deserialize)]
+pub struct OidcFlowClaims {
+    pub state: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+    pub purpose: String,
+    pub exp: u64,
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The S256 PKCE challenge for a verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn oidc_error(message: &str, err: impl std::fmt::Display) -> AppError {
+    error!("{}: {}", message, err);
+    AppError::SingleSignOnFailed
+}
+
+pub async fn discover(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+) -> Result<ProviderMetadata, AppError> {
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let metadata: ProviderMetadata = client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // The issuer in the document has to be the one we were configured with.
+    if metadata.issuer.trim_end_matches('/') != config.issuer {
+        return Err(oidc_error("OIDC issuer mismatch", &metadata.issuer));
+    }
+
+    Ok(metadata)
+}
+
+pub fn authorization_url(
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    flow: &OidcFlowClaims,
+) -> Result<String, AppError> {
+    let mut url = url::Url::parse(&metadata.authorization_endpoint)
+        .map_err(|err| oidc_error("Invalid authorization endpoint", err))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_url)
+        .append_pair("scope", "openid email profile")
+        .append_pair("state", &flow.state)
+        .append_pair("nonce", &flow.nonce)
+        .append_pair("code_challenge", &pkce_challenge(&flow.pkce_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(url.to_string())
+}
+
+/// Swaps an authorization code for tokens and returns the validated ID token claims.
+pub async fn exchange_code(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    code: &str,
+    flow: &OidcFlowClaims,
+) -> Result<IdTokenClaims, AppError> {
+    let response = client
+        .post(&metadata.token_endpoint)
+        .basic_auth(&config.client_id, Some(&config.client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("code_verifier", flow.pkce_verifier.as_str()),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(oidc_error("OIDC token request failed", body));
+    }
+    let tokens: TokenResponse = response.json().await?;
+
+    validate_id_token(client, config, metadata, &tokens.id_token, &flow.nonce).await
+}
+
+async fn validate_id_token(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    expected_nonce: &str,
+) -> Result<IdTokenClaims, AppError> {
+    let header = decode_header(id_token).map_err(|err| oidc_error("Invalid ID token", err))?;
+    // Symmetric algorithms would let anyone holding the client secret mint tokens.
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(oidc_error("Refusing ID token algorithm", format!("{:?}", header.alg)));
+    }
+
+    let jwks: JwkSet = client
+        .get(&metadata.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or_else(|| oidc_error("No matching JWK for ID token", format!("{:?}", header.kid)))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|err| oidc_error("Unusable JWK", err))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&config.client_id]);
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|err| oidc_error("ID token failed validation", err))?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(expected_nonce) {
+        return Err(oidc_error("ID token nonce mismatch", &claims.sub));
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::routing::{get, post};
+    use axum::{Form, Json, Router};
+    use http::header::COOKIE;
+    use http::{HeaderMap, HeaderValue, StatusCode};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use p256::pkcs8::{EncodePrivateKey, LineEnding};
+    use rand::rngs::OsRng;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::cookies::COOKIE_POLICY;
+    use crate::db::Store;
+    use crate::get_timestamp_after;
+    use crate::handlers::{finish_oidc_login, hash_password};
+    use crate::{keys, test_support};
+
+    const CLIENT_ID: &str = "blog";
+    const CLIENT_SECRET: &str = "provider-shared-secret";
+    const KID: &str = "provider-key";
+
+    /// An identity provider with discovery, a JWKS and a token endpoint, and
+    /// the configuration for signing in with it. The authorization code is
+    /// taken to be the ID token itself, so each test decides what the
+    /// provider hands back.
+    struct MockProvider {
+        config: OidcConfig,
+        key: EncodingKey,
+    }
+
+    fn start_provider() -> MockProvider {
+        let secret = p256::SecretKey::random(&mut OsRng);
+        let point = secret.public_key().to_encoded_point(false);
+        let jwks = json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": KID,
+                "alg": "ES256",
+                "use": "sig",
+                "x": URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+                "y": URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+            }]
+        });
+        let key = EncodingKey::from_ec_pem(secret.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+
+        let issuer = test_support::serve(|issuer| {
+            let discovery = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+            });
+            Router::new()
+                .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+                .route("/jwks", get(move || async move { Json(jwks) }))
+                .route("/token", post(token_endpoint))
+        });
+        let config = OidcConfig {
+            issuer,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            redirect_url: "https://blog.example/oidc/callback".to_string(),
+            provider_name: "Test".to_string(),
+        };
+
+        MockProvider { config, key }
+    }
+
+    async fn token_endpoint(Form(form): Form<HashMap<String, String>>) -> Result<Json<Value>, StatusCode> {
+        let grant_type = form.get("grant_type").map(String::as_str);
+        match (grant_type, form.get("code"), form.get("code_verifier")) {
+            (Some("authorization_code"), Some(code), Some(_)) => Ok(Json(json!({
+                "access_token": "unused",
+                "token_type": "Bearer",
+                "id_token": code,
+            }))),
+            _ => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    fn flow() -> OidcFlowClaims {
+        OidcFlowClaims {
+            state: random_token(),
+            nonce: random_token(),
+            pkce_verifier: random_token(),
+            purpose: OIDC_FLOW_PURPOSE.to_string(),
+            exp: get_timestamp_after(std::time::Duration::from_secs(600)),
+        }
+    }
+
+    /// The claims a well-behaved provider would put in the ID token.
+    fn claims(provider: &MockProvider, flow: &OidcFlowClaims, email: &str) -> Value {
+        let now = chrono::Utc::now().timestamp();
+        json!({
+            "iss": provider.config.issuer,
+            "aud": CLIENT_ID,
+            "sub": format!("subject-{}", email),
+            "nonce": flow.nonce,
+            "email": email,
+            "email_verified": true,
+            "iat": now,
+            "exp": now + 300,
+        })
+    }
+
+    fn id_token(provider: &MockProvider, claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(KID.to_string());
+        encode(&header, claims, &provider.key).unwrap()
+    }
+
+    /// Comes back from the provider with `id_token` as the code.
+    async fn callback(
+        store: &Store,
+        provider: &MockProvider,
+        flow: &OidcFlowClaims,
+        state: &str,
+        id_token: String,
+    ) -> Result<http::Response<hyper::Body>, AppError> {
+        let cookie = COOKIE_POLICY.build(&OIDC_FLOW_COOKIE, keys::sign(flow).unwrap(), None);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("{}={}", cookie.name(), cookie.value())).unwrap(),
+        );
+        let callback = OidcCallback {
+            code: Some(id_token),
+            state: Some(state.to_string()),
+            error: None,
+        };
+        finish_oidc_login(store, &provider.config, &headers, callback).await
+    }
+
+    fn is_rejected<T>(result: Result<T, AppError>) -> bool {
+        matches!(result, Err(AppError::SingleSignOnFailed))
+    }
+
+    #[sqlx::test]
+    async fn new_identity_gets_an_account_and_a_session(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        let email = "new@blog.example";
+
+        let response = callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims(&provider, &flow, email)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert!(response.headers().get_all(http::header::SET_COOKIE).iter().count() >= 2);
+
+        let user = store.get_user(email).await.unwrap();
+        assert!(user.email_verified);
+        let linked = store.get_oidc_identity(&provider.config.issuer, &format!("subject-{}", email)).await.unwrap();
+        assert_eq!(linked, Some(user.id));
+    }
+
+    #[sqlx::test]
+    async fn verified_email_links_to_the_existing_account(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let email = "existing@blog.example";
+        let existing = store
+            .create_verified_user(email, &hash_password("a password").unwrap())
+            .await
+            .unwrap();
+
+        let flow = flow();
+        callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims(&provider, &flow, email)))
+            .await
+            .unwrap();
+
+        let subject = format!("subject-{}", email);
+        let linked = store.get_oidc_identity(&provider.config.issuer, &subject).await.unwrap();
+        assert_eq!(linked, Some(existing.id));
+
+        // Later sign-ins find the account by the identity.
+        let flow = self::flow();
+        let mut claims = claims(&provider, &flow, email);
+        claims["email"] = json!("changed@blog.example");
+        callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await.unwrap();
+        assert!(store.get_user("changed@blog.example").await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn unverified_email_is_not_linked(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let email = "existing@blog.example";
+        store
+            .create_verified_user(email, &hash_password("a password").unwrap())
+            .await
+            .unwrap();
+
+        let flow = flow();
+        let mut claims = claims(&provider, &flow, email);
+        claims["email_verified"] = json!(false);
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await));
+
+        let subject = format!("subject-{}", email);
+        assert_eq!(store.get_oidc_identity(&provider.config.issuer, &subject).await.unwrap(), None);
+    }
+
+    #[sqlx::test]
+    async fn state_mismatch_is_rejected(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        let token = id_token(&provider, &claims(&provider, &flow, "someone@blog.example"));
+        assert!(is_rejected(callback(&store, &provider, &flow, "someone-elses-state", token).await));
+    }
+
+    #[sqlx::test]
+    async fn nonce_mismatch_is_rejected(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        let mut claims = claims(&provider, &flow, "someone@blog.example");
+        claims["nonce"] = json!(random_token());
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await));
+
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await));
+    }
+
+    #[sqlx::test]
+    async fn token_for_another_client_is_rejected(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        let mut claims = claims(&provider, &flow, "someone@blog.example");
+        claims["aud"] = json!("another-client");
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await));
+    }
+
+    #[sqlx::test]
+    async fn token_from_another_issuer_is_rejected(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        let mut claims = claims(&provider, &flow, "someone@blog.example");
+        claims["iss"] = json!("https://issuer.example");
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await));
+    }
+
+    #[sqlx::test]
+    async fn expired_token_is_rejected(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        let mut claims = claims(&provider, &flow, "someone@blog.example");
+        let now = chrono::Utc::now().timestamp();
+        claims["iat"] = json!(now - 3600);
+        claims["exp"] = json!(now - 600);
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, id_token(&provider, &claims)).await));
+    }
+
+    #[sqlx::test]
+    async fn hs256_token_is_rejected(pool: PgPool) {
+        let store = test_support::store(pool);
+        let provider = start_provider();
+        let flow = flow();
+        // Signed with the client secret, which we share with the provider.
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims(&provider, &flow, "someone@blog.example"),
+            &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+        )
+        .unwrap();
+        assert!(is_rejected(callback(&store, &provider, &flow, &flow.state, token).await));
+        assert!(store.get_user("someone@blog.example").await.is_err());
+    }
+}
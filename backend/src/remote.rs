@@ -0,0 +1,143 @@
+//! Requests to URLs that someone else chose, such as a Webmention source or
+//! the key an ActivityPub request is signed with. They go through a
+//! [`Remote`], whose client won't connect to loopback, private or link-local
+//! addresses however the URL gets there: as an IP address, as a host name
+//! that resolves to one, or through a redirect. Otherwise anyone could use
+//! us to probe our own network.
+//!
+//! `REMOTE_ALLOW_LOCAL=true` turns that off, along with the need for https,
+//! for trying federation and Webmentions against servers on your own
+//! machine. It has nothing to do with `PUBLIC_URL`, so a site behind a
+//! TLS-terminating proxy keeps the guard.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+
+/// Redirects we follow before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// The guarded client for outside URLs, and what it allows.
+#[derive(Clone)]
+pub struct Remote {
+    allow_local: bool,
+    client: reqwest::Client,
+}
+
+impl Remote {
+    /// `allow_local` lets requests go to private addresses and over plain http.
+    pub fn new(allow_local: bool) -> Self {
+        Self {
+            allow_local,
+            client: client(allow_local),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("REMOTE_ALLOW_LOCAL").is_ok_and(|value| value == "true"))
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Whether private addresses and plain http are allowed, see [`Remote::new`].
+    pub fn allows_local(&self) -> bool {
+        self.allow_local
+    }
+
+    /// Whether `url` is http or https and doesn't name a private address
+    /// outright. Host names are checked again when the client resolves them.
+    pub fn is_fetchable(&self, url: &url::Url) -> bool {
+        is_fetchable(url, self.allow_local)
+    }
+}
+
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local, fc00::/7, and link-local, fe80::/10.
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+fn is_fetchable(url: &url::Url, allow_local: bool) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    if allow_local {
+        return true;
+    }
+
+    match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.');
+            !(domain.eq_ignore_ascii_case("localhost") || domain.to_ascii_lowercase().ends_with(".localhost"))
+        }
+        Some(url::Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+        None => false,
+    }
+}
+
+/// Looks host names up as usual but drops private addresses from the
+/// answer, so the address that is checked is the one connected to.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| is_public_ip(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Every redirect is checked with [`is_fetchable`] before it is followed.
+fn client(allow_local: bool) -> reqwest::Client {
+    let redirects = Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if !is_fetchable(attempt.url(), allow_local) {
+            attempt.error("redirected to an address we don't fetch")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    let mut builder = reqwest::Client::builder().redirect(redirects);
+    if !allow_local {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    builder
+        .build()
+        .expect("Could not build the HTTP client for remote requests")
+}
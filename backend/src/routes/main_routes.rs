@@ -1,13 +1,14 @@
+use axum::extract::DefaultBodyLimit;
 use axum::response::Response;
 use axum::routing::*;
-use axum::Router;
+use axum::{middleware, Router};
 use http::StatusCode;
 use hyper::Body;
 use sqlx::PgPool;
 
 use crate::db::Store;
 use crate::handlers::root;
-use crate::{file_handler, handlers, layers};
+use crate::{csrf, file_handler, handlers, layers, micropub, session, wordpress};
 
 pub async fn app(pool: PgPool) -> Router {
     let db = Store::with_pool(pool);
@@ -24,10 +25,101 @@ pub async fn app(pool: PgPool) -> Router {
         .route("/make_blog", get(handlers::make_blog))
         .route("/post_blog", post(handlers::post_blog))
         .route("/all_blogs", get(handlers::all_blogs))
+        .route("/posts/:id", get(handlers::post_page))
+        .route("/tags/:tag", get(handlers::tag_page))
+        .route("/feed.xml", get(handlers::rss_feed))
+        .route("/atom.xml", get(handlers::atom_feed))
+        .route("/feed.json", get(handlers::json_feed))
+        .route("/authors/:handle", get(handlers::author_page))
+        .route("/authors/:handle/feed.xml", get(handlers::author_rss_feed))
+        .route("/authors/:handle/atom.xml", get(handlers::author_atom_feed))
+        .route("/tags/:tag/feed.xml", get(handlers::tag_rss_feed))
+        .route("/tags/:tag/atom.xml", get(handlers::tag_atom_feed))
+        .route(
+            "/profile",
+            get(handlers::profile_page).post(handlers::update_profile),
+        )
         .route("/users", post(handlers::register))
         .route("/login", post(handlers::login))
+        .route(
+            "/login/2fa",
+            get(handlers::second_factor_page).post(handlers::second_factor),
+        )
+        .route(
+            "/2fa/setup",
+            get(handlers::two_factor_setup_page).post(handlers::two_factor_setup),
+        )
+        .route("/2fa/disable", post(handlers::two_factor_disable))
+        .route("/passkeys/register/start", post(handlers::passkey_register_start))
+        .route("/passkeys/register/finish", post(handlers::passkey_register_finish))
+        .route("/passkeys/login/start", post(handlers::passkey_login_start))
+        .route("/passkeys/login/finish", post(handlers::passkey_login_finish))
+        .route("/oidc/login", get(handlers::oidc_login))
+        .route("/oidc/callback", get(handlers::oidc_callback))
+        .route(
+            "/tokens",
+            get(handlers::api_tokens_page).post(handlers::create_api_token),
+        )
+        .route("/tokens/revoke", post(handlers::revoke_api_token))
+        .route("/admin/users", get(handlers::admin_users_page))
+        .route("/admin/users/role", post(handlers::set_user_role))
+        .route("/admin/users/unlock", post(handlers::unlock_account))
+        .route(
+            "/admin/invites",
+            get(handlers::invites_page).post(handlers::create_invite),
+        )
+        .route("/admin/invites/revoke", post(handlers::revoke_invite))
+        .route("/admin/invite_only", post(handlers::set_invite_only))
+        .route("/admin/webmentions", get(handlers::admin_webmentions_page))
+        .route("/admin/webmentions/moderate", post(handlers::moderate_webmention))
+        .route(
+            "/admin/import",
+            get(handlers::admin_import_page)
+                .post(handlers::import_wordpress)
+                .layer(DefaultBodyLimit::max(wordpress::MAX_WXR_BYTES)),
+        )
+        .route("/admin/export", get(handlers::export_archive))
+        .route("/admin/security", get(handlers::admin_security_page))
+        .route("/admin/require_2fa", post(handlers::set_require_admin_2fa))
+        .route("/token/refresh", post(handlers::refresh_token))
+        .route(
+            "/account/email",
+            get(handlers::change_email_page).post(handlers::change_email),
+        )
+        .route("/account/email/confirm", get(handlers::confirm_email_change))
+        .route("/verify_email", get(handlers::verify_email))
+        .route("/verify_email/resend", post(handlers::resend_verification))
+        .route(
+            "/forgot_password",
+            get(handlers::forgot_password_page).post(handlers::forgot_password),
+        )
+        .route(
+            "/reset_password",
+            get(handlers::reset_password_page).post(handlers::reset_password),
+        )
+        .route("/.well-known/webfinger", get(handlers::webfinger))
+        .route("/ap/users/:id", get(handlers::actor))
+        .route("/ap/users/:id/outbox", get(handlers::actor_outbox))
+        .route("/ap/users/:id/followers", get(handlers::actor_followers))
+        .route("/ap/users/:id/inbox", post(handlers::actor_inbox))
+        .route("/ap/posts/:id", get(handlers::activitypub_post))
+        .route("/webmention", post(handlers::receive_webmention))
+        .route(
+            "/micropub",
+            get(handlers::micropub_query).post(handlers::micropub),
+        )
+        .route(
+            "/micropub/media",
+            post(handlers::micropub_media).layer(DefaultBodyLimit::max(micropub::MAX_MEDIA_BYTES)),
+        )
+        .route("/media/:file", get(handlers::media_file))
+        .route("/sitemap.xml", get(handlers::sitemap))
+        .route("/sitemaps/:file", get(handlers::sitemap_page))
+        .route("/robots.txt", get(handlers::robots_txt))
         .route("/protected", get(handlers::protected))
         .route("/*_", get(handle_404))
+        .layer(middleware::from_fn_with_state(db.clone(), session::refresh_session))
+        .layer(middleware::from_fn(csrf::csrf_protect))
         .layer(cors_layer)
         .layer(trace_layer)
         .with_state(db)
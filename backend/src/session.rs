@@ -0,0 +1,201 @@
+//! Keeps browser sessions going past the access token's short lifetime.
+//!
+//! The `jwt` cookie only lasts [`crate::ACCESS_TOKEN_LIFETIME`]. When a
+//! request comes in without a valid one but with a refresh cookie,
+//! [`refresh_session`] exchanges the refresh token on the way in, so the
+//! handler sees the new access token, and sets the new cookies on the way
+//! out. Pages never have to call `/token/refresh` themselves.
+
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::Response;
+use cookie::Cookie;
+use http::header::{COOKIE, SET_COOKIE};
+use http::{HeaderMap, HeaderValue, Request};
+use hyper::Body;
+use tracing::error;
+
+use crate::cookies::COOKIE_POLICY;
+use crate::db::Store;
+use crate::error::AppError;
+use crate::handlers::rotate_session;
+use crate::keys;
+use crate::models::tokens::REFRESH_COOKIE;
+use crate::models::users::{bearer_token, Claims, ACCESS_COOKIE};
+
+/// Exchanges its refresh token itself.
+const REFRESH_PATH: &str = "/token/refresh";
+
+pub async fn refresh_session(
+    State(database): State<Store>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(refresh_token) = needs_refresh(&request) else {
+        return next.run(request).await;
+    };
+
+    let cookies = match rotate_session(&database, &refresh_token).await {
+        Ok(cookies) => cookies,
+        Err(AppError::InvalidToken) => {
+            // A dead refresh token would only be tried again on every request.
+            vec![COOKIE_POLICY.clear(&ACCESS_COOKIE), COOKIE_POLICY.clear(&REFRESH_COOKIE)]
+        }
+        Err(err) => {
+            error!("Could not refresh session: {:?}", err);
+            return next.run(request).await;
+        }
+    };
+
+    let access_name = COOKIE_POLICY.name_for(&ACCESS_COOKIE);
+    let access_token = cookies
+        .iter()
+        .find(|cookie| cookie.name() == access_name && !cookie.value().is_empty())
+        .map(|cookie| cookie.value().to_string());
+    replace_cookie(request.headers_mut(), &access_name, access_token.as_deref());
+
+    let mut response = next.run(request).await;
+    for cookie in cookies {
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+
+    response
+}
+
+/// The refresh token to exchange, for browser requests whose access token is
+/// missing or has run out.
+fn needs_refresh(request: &Request<Body>) -> Option<String> {
+    let headers = request.headers();
+    if request.uri().path() == REFRESH_PATH || bearer_token(headers).is_some() {
+        return None;
+    }
+    let has_access = COOKIE_POLICY
+        .read(headers, &ACCESS_COOKIE)
+        .is_some_and(|token| keys::verify::<Claims>(&token).is_ok());
+    if has_access {
+        return None;
+    }
+
+    COOKIE_POLICY.read(headers, &REFRESH_COOKIE)
+}
+
+/// Rewrites the request's cookies so `name` has `value`, or is gone.
+fn replace_cookie(headers: &mut HeaderMap, name: &str, value: Option<&str>) {
+    let mut pairs: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| Cookie::parse(pair.trim().to_string()).ok())
+        .filter(|cookie| cookie.name() != name)
+        .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+        .collect();
+    if let Some(value) = value {
+        pairs.push(format!("{}={}", name, value));
+    }
+
+    headers.remove(COOKIE);
+    if let Ok(value) = HeaderValue::from_str(&pairs.join("; ")) {
+        headers.insert(COOKIE, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use chrono::Utc;
+    use http::StatusCode;
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::models::tokens::generate_token;
+    use crate::test_support;
+
+    async fn whoami(claims: Claims) -> String {
+        claims.email
+    }
+
+    /// A user with a refresh token and nothing else, as after the access
+    /// cookie has run out.
+    async fn refresh_token(store: &Store) -> String {
+        let user = store.create_verified_user("reader@blog.example", "unused").await.unwrap();
+        let (token, hash) = generate_token();
+        store
+            .create_refresh_token(user.id, Uuid::new_v4(), &hash, Utc::now() + chrono::Duration::days(1))
+            .await
+            .unwrap();
+        token
+    }
+
+    async fn get_whoami(store: &Store, refresh_token: &str) -> Response {
+        let app = Router::new()
+            .route("/", get(whoami))
+            .layer(middleware::from_fn_with_state(store.clone(), refresh_session))
+            .with_state(store.clone());
+        let cookie = format!("{}={}", COOKIE_POLICY.name_for(&REFRESH_COOKIE), refresh_token);
+        let request = Request::builder().uri("/").header(COOKIE, cookie).body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    fn set_cookies(response: &Response) -> Vec<Cookie<'static>> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| Cookie::parse(value.to_str().unwrap().to_string()).unwrap())
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn expired_session_is_refreshed_on_the_way_in(pool: PgPool) {
+        let store = test_support::store(pool);
+        let token = refresh_token(&store).await;
+
+        let response = get_whoami(&store, &token).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let names: Vec<String> = set_cookies(&response).iter().map(|cookie| cookie.name().to_string()).collect();
+        assert!(names.contains(&COOKIE_POLICY.name_for(&ACCESS_COOKIE)));
+        assert!(names.contains(&COOKIE_POLICY.name_for(&REFRESH_COOKIE)));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"reader@blog.example");
+    }
+
+    #[sqlx::test]
+    async fn racing_request_only_gets_an_access_cookie(pool: PgPool) {
+        let store = test_support::store(pool);
+        let token = refresh_token(&store).await;
+        get_whoami(&store, &token).await;
+
+        // A second tab that sent the same refresh token a moment later.
+        let response = get_whoami(&store, &token).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookies = set_cookies(&response);
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].name(), COOKIE_POLICY.name_for(&ACCESS_COOKIE));
+    }
+
+    #[sqlx::test]
+    async fn replayed_refresh_token_ends_the_session(pool: PgPool) {
+        let store = test_support::store(pool);
+        let token = refresh_token(&store).await;
+        get_whoami(&store, &token).await;
+        sqlx::query("UPDATE refresh_tokens SET used_at = NOW() - INTERVAL '1 hour'")
+            .execute(&store.conn_pool)
+            .await
+            .unwrap();
+
+        let response = get_whoami(&store, &token).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(set_cookies(&response).iter().all(|cookie| cookie.value().is_empty()));
+        let live: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM refresh_tokens WHERE revoked_at IS NULL")
+            .fetch_one(&store.conn_pool)
+            .await
+            .unwrap();
+        assert_eq!(live, 0);
+    }
+}
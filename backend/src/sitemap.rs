@@ -0,0 +1,120 @@
+//! `sitemap.xml` for search engines and the `robots.txt` that points them at
+//! it. The sitemap lists every post, author page and tag page. Past the
+//! protocol's limit of [`SITEMAP_URL_LIMIT`] URLs it turns into a sitemap
+//! index of numbered sitemaps under `/sitemaps/`.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::get_public_url;
+
+/// The most URLs one sitemap file may hold.
+pub const SITEMAP_URL_LIMIT: usize = 50_000;
+
+pub const SITEMAP_CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+
+const SITEMAP_NAMESPACE: &str = "http://www.sitemaps.org/schemas/sitemap/0.9";
+
+/// Kept out of search results unless `ROBOTS_DISALLOW` says otherwise: pages
+/// that are only useful when logged in.
+const DEFAULT_DISALLOW: &str = "/admin,/account,/profile,/tokens,/2fa,/login,/oidc,/make_blog";
+
+pub struct SitemapUrl {
+    pub loc: String,
+    pub lastmod: Option<DateTime<Utc>>,
+}
+
+impl SitemapUrl {
+    pub fn new(path: &str, lastmod: Option<DateTime<Utc>>) -> Self {
+        SitemapUrl {
+            loc: format!("{}{}", get_public_url(), path),
+            lastmod,
+        }
+    }
+}
+
+/// Which numbered sitemap `n` serves, or `None` past the last one.
+pub fn page(urls: &[SitemapUrl], n: usize) -> Option<&[SitemapUrl]> {
+    urls.chunks(SITEMAP_URL_LIMIT).nth(n.checked_sub(1)?)
+}
+
+pub fn needs_index(urls: &[SitemapUrl]) -> bool {
+    urls.len() > SITEMAP_URL_LIMIT
+}
+
+fn lastmod_element(lastmod: Option<DateTime<Utc>>) -> String {
+    lastmod
+        .map(|lastmod| {
+            format!(
+                "<lastmod>{}</lastmod>",
+                lastmod.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )
+        })
+        .unwrap_or_default()
+}
+
+pub fn urlset(urls: &[SitemapUrl]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"{}\">\n",
+        SITEMAP_NAMESPACE
+    );
+    for url in urls {
+        xml.push_str(&format!(
+            "<url><loc>{}</loc>{}</url>\n",
+            html_escape::encode_text(&url.loc),
+            lastmod_element(url.lastmod)
+        ));
+    }
+    xml.push_str("</urlset>\n");
+
+    xml
+}
+
+/// Links to `/sitemaps/1.xml` onwards, each dated by its newest URL.
+pub fn sitemap_index(urls: &[SitemapUrl]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sitemapindex xmlns=\"{}\">\n",
+        SITEMAP_NAMESPACE
+    );
+    for (index, chunk) in urls.chunks(SITEMAP_URL_LIMIT).enumerate() {
+        let lastmod = chunk.iter().filter_map(|url| url.lastmod).max();
+        xml.push_str(&format!(
+            "<sitemap><loc>{}/sitemaps/{}.xml</loc>{}</sitemap>\n",
+            html_escape::encode_text(&get_public_url()),
+            index + 1,
+            lastmod_element(lastmod)
+        ));
+    }
+    xml.push_str("</sitemapindex>\n");
+
+    xml
+}
+
+/// Lets everyone crawl except the paths in `ROBOTS_DISALLOW`, a comma
+/// separated list, or everything when `ROBOTS_ALLOW_INDEXING=false`.
+pub fn robots_txt() -> String {
+    let allow_indexing = std::env::var("ROBOTS_ALLOW_INDEXING")
+        .map(|value| value != "false")
+        .unwrap_or(true);
+
+    let mut robots = String::from("User-agent: *\n");
+    if allow_indexing {
+        let disallow =
+            std::env::var("ROBOTS_DISALLOW").unwrap_or_else(|_| DEFAULT_DISALLOW.to_string());
+        let paths: Vec<&str> = disallow
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .collect();
+        if paths.is_empty() {
+            robots.push_str("Disallow:\n");
+        }
+        for path in paths {
+            robots.push_str(&format!("Disallow: {}\n", path));
+        }
+        robots.push_str(&format!("\nSitemap: {}/sitemap.xml\n", get_public_url()));
+    } else {
+        robots.push_str("Disallow: /\n");
+    }
+
+    robots
+}
@@ -10,6 +10,8 @@ lazy_static::lazy_static! {
             }
         };
         tera.autoescape_on(vec![".html", ".sql"]);
+        tera.register_function("csrf_field", crate::csrf::CsrfField);
+        tera.register_function("csrf_token", crate::csrf::CsrfToken);
         tera
     };
 }
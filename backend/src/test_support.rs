@@ -0,0 +1,33 @@
+//! Fixtures shared by the tests.
+
+use std::net::TcpListener;
+
+use axum::Router;
+use sqlx::PgPool;
+
+use crate::db::Store;
+use crate::keys;
+use crate::remote::Remote;
+
+/// Serves the app `build` makes on a free port of this machine until the
+/// test ends, to stand in for another site. `build` gets the base URL, which
+/// is also returned, as sites tend to put their own address in documents.
+pub fn serve(build: impl FnOnce(&str) -> Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(build(&base_url).into_make_service());
+    tokio::spawn(async move { server.await.unwrap() });
+
+    base_url
+}
+
+/// A store on the test's database that signs with the test key and may
+/// reach the servers from [`serve`].
+pub fn store(pool: PgPool) -> Store {
+    keys::install_test_key();
+    let mut store = Store::with_pool(pool);
+    store.remote = Remote::new(true);
+    store
+}
@@ -0,0 +1,435 @@
+//! Just enough of the WebAuthn Level 2 relying party rules to register and
+//! verify passkeys. Only ES256 credentials are accepted and attestation
+//! statements are not checked, matching the `attestation: "none"` we ask for.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::value::Value;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+use crate::get_public_url;
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256.
+pub const ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Who we are as far as authenticators are concerned.
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn from_env() -> Self {
+        let origin = get_public_url().trim_end_matches('/').to_string();
+        let id = std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| {
+            url::Url::parse(&origin)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| "localhost".to_string())
+        });
+
+        Self {
+            id,
+            name: "Rust Blog".to_string(),
+            origin,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// A credential that passed the registration ceremony and is ready to store.
+pub struct RegisteredCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| AppError::InvalidPasskey)
+}
+
+/// Checks `clientDataJSON` and returns the challenge it was signed over.
+pub fn verify_client_data(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    expected_type: &str,
+) -> Result<String, AppError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| AppError::InvalidPasskey)?;
+
+    if client_data.kind != expected_type || client_data.origin != rp.origin {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    Ok(client_data.challenge)
+}
+
+/// Verifies the `attestationObject` from a `navigator.credentials.create()` call.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    attestation_object: &[u8],
+) -> Result<RegisteredCredential, AppError> {
+    let attestation: Value =
+        ciborium::de::from_reader(attestation_object).map_err(|_| AppError::InvalidPasskey)?;
+    let auth_data = map_get(&attestation, |key| key.as_text() == Some("authData"))
+        .and_then(Value::as_bytes)
+        .ok_or(AppError::InvalidPasskey)?;
+
+    let (flags, sign_count) = verify_auth_data(rp, auth_data)?;
+    if flags & FLAG_ATTESTED_CREDENTIAL == 0 || auth_data.len() < 55 {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    // 37 bytes of rpIdHash, flags and signCount, then a 16 byte AAGUID.
+    let id_length = u16::from_be_bytes([auth_data[53], auth_data[54]]) as usize;
+    let credential_id = auth_data
+        .get(55..55 + id_length)
+        .ok_or(AppError::InvalidPasskey)?;
+    let cose_key: Value = ciborium::de::from_reader(&auth_data[55 + id_length..])
+        .map_err(|_| AppError::InvalidPasskey)?;
+
+    Ok(RegisteredCredential {
+        credential_id: encode(credential_id),
+        public_key: cose_to_sec1(&cose_key)?,
+        sign_count: sign_count as i64,
+    })
+}
+
+/// Verifies an assertion from `navigator.credentials.get()` against a stored
+/// public key and returns the authenticator's new signature counter.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    public_key: &[u8],
+    stored_sign_count: i64,
+    auth_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<i64, AppError> {
+    let (_, sign_count) = verify_auth_data(rp, auth_data)?;
+
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| AppError::InvalidPasskey)?;
+    let signature = Signature::from_der(signature).map_err(|_| AppError::InvalidPasskey)?;
+    let mut signed = auth_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    key.verify(&signed, &signature)
+        .map_err(|_| AppError::InvalidPasskey)?;
+
+    // A counter that doesn't move forward suggests a cloned authenticator.
+    // Authenticators that don't keep a counter always report zero.
+    let sign_count = sign_count as i64;
+    if (sign_count != 0 || stored_sign_count != 0) && sign_count <= stored_sign_count {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    Ok(sign_count)
+}
+
+/// Checks the fixed part of authenticator data and returns its flags and counter.
+fn verify_auth_data(rp: &RelyingParty, auth_data: &[u8]) -> Result<(u8, u32), AppError> {
+    if auth_data.len() < 37 {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    if auth_data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    let flags = auth_data[32];
+    if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    let sign_count = u32::from_be_bytes([auth_data[33], auth_data[34], auth_data[35], auth_data[36]]);
+    Ok((flags, sign_count))
+}
+
+/// Converts a COSE EC2 P-256 key into an uncompressed SEC1 point.
+fn cose_to_sec1(key: &Value) -> Result<Vec<u8>, AppError> {
+    let int_label = |label: i128| move |key: &Value| key.as_integer().map(i128::from) == Some(label);
+
+    let kty = map_get(key, int_label(1)).and_then(Value::as_integer).map(i128::from);
+    let alg = map_get(key, int_label(3)).and_then(Value::as_integer).map(i128::from);
+    let crv = map_get(key, int_label(-1)).and_then(Value::as_integer).map(i128::from);
+    if kty != Some(2) || alg != Some(ES256 as i128) || crv != Some(1) {
+        return Err(AppError::InvalidPasskey);
+    }
+
+    let x = map_get(key, int_label(-2)).and_then(Value::as_bytes);
+    let y = map_get(key, int_label(-3)).and_then(Value::as_bytes);
+    match (x, y) {
+        (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(x);
+            point.extend_from_slice(y);
+            Ok(point)
+        }
+        _ => Err(AppError::InvalidPasskey),
+    }
+}
+
+fn map_get(map: &Value, matches: impl Fn(&Value) -> bool) -> Option<&Value> {
+    map.as_map()?
+        .iter()
+        .find(|(key, _)| matches(key))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    const RP_ID: &str = "blog.example";
+    const ORIGIN: &str = "https://blog.example";
+    const UP_UV: u8 = FLAG_USER_PRESENT | FLAG_USER_VERIFIED;
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: RP_ID.to_string(),
+            name: "Rust Blog".to_string(),
+            origin: ORIGIN.to_string(),
+        }
+    }
+
+    /// A software authenticator with one ES256 credential.
+    struct Authenticator {
+        key: SigningKey,
+        credential_id: Vec<u8>,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::random(&mut OsRng),
+                credential_id: b"software-credential".to_vec(),
+            }
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.key.verifying_key().to_encoded_point(false).as_bytes().to_vec()
+        }
+
+        fn auth_data(&self, rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&sign_count.to_be_bytes());
+            data
+        }
+
+        /// Authenticator data with the credential attached, as in registration.
+        fn attested_auth_data(&self, rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let cose_key = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(ES256)),
+                (Value::from(-1), Value::from(1)),
+                (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+                (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+
+            let mut data = self.auth_data(rp_id, flags | FLAG_ATTESTED_CREDENTIAL, sign_count);
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&cose_key, &mut data).unwrap();
+            data
+        }
+
+        fn attestation_object(&self, auth_data: Vec<u8>) -> Vec<u8> {
+            let object = Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(Vec::new())),
+                (Value::from("authData"), Value::Bytes(auth_data)),
+            ]);
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&object, &mut bytes).unwrap();
+            bytes
+        }
+
+        fn sign(&self, auth_data: &[u8], client_data_json: &[u8]) -> Vec<u8> {
+            let mut signed = auth_data.to_vec();
+            signed.extend_from_slice(&Sha256::digest(client_data_json));
+            let signature: Signature = self.key.sign(&signed);
+            signature.to_der().as_bytes().to_vec()
+        }
+    }
+
+    fn client_data(kind: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": kind,
+            "challenge": "c2VydmVyLWNoYWxsZW5nZQ",
+            "origin": origin,
+            "crossOrigin": false,
+        }))
+        .unwrap()
+    }
+
+    fn is_rejected<T>(result: Result<T, AppError>) -> bool {
+        matches!(result, Err(AppError::InvalidPasskey))
+    }
+
+    #[test]
+    fn client_data_from_our_origin_is_accepted() {
+        let challenge =
+            verify_client_data(&rp(), &client_data("webauthn.create", ORIGIN), "webauthn.create").unwrap();
+        assert_eq!(challenge, "c2VydmVyLWNoYWxsZW5nZQ");
+    }
+
+    #[test]
+    fn client_data_from_another_origin_is_rejected() {
+        let data = client_data("webauthn.create", "https://evil.example");
+        assert!(is_rejected(verify_client_data(&rp(), &data, "webauthn.create")));
+    }
+
+    #[test]
+    fn client_data_for_another_ceremony_is_rejected() {
+        let data = client_data("webauthn.get", ORIGIN);
+        assert!(is_rejected(verify_client_data(&rp(), &data, "webauthn.create")));
+    }
+
+    #[test]
+    fn registration_returns_the_credential() {
+        let authenticator = Authenticator::new();
+        let object = authenticator.attestation_object(authenticator.attested_auth_data(RP_ID, UP_UV, 0));
+
+        let credential = verify_registration(&rp(), &object).unwrap();
+        assert_eq!(credential.credential_id, encode(&authenticator.credential_id));
+        assert_eq!(credential.public_key, authenticator.public_key());
+        assert_eq!(credential.sign_count, 0);
+    }
+
+    #[test]
+    fn registration_for_another_rp_id_is_rejected() {
+        let authenticator = Authenticator::new();
+        let object =
+            authenticator.attestation_object(authenticator.attested_auth_data("evil.example", UP_UV, 0));
+        assert!(is_rejected(verify_registration(&rp(), &object)));
+    }
+
+    #[test]
+    fn registration_without_user_verification_is_rejected() {
+        let authenticator = Authenticator::new();
+        let object =
+            authenticator.attestation_object(authenticator.attested_auth_data(RP_ID, FLAG_USER_PRESENT, 0));
+        assert!(is_rejected(verify_registration(&rp(), &object)));
+    }
+
+    #[test]
+    fn registration_without_a_credential_is_rejected() {
+        let authenticator = Authenticator::new();
+        let object = authenticator.attestation_object(authenticator.auth_data(RP_ID, UP_UV, 0));
+        assert!(is_rejected(verify_registration(&rp(), &object)));
+    }
+
+    #[test]
+    fn assertion_returns_the_new_sign_count() {
+        let authenticator = Authenticator::new();
+        let auth_data = authenticator.auth_data(RP_ID, UP_UV, 6);
+        let client_data = client_data("webauthn.get", ORIGIN);
+        let signature = authenticator.sign(&auth_data, &client_data);
+
+        let sign_count = verify_assertion(
+            &rp(),
+            &authenticator.public_key(),
+            5,
+            &auth_data,
+            &client_data,
+            &signature,
+        )
+        .unwrap();
+        assert_eq!(sign_count, 6);
+    }
+
+    #[test]
+    fn assertion_without_counters_is_accepted() {
+        let authenticator = Authenticator::new();
+        let auth_data = authenticator.auth_data(RP_ID, UP_UV, 0);
+        let client_data = client_data("webauthn.get", ORIGIN);
+        let signature = authenticator.sign(&auth_data, &client_data);
+
+        let result = verify_assertion(&rp(), &authenticator.public_key(), 0, &auth_data, &client_data, &signature);
+        assert_eq!(result.ok(), Some(0));
+    }
+
+    #[test]
+    fn assertion_with_a_counter_that_did_not_increase_is_rejected() {
+        let authenticator = Authenticator::new();
+        let client_data = client_data("webauthn.get", ORIGIN);
+
+        for sign_count in [5, 4, 0] {
+            let auth_data = authenticator.auth_data(RP_ID, UP_UV, sign_count);
+            let signature = authenticator.sign(&auth_data, &client_data);
+            let result =
+                verify_assertion(&rp(), &authenticator.public_key(), 5, &auth_data, &client_data, &signature);
+            assert!(is_rejected(result), "sign count {} was accepted", sign_count);
+        }
+    }
+
+    #[test]
+    fn assertion_for_another_rp_id_is_rejected() {
+        let authenticator = Authenticator::new();
+        let auth_data = authenticator.auth_data("evil.example", UP_UV, 1);
+        let client_data = client_data("webauthn.get", ORIGIN);
+        let signature = authenticator.sign(&auth_data, &client_data);
+
+        let result = verify_assertion(&rp(), &authenticator.public_key(), 0, &auth_data, &client_data, &signature);
+        assert!(is_rejected(result));
+    }
+
+    #[test]
+    fn assertion_without_user_verification_is_rejected() {
+        let authenticator = Authenticator::new();
+        let auth_data = authenticator.auth_data(RP_ID, FLAG_USER_PRESENT, 1);
+        let client_data = client_data("webauthn.get", ORIGIN);
+        let signature = authenticator.sign(&auth_data, &client_data);
+
+        let result = verify_assertion(&rp(), &authenticator.public_key(), 0, &auth_data, &client_data, &signature);
+        assert!(is_rejected(result));
+    }
+
+    #[test]
+    fn assertion_with_a_bad_signature_is_rejected() {
+        let authenticator = Authenticator::new();
+        let auth_data = authenticator.auth_data(RP_ID, UP_UV, 1);
+        let client_data = client_data("webauthn.get", ORIGIN);
+
+        // Signed over different client data.
+        let signature = authenticator.sign(&auth_data, &self::client_data("webauthn.get", "https://evil.example"));
+        let result = verify_assertion(&rp(), &authenticator.public_key(), 0, &auth_data, &client_data, &signature);
+        assert!(is_rejected(result));
+
+        // Signed by another key.
+        let signature = Authenticator::new().sign(&auth_data, &client_data);
+        let result = verify_assertion(&rp(), &authenticator.public_key(), 0, &auth_data, &client_data, &signature);
+        assert!(is_rejected(result));
+
+        // Not a signature at all.
+        let result = verify_assertion(&rp(), &authenticator.public_key(), 0, &auth_data, &client_data, b"junk");
+        assert!(is_rejected(result));
+    }
+}
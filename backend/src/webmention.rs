@@ -0,0 +1,237 @@
+//! [Webmention](https://www.w3.org/TR/webmention/): telling other sites when
+//! a post links to them, and hearing from them when they link to ours.
+//!
+//! Incoming mentions are stored as pending and checked in the background by
+//! fetching the source and looking for a link to the post. Outgoing mentions
+//! are sent after a post is published to every page it links to that
+//! advertises a Webmention endpoint.
+
+use std::time::Duration;
+
+use http::header::LINK;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::feeds::post_url;
+use crate::get_public_url;
+use crate::models::webmentions::{Webmention, WebmentionStatus};
+
+/// How long we wait on another site.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// We stop reading pages after this many bytes.
+const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+/// Sources being fetched at once; further checks wait their turn.
+const MAX_CONCURRENT_CHECKS: usize = 4;
+
+static CHECKS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(MAX_CONCURRENT_CHECKS));
+
+static LINK_HEADER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"<([^>]*)>\s*;[^,]*?\brel\s*=\s*"?([^";,]*)"?"#).unwrap());
+static HTML_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?is)<(link|a)\s[^>]*>"#).unwrap());
+static HTML_ATTRIBUTE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?is)([a-z-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static HTML_TITLE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?is)<title[^>]*>(.*?)</title>"#).unwrap());
+static URL_IN_TEXT: Lazy<Regex> = Lazy::new(|| Regex::new(r#"https?://[^\s"'<>)\]]+"#).unwrap());
+
+/// The value of attribute `name` in an HTML tag, with entities decoded.
+fn tag_attribute(tag: &str, name: &str) -> Option<String> {
+    HTML_ATTRIBUTE
+        .captures_iter(tag)
+        .find(|attribute| attribute[1].eq_ignore_ascii_case(name))
+        .and_then(|attribute| attribute.get(2).or_else(|| attribute.get(3)))
+        .map(|value| html_escape::decode_html_entities(value.as_str()).into_owned())
+}
+
+fn is_own_url(url: &url::Url) -> bool {
+    let own = url::Url::parse(&get_public_url()).ok();
+    own.is_some_and(|own| own.host_str() == url.host_str() && own.port_or_known_default() == url.port_or_known_default())
+}
+
+async fn fetch(client: &reqwest::Client, url: &url::Url) -> Result<reqwest::Response, reqwest::Error> {
+    client.get(url.clone()).timeout(FETCH_TIMEOUT).send().await
+}
+
+/// The page's text, cut off after [`MAX_PAGE_BYTES`].
+async fn read_limited(mut response: reqwest::Response) -> Result<String, reqwest::Error> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PAGE_BYTES {
+            body.truncate(MAX_PAGE_BYTES);
+            break;
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Checks a mention someone sent us and stores it as pending. It is checked
+/// in the background by [`verify`], unless it was already waiting for that,
+/// in which case there is nothing new to check.
+pub async fn receive(store: &Store, source: &str, target: &str) -> Result<Option<Webmention>, AppError> {
+    let source_url = url::Url::parse(source).map_err(|_| AppError::invalid_webmention("source isn't a URL"))?;
+    let target_url = url::Url::parse(target).map_err(|_| AppError::invalid_webmention("target isn't a URL"))?;
+    if source_url == target_url {
+        return Err(AppError::invalid_webmention("source and target are the same page"));
+    }
+    if !store.remote.is_fetchable(&source_url) {
+        return Err(AppError::invalid_webmention("source has to be a public http or https URL"));
+    }
+    if !is_own_url(&target_url) {
+        return Err(AppError::invalid_webmention("target isn't on this site"));
+    }
+
+    let blog_id = target_url
+        .path()
+        .strip_prefix("/posts/")
+        .and_then(|id| id.trim_end_matches('/').parse::<i32>().ok())
+        .ok_or_else(|| AppError::invalid_webmention("target isn't a post"))?;
+    store
+        .get_blog(blog_id)
+        .await?
+        .ok_or_else(|| AppError::invalid_webmention("target isn't a post"))?;
+
+    store.upsert_webmention(blog_id, source, target).await
+}
+
+/// Fetches a pending mention's source and marks it verified if the page
+/// still links to the target.
+pub async fn verify(store: Store, webmention: Webmention) {
+    if webmention.status != WebmentionStatus::Pending {
+        return;
+    }
+    let Ok(_permit) = CHECKS.acquire().await else {
+        return;
+    };
+
+    let (status, title) = match check_source(store.remote.client(), &webmention).await {
+        Ok(title) => (WebmentionStatus::Verified, title),
+        Err(reason) => {
+            info!("Webmention from {} is invalid: {}", webmention.source, reason);
+            (WebmentionStatus::Invalid, None)
+        }
+    };
+
+    if let Err(err) = store
+        .finish_webmention_check(webmention.id, status, title.as_deref())
+        .await
+    {
+        warn!("Could not store webmention check {}: {:?}", webmention.id, err);
+    }
+}
+
+/// The source's title if it links to the target.
+async fn check_source(client: &reqwest::Client, webmention: &Webmention) -> Result<Option<String>, String> {
+    let source = url::Url::parse(&webmention.source).map_err(|err| err.to_string())?;
+    let response = fetch(client, &source).await.map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("source returned {}", response.status()));
+    }
+
+    let body = read_limited(response).await.map_err(|err| err.to_string())?;
+    let links_to_target = HTML_TAG
+        .find_iter(&body)
+        .any(|tag| tag_attribute(tag.as_str(), "href").as_deref() == Some(webmention.target.as_str()));
+    if !links_to_target {
+        return Err("source doesn't link to the target".to_string());
+    }
+
+    let title = HTML_TITLE
+        .captures(&body)
+        .and_then(|captures| captures.get(1))
+        .map(|title| html_escape::decode_html_entities(title.as_str().trim()).into_owned())
+        .filter(|title| !title.is_empty());
+
+    Ok(title)
+}
+
+/// Finds where `target` takes Webmentions: a `Link` header first, then the
+/// first `<link>` or `<a>` with `rel="webmention"` in the page.
+async fn discover_endpoint(client: &reqwest::Client, target: &url::Url) -> Option<url::Url> {
+    let response = fetch(client, target).await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+
+    // Relative endpoints are resolved against the page after any redirects.
+    let base = response.url().clone();
+    for value in response.headers().get_all(LINK) {
+        let Ok(value) = value.to_str() else { continue };
+        for captures in LINK_HEADER.captures_iter(value) {
+            if captures[2].split_whitespace().any(|rel| rel == "webmention") {
+                return base.join(&captures[1]).ok();
+            }
+        }
+    }
+
+    let body = read_limited(response).await.ok()?;
+    for tag in HTML_TAG.find_iter(&body) {
+        let rel = tag_attribute(tag.as_str(), "rel");
+        if rel.is_some_and(|rel| rel.split_whitespace().any(|rel| rel == "webmention")) {
+            if let Some(href) = tag_attribute(tag.as_str(), "href") {
+                return base.join(&href).ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// Tells every page a post links to about it. Failures are only logged;
+/// the other site simply won't show the mention.
+pub async fn send_for_post(store: Store, blog_id: i32) {
+    let blog = match store.get_blog(blog_id).await {
+        Ok(Some(blog)) => blog,
+        Ok(None) => return,
+        Err(err) => {
+            warn!("Could not load post {} to send webmentions: {:?}", blog_id, err);
+            return;
+        }
+    };
+    let source = post_url(&blog);
+
+    let mut targets: Vec<url::Url> = URL_IN_TEXT
+        .find_iter(&html_escape::decode_html_entities(&blog.content))
+        .filter_map(|found| url::Url::parse(found.as_str().trim_end_matches(['.', ',', ';'])).ok())
+        .filter(|target| !is_own_url(target))
+        .collect();
+    targets.sort();
+    targets.dedup();
+
+    for target in targets {
+        if !store.remote.is_fetchable(&target) {
+            continue;
+        }
+        let Some(endpoint) = discover_endpoint(store.remote.client(), &target).await else {
+            continue;
+        };
+        if !store.remote.is_fetchable(&endpoint) {
+            warn!("Not sending a webmention to {} for {}", endpoint, target);
+            continue;
+        }
+
+        let result = store
+            .remote
+            .client()
+            .post(endpoint.clone())
+            .form(&[("source", source.as_str()), ("target", target.as_str())])
+            .timeout(FETCH_TIMEOUT)
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                info!("Sent webmention for {} to {}", target, endpoint);
+            }
+            Ok(response) => {
+                warn!("Webmention endpoint {} returned {}", endpoint, response.status());
+            }
+            Err(err) => warn!("Could not send webmention to {}: {}", endpoint, err),
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Imports a WordPress export (WXR, the XML file from Tools → Export):
+//! authors become users, published posts become posts with their categories
+//! and tags as tags, and approved comments come along with them.
+//!
+//! Posts and comments are keyed by their WordPress guid, so importing the
+//! same file again only updates posts that changed and never adds anything
+//! twice. Authors are matched by email address. Imported posts aren't sent
+//! to followers or linked pages, as they are old news.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use once_cell::sync::Lazy;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use regex::Regex;
+use tracing::info;
+
+use crate::db::Store;
+use crate::error::AppError;
+use crate::feeds::plain_text;
+use crate::handlers::hash_password;
+use crate::models::blog::{normalize_tags, Blog, MAX_TITLE_LENGTH};
+use crate::models::comments::Comment;
+use crate::models::imports::ImportReport;
+use crate::models::profiles::{default_handle, ProfileForm, MAX_DISPLAY_NAME_LENGTH, MAX_HANDLE_LENGTH};
+use crate::models::tokens::generate_token;
+
+/// The biggest export the admin page takes.
+pub const MAX_WXR_BYTES: usize = 64 * 1024 * 1024;
+
+/// The category WordPress files posts under when nobody picked one.
+const DEFAULT_CATEGORY: &str = "uncategorized";
+
+static COMMENT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<!--.*?-->").unwrap());
+static HEADING: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<h([1-6])[^>]*>(.*?)</h[1-6]\s*>").unwrap());
+static RULE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<hr[^>]*>").unwrap());
+static LINE_BREAK: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<br\s*/?>").unwrap());
+static PARAGRAPH: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)</?p(\s[^>]*)?>").unwrap());
+static WHOLE_LINE_STYLE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^<(strong|b|em|i|del|s|strike)>([^<]*)</(strong|b|em|i|del|s|strike)>$").unwrap()
+});
+static BLANK_LINES: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").unwrap());
+
+#[derive(Default)]
+pub struct WxrAuthor {
+    pub login: String,
+    pub email: String,
+    pub display_name: String,
+}
+
+#[derive(Default)]
+pub struct WxrComment {
+    pub id: String,
+    pub author: String,
+    pub author_email: String,
+    pub author_url: String,
+    pub date_gmt: String,
+    pub content: String,
+    /// "1" once approved; "0", "spam" and "trash" otherwise.
+    pub approved: String,
+    /// Empty for comments, "pingback" or "trackback" for those.
+    pub comment_type: String,
+}
+
+/// An `<item>`: a post, page, attachment or anything else WordPress stores.
+#[derive(Default)]
+pub struct WxrItem {
+    pub title: String,
+    pub link: String,
+    pub guid: String,
+    /// The author's login.
+    pub creator: String,
+    pub content: String,
+    pub post_id: String,
+    pub post_date: String,
+    pub post_date_gmt: String,
+    pub post_type: String,
+    pub status: String,
+    pub categories: Vec<String>,
+    pub comments: Vec<WxrComment>,
+}
+
+#[derive(Default)]
+pub struct WxrExport {
+    pub authors: Vec<WxrAuthor>,
+    pub items: Vec<WxrItem>,
+}
+
+pub fn parse(xml: &str) -> Result<WxrExport, AppError> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut export = WxrExport::default();
+    let mut is_wxr = false;
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut author: Option<WxrAuthor> = None;
+    let mut item: Option<WxrItem> = None;
+    let mut comment: Option<WxrComment> = None;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(start)) => {
+                let name = String::from_utf8_lossy(start.name().as_ref()).into_owned();
+                match name.as_str() {
+                    "wp:author" => author = Some(WxrAuthor::default()),
+                    "item" => item = Some(WxrItem::default()),
+                    "wp:comment" if item.is_some() => comment = Some(WxrComment::default()),
+                    _ => {}
+                }
+                path.push(name);
+                text.clear();
+            }
+            Ok(Event::Text(value)) => {
+                let value = value
+                    .unescape()
+                    .map_err(|err| AppError::invalid_import(format!("The file isn't valid XML: {}", err)))?;
+                text.push_str(&value);
+            }
+            Ok(Event::CData(value)) => {
+                let value = value
+                    .decode()
+                    .map_err(|err| AppError::invalid_import(format!("The file isn't valid XML: {}", err)))?;
+                text.push_str(&value);
+            }
+            Ok(Event::End(_)) => {
+                let name = path.pop().unwrap_or_default();
+                let parent = path.last().map(String::as_str).unwrap_or_default();
+                let value = std::mem::take(&mut text);
+
+                match name.as_str() {
+                    "wp:wxr_version" => is_wxr = true,
+                    "wp:author" => export.authors.extend(author.take()),
+                    "item" => export.items.extend(item.take()),
+                    "wp:comment" => {
+                        if let (Some(item), Some(comment)) = (item.as_mut(), comment.take()) {
+                            item.comments.push(comment);
+                        }
+                    }
+                    _ => {}
+                }
+
+                if let (Some(author), "wp:author") = (author.as_mut(), parent) {
+                    match name.as_str() {
+                        "wp:author_login" => author.login = value,
+                        "wp:author_email" => author.email = value,
+                        "wp:author_display_name" => author.display_name = value,
+                        _ => {}
+                    }
+                } else if let (Some(comment), "wp:comment") = (comment.as_mut(), parent) {
+                    match name.as_str() {
+                        "wp:comment_id" => comment.id = value,
+                        "wp:comment_author" => comment.author = value,
+                        "wp:comment_author_email" => comment.author_email = value,
+                        "wp:comment_author_url" => comment.author_url = value,
+                        "wp:comment_date_gmt" => comment.date_gmt = value,
+                        "wp:comment_content" => comment.content = value,
+                        "wp:comment_approved" => comment.approved = value,
+                        "wp:comment_type" => comment.comment_type = value,
+                        _ => {}
+                    }
+                } else if let (Some(item), "item") = (item.as_mut(), parent) {
+                    match name.as_str() {
+                        "title" => item.title = value,
+                        "link" => item.link = value,
+                        "guid" => item.guid = value,
+                        "dc:creator" => item.creator = value,
+                        "content:encoded" => item.content = value,
+                        "wp:post_id" => item.post_id = value,
+                        "wp:post_date" => item.post_date = value,
+                        "wp:post_date_gmt" => item.post_date_gmt = value,
+                        "wp:post_type" => item.post_type = value,
+                        "wp:status" => item.status = value,
+                        "category" => item.categories.push(value),
+                        _ => {}
+                    }
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(err) => return Err(AppError::invalid_import(format!("The file isn't valid XML: {}", err))),
+        }
+    }
+
+    if !is_wxr {
+        return Err(AppError::invalid_import("The file isn't a WordPress export"));
+    }
+
+    Ok(export)
+}
+
+/// Rewrites what the blog's markdown covers (headings, rules, paragraphs,
+/// line breaks and lines that are all bold, italic or struck through) and
+/// leaves any other HTML as it is, which posts can contain anyway.
+pub fn html_to_markdown(html: &str) -> String {
+    let html = html.replace("\r\n", "\n");
+    let html = COMMENT.replace_all(&html, "");
+    let html = HEADING.replace_all(&html, |captures: &regex::Captures| {
+        let level = captures[1].parse::<usize>().unwrap_or(1).min(3);
+        let text = captures[2].split_whitespace().collect::<Vec<_>>().join(" ");
+        format!("\n{} {}\n", "#".repeat(level), text)
+    });
+    let html = RULE.replace_all(&html, "\n---\n");
+    let html = LINE_BREAK.replace_all(&html, "\n");
+    let html = PARAGRAPH.replace_all(&html, "\n\n");
+    let html = BLANK_LINES.replace_all(&html, "\n\n");
+
+    html.trim()
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            match WHOLE_LINE_STYLE.captures(line) {
+                Some(captures) if captures[1].eq_ignore_ascii_case(&captures[3]) => {
+                    let marker = match captures[1].to_lowercase().as_str() {
+                        "strong" | "b" => "**",
+                        "em" | "i" => "*",
+                        _ => "~~",
+                    };
+                    format!("{}{}{}", marker, &captures[2], marker)
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Comments are shown as plain text, so their markup is dropped but their
+/// paragraphs are kept.
+fn comment_text(html: &str) -> String {
+    let html = LINE_BREAK.replace_all(html, "\n");
+    let html = PARAGRAPH.replace_all(&html, "\n\n");
+    let text = plain_text(&html);
+    let text = text.lines().map(str::trim).collect::<Vec<_>>().join("\n");
+
+    BLANK_LINES.replace_all(text.trim(), "\n\n").into_owned()
+}
+
+/// WordPress dates look like `2020-01-31 12:00:00`; drafts have all zeroes.
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(date.trim(), "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|date| date.and_utc())
+}
+
+/// A login tidied into a handle, which may still be refused if it's too
+/// short or taken.
+fn handle_from_login(login: &str) -> String {
+    let handle: String = login
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '-' })
+        .collect();
+
+    handle.trim_matches('-').chars().take(MAX_HANDLE_LENGTH).collect()
+}
+
+fn post_key(item: &WxrItem) -> String {
+    let id = [&item.guid, &item.link, &item.post_id]
+        .into_iter()
+        .find(|id| !id.trim().is_empty())
+        .map(|id| id.trim().to_string())
+        .unwrap_or_else(|| item.title.clone());
+
+    format!("wxr:{}", id)
+}
+
+/// Finds the account for an author by email, or makes one. Returns `None`
+/// for authors without an email address.
+async fn import_author(
+    store: &Store,
+    author: &WxrAuthor,
+    report: &mut ImportReport,
+) -> Result<Option<i32>, AppError> {
+    let email = author.email.trim();
+    if email.is_empty() {
+        return Ok(None);
+    }
+    if let Some(user) = store.find_user(email).await? {
+        return Ok(Some(user.id));
+    }
+
+    // Nobody knows this password, so imported authors reset it to sign in.
+    let password = hash_password(&generate_token().0)?;
+    let user = store.create_verified_user(email, &password).await?;
+    report.users_created += 1;
+
+    let display_name = if author.display_name.trim().is_empty() {
+        author.login.trim()
+    } else {
+        author.display_name.trim()
+    };
+    if display_name.is_empty() {
+        return Ok(Some(user.id));
+    }
+    let form = |handle: String| ProfileForm {
+        handle,
+        display_name: display_name.chars().take(MAX_DISPLAY_NAME_LENGTH).collect(),
+        bio: String::new(),
+        website: String::new(),
+        avatar_url: String::new(),
+    };
+    let profile = match form(handle_from_login(&author.login)).into_profile(user.id) {
+        Ok(profile) => profile,
+        Err(_) => form(default_handle(user.id)).into_profile(user.id)?,
+    };
+
+    match store.update_profile(&profile).await {
+        Err(AppError::HandleTaken) => {
+            store
+                .update_profile(&form(default_handle(user.id)).into_profile(user.id)?)
+                .await?
+        }
+        result => result?,
+    }
+
+    Ok(Some(user.id))
+}
+
+pub async fn import(store: &Store, export: &WxrExport) -> Result<ImportReport, AppError> {
+    let mut report = ImportReport::default();
+
+    let mut authors: HashMap<&str, i32> = HashMap::new();
+    for author in &export.authors {
+        match import_author(store, author, &mut report).await? {
+            Some(user_id) => {
+                authors.insert(author.login.as_str(), user_id);
+            }
+            None => report
+                .skipped
+                .push(format!("Author {} has no email address", author.login)),
+        }
+    }
+
+    // Pages, attachments and menus have no place here.
+    for item in export.items.iter().filter(|item| item.post_type == "post") {
+        if item.status != "publish" {
+            report
+                .skipped
+                .push(format!("\"{}\" isn't published ({})", item.title, item.status));
+            continue;
+        }
+        let Some(&author_id) = authors.get(item.creator.as_str()) else {
+            report
+                .skipped
+                .push(format!("\"{}\" is by {}, who couldn't be imported", item.title, item.creator));
+            continue;
+        };
+        let Some(published_at) = parse_date(&item.post_date_gmt).or_else(|| parse_date(&item.post_date))
+        else {
+            report.skipped.push(format!("\"{}\" has no publish date", item.title));
+            continue;
+        };
+
+        let title = item.title.trim();
+        let title = if title.is_empty() { "Untitled post" } else { title };
+        let mut blog = Blog::new(
+            title.chars().take(MAX_TITLE_LENGTH).collect(),
+            author_id,
+            html_to_markdown(&item.content),
+            published_at.format("%Y-%m-%d").to_string(),
+        );
+        blog.published_at = published_at;
+        blog.tags = normalize_tags(
+            item.categories
+                .iter()
+                .map(String::as_str)
+                .filter(|category| !category.eq_ignore_ascii_case(DEFAULT_CATEGORY)),
+        );
+
+        let key = post_key(item);
+        let (blog_id, outcome) = store.upsert_imported_blog(&key, &blog).await?;
+        report.count_post(outcome);
+
+        let comments = item
+            .comments
+            .iter()
+            .filter(|comment| comment.approved == "1")
+            .filter(|comment| comment.comment_type.is_empty() || comment.comment_type == "comment");
+        for comment in comments {
+            let optional = |value: &str| Some(value.trim().to_string()).filter(|value| !value.is_empty());
+            let imported = Comment {
+                id: 0,
+                blog_id,
+                author_name: optional(&comment.author).unwrap_or_else(|| "Anonymous".to_string()),
+                author_email: optional(&comment.author_email),
+                author_url: optional(&comment.author_url)
+                    .filter(|url| url.starts_with("https://") || url.starts_with("http://")),
+                content: comment_text(&comment.content),
+                created_at: parse_date(&comment.date_gmt).unwrap_or(published_at),
+            };
+            let comment_key = format!("{}#comment-{}", key, comment.id);
+            if store.add_imported_comment(&comment_key, &imported).await? {
+                report.comments_created += 1;
+            }
+        }
+    }
+
+    info!(
+        "Imported a WordPress export: {} posts created, {} updated",
+        report.posts_created, report.posts_updated
+    );
+
+    Ok(report)
+}
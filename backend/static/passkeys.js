@@ -0,0 +1,81 @@
+// Glue between the passkey endpoints and the browser's WebAuthn API.
+// Binary fields travel as base64url strings in both directions.
+
+function toBase64Url(buffer) {
+  const bytes = new Uint8Array(buffer);
+  let binary = "";
+  for (const byte of bytes) {
+    binary += String.fromCharCode(byte);
+  }
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function fromBase64Url(value) {
+  const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+  const padded = base64 + "=".repeat((4 - (base64.length % 4)) % 4);
+  return Uint8Array.from(atob(padded), (c) => c.charCodeAt(0));
+}
+
+function csrfToken() {
+  const meta = document.querySelector('meta[name="csrf-token"]');
+  return meta ? meta.content : "";
+}
+
+async function postJson(url, body) {
+  const response = await fetch(url, {
+    method: "POST",
+    headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken() },
+    body: JSON.stringify(body || {}),
+  });
+  const data = await response.json();
+  if (!response.ok) {
+    throw new Error(data.error || "Request failed");
+  }
+  return data;
+}
+
+async function registerPasskey() {
+  const name = prompt("Name this passkey", "My passkey");
+  if (name === null) {
+    return;
+  }
+
+  try {
+    const { publicKey } = await postJson("/passkeys/register/start");
+    publicKey.challenge = fromBase64Url(publicKey.challenge);
+    publicKey.user.id = fromBase64Url(publicKey.user.id);
+    publicKey.excludeCredentials = publicKey.excludeCredentials.map((c) => ({
+      ...c,
+      id: fromBase64Url(c.id),
+    }));
+
+    const credential = await navigator.credentials.create({ publicKey });
+    await postJson("/passkeys/register/finish", {
+      id: credential.id,
+      name,
+      client_data_json: toBase64Url(credential.response.clientDataJSON),
+      attestation_object: toBase64Url(credential.response.attestationObject),
+    });
+    alert("Passkey added!");
+  } catch (err) {
+    alert("Could not add a passkey: " + err.message);
+  }
+}
+
+async function loginWithPasskey() {
+  try {
+    const { publicKey } = await postJson("/passkeys/login/start");
+    publicKey.challenge = fromBase64Url(publicKey.challenge);
+
+    const credential = await navigator.credentials.get({ publicKey });
+    const result = await postJson("/passkeys/login/finish", {
+      id: credential.id,
+      client_data_json: toBase64Url(credential.response.clientDataJSON),
+      authenticator_data: toBase64Url(credential.response.authenticatorData),
+      signature: toBase64Url(credential.response.signature),
+    });
+    window.location = result.redirect;
+  } catch (err) {
+    alert("Could not sign in with a passkey: " + err.message);
+  }
+}
@@ -10,8 +10,7 @@ async fn main() -> anyhow::Result<()> {
             "{
                 \"email\": \"test@test.com\",
                 \"password\": \"1234\",
-                \"confirm_password\": \"1234\",
-                \"is_admin\": true
+                \"confirm_password\": \"1234\"
             }",
         )
         .send()